@@ -0,0 +1,48 @@
+#!/bin/sh
+# Fetch the third-party libraries the theme loads into defaults/theme/vendor/,
+# to be reviewed and committed. The versions are read from LIBRARIES in
+# src/vendor.rs, so bump them there and run this again. npm checks each
+# package against the integrity hash the registry publishes for it.
+set -eu
+
+cd "$(dirname "$0")/.."
+vendor=defaults/theme/vendor
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+# The version pinned in the CDN URL of src/vendor.rs, e.g. `katex@0.16.21`
+version() {
+    grep -o "$1[@/][0-9][0-9.]*" src/vendor.rs | head -n 1 | sed 's/.*[@\/]//'
+}
+
+# fetch <folder> <npm package> <version> <part>... copies each part (a file or
+# a folder ending in /) of the package, without its leading `dist/` or
+# `dist/umd/`, as the CDN URLs point into that folder
+fetch() {
+    folder=$1 package=$2 version=$3
+    shift 3
+    [ -n "$version" ] || { echo "no version for $folder in src/vendor.rs" >&2; exit 1; }
+    echo "$folder: $package@$version"
+    tarball=$(cd "$work" && npm pack --silent "$package@$version")
+    rm -rf "$work/package" "$vendor/$folder"
+    tar -xzf "$work/$tarball" -C "$work"
+    for part in "$@"; do
+        target=$(echo "$part" | sed -e 's#^dist/umd/##' -e 's#^dist/##')
+        mkdir -p "$vendor/$folder/$(dirname "$target")"
+        cp -R "$work/package/$part" "$vendor/$folder/$target"
+    done
+}
+
+fetch fontawesome @fortawesome/fontawesome-free "$(version font-awesome)" \
+    LICENSE.txt css/all.min.css webfonts/ svgs/
+fetch prism prismjs "$(version prism)" \
+    LICENSE themes/prism-tomorrow.min.css components/prism-core.min.js \
+    components/prism-markup.min.js components/prism-bash.min.js
+fetch katex katex "$(version katex)" \
+    LICENSE dist/katex.min.css dist/katex.min.js dist/contrib/auto-render.min.js \
+    dist/fonts/
+fetch minisearch minisearch "$(version minisearch)" \
+    LICENSE.txt dist/umd/index.min.js
+fetch lightbox2 lightbox2 "$(version lightbox2)" \
+    LICENSE dist/css/lightbox.min.css dist/js/lightbox-plus-jquery.min.js \
+    dist/images/
@@ -0,0 +1,316 @@
+use anyhow::{Context, Result, bail};
+use colored::Colorize;
+use regex::{Captures, Regex};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tera::Value;
+use walkdir::WalkDir;
+
+/// Written to the output root, mapping each asset to its fingerprinted path
+pub const MANIFEST_FILE: &str = "asset-manifest.json";
+
+/// Content-hashed names for the theme's stylesheets (`style.css` and compiled
+/// Sass) and `fonts/`, e.g. `style.3fa9c1e2.css`, which are renamed. Any other
+/// file in the output that a template asks for (from `static/` or the content
+/// directory) gets a hashed copy instead, as pages may still link it by name.
+/// Templates ask for them with `asset_url(path=...)` while the pages render,
+/// but fonts may still be subset to the rendered text afterwards, so the
+/// hashes are taken once the build is done and the pages are patched then.
+pub struct AssetFingerprints {
+    output_dir: PathBuf,
+    enabled: bool,
+    requested: Mutex<Vec<String>>, // paths handed out as placeholders, by index
+}
+
+impl AssetFingerprints {
+    pub fn new(output_dir: &Path, enabled: bool) -> Self {
+        AssetFingerprints {
+            output_dir: output_dir.to_path_buf(),
+            enabled,
+            requested: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// `asset_url(path="style.css")`, relative to the site root like the
+    /// `url` of `resize_image`
+    pub fn tera_function(self: &Arc<Self>) -> impl tera::Function + use<> {
+        let assets = Arc::clone(self);
+        move |args: &HashMap<String, Value>| -> tera::Result<Value> {
+            let path = match args.get("path") {
+                Some(Value::String(path)) => path,
+                Some(_) => return Err(tera::Error::msg("asset_url: `path` must be a string")),
+                None => return Err(tera::Error::msg("asset_url: `path` is required")),
+            };
+            assets
+                .url(path)
+                .map(Value::String)
+                .map_err(|e| tera::Error::msg(format!("asset_url(path=\"{}\"): {}", path, e)))
+        }
+    }
+
+    /// The URL of an output file relative to the site root, as a placeholder
+    /// for its fingerprinted name when fingerprinting is on
+    pub fn url(&self, path: &str) -> Result<String> {
+        let path = path.trim_start_matches("./").trim_start_matches('/');
+        if !self.output_dir.join(path).is_file() {
+            bail!("no such file in the output");
+        }
+        if !self.enabled {
+            return Ok(path.to_string());
+        }
+        let mut requested = self.requested.lock().unwrap();
+        let index = match requested.iter().position(|known| known == path) {
+            Some(index) => index,
+            None => {
+                requested.push(path.to_string());
+                requested.len() - 1
+            }
+        };
+        Ok(placeholder(index))
+    }
+
+    /// Rename the assets after their content, rewrite the font URLs in the
+    /// theme's `stylesheets`, fill in the `asset_url` placeholders of `pages`
+    /// and write the manifest
+    pub fn finish(&self, pages: &[PathBuf], stylesheets: &[PathBuf]) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let mut manifest = BTreeMap::new();
+        let fonts_dir = self.output_dir.join("fonts");
+        if fonts_dir.is_dir() {
+            // Listed before renaming, so that renamed files are not visited again
+            let fonts: Vec<String> = WalkDir::new(&fonts_dir)
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_file())
+                .map(|entry| self.relative(entry.path()))
+                .collect();
+            for relative in fonts {
+                let hashed = self.fingerprint(&relative, true)?;
+                manifest.insert(relative, hashed);
+            }
+        }
+        let url_re = Regex::new(r#"url\(\s*(["']?)([^"')]+)(["']?)\s*\)"#).unwrap();
+        for path in stylesheets {
+            let name = self.relative(path);
+            let css = fs::read_to_string(path)?;
+            let css = url_re.replace_all(&css, |caps: &Captures| match manifest.get(&caps[2]) {
+                Some(hashed) => format!("url({}{}{})", &caps[1], hashed, &caps[3]),
+                None => caps[0].to_string(),
+            });
+            fs::write(path, css.as_ref())?;
+            let hashed = self.fingerprint(&name, true)?;
+            manifest.insert(name, hashed);
+        }
+
+        let requested = self.requested.lock().unwrap();
+        for path in requested.iter() {
+            if !manifest.contains_key(path) && self.output_dir.join(path).is_file() {
+                let hashed = self.fingerprint(path, false)?;
+                manifest.insert(path.clone(), hashed);
+            }
+        }
+        let placeholder_re = Regex::new(r"@@mdbear-asset:(\d+)@@").unwrap();
+        for page in pages {
+            let html = fs::read_to_string(page)
+                .with_context(|| format!("Cannot read generated page: {:?}", page))?;
+            let mut missing = None;
+            let html = placeholder_re.replace_all(&html, |caps: &Captures| {
+                let path = &requested[caps[1].parse::<usize>().unwrap()];
+                match manifest.get(path) {
+                    Some(hashed) => hashed.clone(),
+                    None => {
+                        // Not fingerprinted, and gone if it was a font replaced by its subset
+                        if !self.output_dir.join(path).is_file() {
+                            missing = Some(path.clone());
+                        }
+                        path.clone()
+                    }
+                }
+            });
+            if let Some(path) = missing {
+                bail!(
+                    "asset_url(path=\"{}\") in {}: the file was removed from the output during the build",
+                    path,
+                    self.relative(page)
+                );
+            }
+            fs::write(page, html.as_ref())?;
+        }
+
+        fs::write(
+            self.output_dir.join(MANIFEST_FILE),
+            serde_json::to_string_pretty(&manifest)?,
+        )?;
+        println!(
+            "  {} {} {}",
+            "Fingerprinted".green(),
+            manifest.len().to_string().green(),
+            format!("asset(s), see {}", MANIFEST_FILE).green()
+        );
+        Ok(())
+    }
+
+    /// Rename (or copy) `relative` to `<stem>.<hash>.<ext>` and return the new
+    /// relative path
+    fn fingerprint(&self, relative: &str, rename: bool) -> Result<String> {
+        let path = self.output_dir.join(relative);
+        let hash = format!("{:x}", Sha256::digest(fs::read(&path)?));
+        let hashed = match relative.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() && !ext.contains('/') => {
+                format!("{}.{}.{}", stem, &hash[..8], ext)
+            }
+            _ => format!("{}.{}", relative, &hash[..8]),
+        };
+        if rename {
+            fs::rename(&path, self.output_dir.join(&hashed))?;
+        } else {
+            fs::copy(&path, self.output_dir.join(&hashed))?;
+        }
+        Ok(hashed)
+    }
+
+    fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.output_dir)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .replace('\\', "/")
+    }
+}
+
+fn placeholder(index: usize) -> String {
+    format!("@@mdbear-asset:{}@@", index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    fn write(dir: &Path, path: &str, contents: &str) {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// `path` with the first 8 hex digits of the SHA-256 of `contents` before its extension
+    fn hashed(path: &str, contents: &str) -> String {
+        let (stem, ext) = path.rsplit_once('.').unwrap();
+        format!(
+            "{}.{}.{}",
+            stem,
+            &format!("{:x}", Sha256::digest(contents))[..8],
+            ext
+        )
+    }
+
+    #[test]
+    fn assets_are_named_after_their_content() {
+        let dir = test_dir("fingerprints");
+        write(&dir, "fonts/sans.woff2", "font");
+        write(
+            &dir,
+            "style.css",
+            "@font-face { src: url(\"fonts/sans.woff2\"); }",
+        );
+        write(&dir, "js/app.js", "console.log('app');");
+        let assets = AssetFingerprints::new(&dir, true);
+        let style = assets.url("./style.css").unwrap();
+        let app = assets.url("/js/app.js").unwrap();
+        assert_eq!(
+            assets.url("style.css").unwrap(),
+            style,
+            "one placeholder per file"
+        );
+        write(
+            &dir,
+            "index.html",
+            &format!(
+                "<link href=\"./{}\"><script src=\"./{}\"></script>",
+                style, app
+            ),
+        );
+
+        assets
+            .finish(&[dir.join("index.html")], &[dir.join("style.css")])
+            .unwrap();
+
+        let font = hashed("fonts/sans.woff2", "font");
+        let css = format!("@font-face {{ src: url(\"{}\"); }}", font);
+        let style = hashed("style.css", &css);
+        let app = hashed("js/app.js", "console.log('app');");
+        let manifest: BTreeMap<String, String> =
+            serde_json::from_str(&fs::read_to_string(dir.join(MANIFEST_FILE)).unwrap()).unwrap();
+        assert_eq!(
+            manifest,
+            BTreeMap::from([
+                ("fonts/sans.woff2".to_string(), font.clone()),
+                ("js/app.js".to_string(), app.clone()),
+                ("style.css".to_string(), style.clone()),
+            ])
+        );
+        assert_eq!(fs::read_to_string(dir.join(&style)).unwrap(), css);
+        assert_eq!(
+            fs::read_to_string(dir.join("index.html")).unwrap(),
+            format!(
+                "<link href=\"./{}\"><script src=\"./{}\"></script>",
+                style, app
+            )
+        );
+        // The theme's assets are renamed, requested files are copied
+        assert!(!dir.join("style.css").exists() && !dir.join("fonts/sans.woff2").exists());
+        assert!(dir.join(&font).is_file());
+        assert!(dir.join("js/app.js").is_file() && dir.join(&app).is_file());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn assets_keep_their_names_without_fingerprinting() {
+        let dir = test_dir("no-fingerprints");
+        write(&dir, "style.css", "body {}");
+        let assets = AssetFingerprints::new(&dir, false);
+        assert_eq!(assets.url("/style.css").unwrap(), "style.css");
+        assets.finish(&[], &[dir.join("style.css")]).unwrap();
+        assert!(dir.join("style.css").is_file());
+        assert!(!dir.join(MANIFEST_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn asset_url_needs_a_file_that_stays_in_the_output() {
+        let dir = test_dir("missing-assets");
+        write(&dir, "fonts/sans.woff2", "font");
+        let assets = Arc::new(AssetFingerprints::new(&dir, true));
+        let function = assets.tera_function();
+        let call = |args: serde_json::Value| {
+            let args: HashMap<String, Value> = serde_json::from_value(args).unwrap();
+            tera::Function::call(&function, &args).map_err(|e| e.to_string())
+        };
+        assert_eq!(
+            call(serde_json::json!({})).unwrap_err(),
+            "asset_url: `path` is required"
+        );
+        assert_eq!(
+            call(serde_json::json!({ "path": "app.js" })).unwrap_err(),
+            "asset_url(path=\"app.js\"): no such file in the output"
+        );
+
+        // A font replaced by its subset is gone by the time pages are patched
+        let font = call(serde_json::json!({ "path": "fonts/sans.woff2" })).unwrap();
+        write(&dir, "index.html", font.as_str().unwrap());
+        fs::remove_file(dir.join("fonts/sans.woff2")).unwrap();
+        assert_eq!(
+            assets
+                .finish(&[dir.join("index.html")], &[])
+                .unwrap_err()
+                .to_string(),
+            "asset_url(path=\"fonts/sans.woff2\") in index.html: the file was removed from the output during the build"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -28,6 +28,47 @@ pub enum Commands {
             help = "Configuration file to use for building the site"
         )]
         config: String,
+        #[arg(
+            short,
+            long,
+            help = "Number of worker threads for images and pages (defaults to the number of CPUs)"
+        )]
+        jobs: Option<usize>,
+        #[arg(
+            long,
+            help = "Minify HTML, CSS, inline scripts and the search index (same as `minify = true` under [build])"
+        )]
+        minify: bool,
+    },
+
+    #[command(about = "Build the site to a temporary directory and check for broken links")]
+    Check {
+        #[arg(
+            short,
+            long,
+            default_value = "config.toml",
+            help = "Configuration file to use for checking the site"
+        )]
+        config: String,
+        #[arg(
+            short,
+            long,
+            help = "Number of worker threads for images and pages (defaults to the number of CPUs)"
+        )]
+        jobs: Option<usize>,
+    },
+
+    #[command(about = "Remove the build output, and optionally the image cache")]
+    Clean {
+        #[arg(
+            short,
+            long,
+            default_value = "config.toml",
+            help = "Configuration file that names the output directory"
+        )]
+        config: String,
+        #[arg(long, help = "Also remove the image cache in .mdbear-cache")]
+        cache: bool,
     },
 
     #[command(about = "Serve the site locally with auto-reload")]
@@ -46,6 +87,12 @@ pub enum Commands {
             help = "Configuration file to use for serving the site"
         )]
         config: String,
+        #[arg(
+            short,
+            long,
+            help = "Number of worker threads for images and pages (defaults to the number of CPUs)"
+        )]
+        jobs: Option<usize>,
     },
 
     #[command(about = "Sync theme files from mdbear defaults")]
@@ -1,23 +1,65 @@
-use crate::utils::{Config, copy_dir_all, generate_rss, images2webp, load_page, scan_blog_posts};
+use crate::assets::AssetFingerprints;
+use crate::csp::ContentSecurityPolicy;
+use crate::favicons::generate_site_icons;
+use crate::figures::Figures;
+use crate::fonts::FontSubsetter;
+use crate::graph::SiteGraph;
+use crate::images::{ImageManifest, ImageResizer, process_images};
+use crate::links::{BrokenLinks, LinkIndex};
+use crate::minify::minify_site;
+use crate::palettes::ThemePalette;
+use crate::pwa;
+use crate::sass::SassCompiler;
+use crate::utils::{
+    CACHE_DIR, Config, Page, STATIC_DIR, copy_dir_all, generate_rss, load_page, root_path_for_url,
+    scan_blog_posts,
+};
+use crate::vendor::{Needs, Vendor};
 use anyhow::{Context, Result, bail};
 use colored::Colorize;
-use serde::{Serialize};
+use rayon::prelude::*;
+use serde::Serialize;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tera::{Context as TeraContext, Result as TeraResult, Tera, Value};
+use walkdir::WalkDir;
 
-pub fn execute(config_path: &str) -> Result<()> {
+/// Build settings that come from the command line rather than the config file
+#[derive(Debug, Default)]
+pub struct BuildOptions {
+    pub minify: Option<bool>, // overrides `minify` under [build]
+    pub source_maps: bool,    // writes `.css.map` files for the theme's Sass
+}
+
+pub fn execute(config_path: &str, options: &BuildOptions) -> Result<()> {
     let config_str = fs::read_to_string(config_path)?;
     let config: Config = toml::from_str(&config_str)?;
     let output_dir = Path::new(&config.output_dir);
+    validate_output_dir(
+        output_dir,
+        Path::new("content"),
+        Path::new("theme"),
+        Path::new(STATIC_DIR),
+    )?;
+    build_site(&config, output_dir, options)
+}
+
+/// Render the site described by `config` into `output_dir`, which is wiped first.
+/// Callers are responsible for validating that `output_dir` is safe to delete.
+pub fn build_site(config: &Config, output_dir: &Path, options: &BuildOptions) -> Result<()> {
     let content_dir = Path::new("content");
     let theme_dir = Path::new("theme");
-    validate_output_dir(output_dir, content_dir, theme_dir)?;
     println!(
         "{} {}",
         "Building site to".cyan(),
         output_dir.display().to_string().cyan()
     );
+    let figures = Figures::from_config(&config.figures)?;
+    let fonts = FontSubsetter::from_config(&config.fonts)?;
+    let palette = ThemePalette::from_config(&config.theme)?;
+    let csp = ContentSecurityPolicy::from_config(&config.build)?;
+    let broken_links = BrokenLinks::from_config(&config.build)?;
     let mut tera = Tera::new("theme/**/*.html")?;
 
     // Register custom filters
@@ -33,9 +75,19 @@ pub fn execute(config_path: &str) -> Result<()> {
         copy_dir_all(&assets_src, output_dir.join("assets"))?;
     }
 
-    let images_src = output_dir.join("assets");
-    let images_dst = output_dir.join("assets");
-    let converted_images = images2webp(&images_src, &images_dst)?;
+    let images = process_images(output_dir, &config.images, Some(Path::new(CACHE_DIR)))?;
+    let resizer = Arc::new(ImageResizer::new(
+        content_dir,
+        output_dir,
+        Some(Path::new(CACHE_DIR)),
+        &config.images,
+    ));
+    tera.register_function("resize_image", resizer.tera_function());
+    let assets = Arc::new(AssetFingerprints::new(
+        output_dir,
+        config.build.fingerprint.unwrap_or(false),
+    ));
+    tera.register_function("asset_url", assets.tera_function());
 
     let fonts_src = theme_dir.join("fonts");
     if fonts_src.exists() {
@@ -46,44 +98,111 @@ pub fn execute(config_path: &str) -> Result<()> {
     if favicon_src.exists() {
         fs::copy(&favicon_src, output_dir.join("favicon.ico"))?;
     }
+    let mut icon_links = generate_site_icons(config, output_dir)?;
+    let offline = config.pwa.enabled.unwrap_or(false);
+    if offline {
+        pwa::write_manifest(config, &mut icon_links, output_dir)?;
+    }
 
+    let vendor = Vendor::detect(theme_dir);
+    vendor.copy_to(output_dir)?;
+    let missing = vendor.missing();
+    if !missing.is_empty() {
+        println!(
+            "{} {}",
+            "Loading from CDN (not in theme/vendor):".yellow(),
+            missing.join(", ").yellow()
+        );
+    }
+
+    // Stylesheets that the minifier and fingerprinting may rewrite
+    let mut stylesheets = Vec::new();
     let style_src = theme_dir.join("style.css");
     if style_src.exists() {
         fs::copy(&style_src, output_dir.join("style.css"))?;
+        stylesheets.push(output_dir.join("style.css"));
     }
-
-    for item in &config.nav {
-        match item.item_type.as_str() {
-            "page" => {
-                if !item.path.ends_with(".md") {
-                    continue;
-                }
-                let full_path = content_dir.join(&item.path);
-                if !full_path.exists() {
-                    continue;
-                }
-                let page = load_page(content_dir, &item.path, false, Some(&converted_images))?;
-                let mut ctx = TeraContext::new();
-                ctx.insert("config", &config);
-                ctx.insert("current_page", &page);
-                ctx.insert("content", &page.content_html);
-                ctx.insert("root_path", &root_path_for_url(&page.url));
-                ctx.insert("current_url", &page.url);
-
-                let render_out = tera.render("page.html", &ctx)?;
-                let page_path = output_dir.join(&page.url);
-                if let Some(parent) = page_path.parent() {
-                    fs::create_dir_all(parent)?;
-                }
-                fs::write(page_path, render_out)?;
-            }
-            "link" => {}
-            _ => {}
+    // A single-flavor color scheme fixes the mode, so Sass sees it as set
+    let mut theme = config.theme.clone();
+    if theme.mode.is_none() && palette.mode() != "auto" {
+        theme.mode = Some(palette.mode().to_string());
+    }
+    let compiled =
+        SassCompiler::new(theme_dir, &theme, options.source_maps)?.compile_to(output_dir)?;
+    for path in compiled {
+        if !stylesheets.contains(&path) {
+            stylesheets.push(path);
         }
     }
+    copy_static_files(theme_dir, Path::new(STATIC_DIR), output_dir)?;
+
+    // Template variables shared by every page
+    let mut site_ctx = TeraContext::new();
+    site_ctx.insert("config", config);
+    site_ctx.insert("icon_links", &icon_links);
+    site_ctx.insert("theme_mode", palette.mode());
+    site_ctx.insert("palette_css", palette.css());
+    site_ctx.insert(
+        "head_html",
+        &read_include(config.head_html.as_deref(), "head_html")?,
+    );
+    site_ctx.insert(
+        "body_end_html",
+        &read_include(config.body_end_html.as_deref(), "body_end_html")?,
+    );
+    site_ctx.insert("extra_css", &Vec::<String>::new());
+    site_ctx.insert("extra_js", &Vec::<String>::new());
+
+    // Nav entries that point at Markdown pages, rendered in parallel below
+    let page_paths: Vec<&str> = config
+        .nav
+        .iter()
+        .filter(|item| item.item_type == "page" && item.path.ends_with(".md"))
+        .filter(|item| content_dir.join(&item.path).exists())
+        .map(|item| item.path.as_str())
+        .collect();
+    let mut pages = page_paths
+        .par_iter()
+        .map(|path| {
+            load_page(
+                content_dir,
+                path,
+                false,
+                Some(&images),
+                Some(&tera),
+                &figures,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
 
     // Generate blog pages from content/blog/
-    let blog_posts = scan_blog_posts(content_dir, Some(&converted_images))?;
+    let mut blog_posts = scan_blog_posts(content_dir, Some(&images), Some(&tera), &figures)?;
+
+    let edges = resolve_internal_links(
+        broken_links,
+        content_dir,
+        &images,
+        &mut pages,
+        &mut blog_posts,
+    )?;
+    build_graph(config, output_dir, edges, &mut pages, &mut blog_posts)?;
+    copy_extra_assets(content_dir, output_dir, pages.iter().chain(&blog_posts))?;
+
+    let mut rendered = pages
+        .par_iter()
+        .map(|page| {
+            render_page(
+                &tera,
+                &site_ctx,
+                &vendor,
+                &assets,
+                "page.html",
+                page,
+                output_dir,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+
     if !blog_posts.is_empty() {
         println!(
             "{} {} {}",
@@ -93,37 +212,47 @@ pub fn execute(config_path: &str) -> Result<()> {
         );
 
         // Render individual blog post pages
-        for post in &blog_posts {
-            let mut ctx = TeraContext::new();
-            ctx.insert("config", &config);
-            ctx.insert("current_page", &post);
-            ctx.insert("content", &post.content_html);
-            ctx.insert("root_path", &root_path_for_url(&post.url));
-            ctx.insert("current_url", &post.url);
-
-            let render_out = tera.render("post.html", &ctx)?;
-            let post_path = output_dir.join(&post.url);
-            if let Some(parent) = post_path.parent() {
-                fs::create_dir_all(parent)?;
-            }
-            fs::write(&post_path, render_out)?;
+        let post_paths = blog_posts
+            .par_iter()
+            .map(|post| {
+                render_page(
+                    &tera,
+                    &site_ctx,
+                    &vendor,
+                    &assets,
+                    "post.html",
+                    post,
+                    output_dir,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for post_path in &post_paths {
             println!(
                 "  {} {}",
                 "Generated:".green(),
                 post_path.display().to_string().green()
             );
         }
+        rendered.extend(post_paths);
 
         // Render blog listing page
-        let mut ctx = TeraContext::new();
-        ctx.insert("config", &config);
+        let mut ctx = site_ctx.clone();
         ctx.insert("posts", &blog_posts);
         ctx.insert("root_path", ".");
         ctx.insert("current_url", "blog.html");
+        let needs = Needs {
+            icons: vendor.icons().needs_font(),
+            search: true,
+            ..Needs::default()
+        };
+        ctx.insert("vendor", &vendor.urls("."));
+        ctx.insert("libraries", &needs.libraries());
+        ctx.insert("needs", &needs);
 
         let render_out = tera.render("blog.html", &ctx)?;
         let blog_path = output_dir.join("blog.html");
         fs::write(&blog_path, render_out)?;
+        rendered.push(blog_path.clone());
         println!(
             "  {} {}",
             "Generated blog index:".green(),
@@ -131,7 +260,7 @@ pub fn execute(config_path: &str) -> Result<()> {
         );
 
         let rss_path = output_dir.join("rss.xml");
-        fs::write(&rss_path, generate_rss(&config, &blog_posts))?;
+        fs::write(&rss_path, generate_rss(config, &blog_posts))?;
         println!(
             "  {} {}",
             "Generated RSS feed:".green(),
@@ -170,10 +299,239 @@ pub fn execute(config_path: &str) -> Result<()> {
         }
     }
 
+    vendor.icons().apply(&rendered, output_dir)?;
+    if config.fonts.subset.unwrap_or(false) {
+        fonts.apply(&rendered, output_dir)?;
+    }
+    if options
+        .minify
+        .unwrap_or(config.build.minify.unwrap_or(false))
+    {
+        minify_site(&rendered, &stylesheets, output_dir)?;
+    }
+    assets.finish(&rendered, &stylesheets)?;
+    csp.apply(&rendered, output_dir)?;
+    if offline {
+        pwa::write_service_worker(config, &pages, &blog_posts, output_dir)?;
+    }
+    resizer.remove_stale_cache()?;
+
     println!("{}", "Build success!".green().bold());
     Ok(())
 }
 
+/// Copy `theme/static/` and then the project's `static/` verbatim to the
+/// output root, noting each theme file the project overrides
+fn copy_static_files(theme_dir: &Path, static_dir: &Path, output_dir: &Path) -> Result<()> {
+    let theme_static = theme_dir.join(STATIC_DIR);
+    if theme_static.is_dir() {
+        copy_dir_all(&theme_static, output_dir)?;
+    }
+    if !static_dir.is_dir() {
+        return Ok(());
+    }
+    for entry in WalkDir::new(static_dir).sort_by_file_name() {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(static_dir)?;
+        if entry.file_type().is_file() && theme_static.join(relative).is_file() {
+            println!(
+                "  {} {} {}",
+                "Overriding theme file:".yellow(),
+                theme_static.join(relative).display().to_string().yellow(),
+                format!("(with {})", entry.path().display()).yellow()
+            );
+        }
+    }
+    copy_dir_all(static_dir, output_dir)?;
+    Ok(())
+}
+
+/// The contents of the `head_html` or `body_end_html` file, or nothing when unset
+fn read_include(path: Option<&str>, key: &str) -> Result<String> {
+    let Some(path) = path else {
+        return Ok(String::new());
+    };
+    fs::read_to_string(path).with_context(|| format!("Cannot read {} file {}", key, path))
+}
+
+/// Whether an `extra_css`/`extra_js` entry is a URL rather than a content file
+fn is_external(path: &str) -> bool {
+    path.starts_with("https://") || path.starts_with("http://") || path.starts_with("//")
+}
+
+/// Copy the local `extra_css` and `extra_js` files of `pages` from the content
+/// directory to the same place in the output
+fn copy_extra_assets<'a>(
+    content_dir: &Path,
+    output_dir: &Path,
+    pages: impl Iterator<Item = &'a Page>,
+) -> Result<()> {
+    for page in pages {
+        for path in page.meta.extra_css.iter().chain(&page.meta.extra_js) {
+            if is_external(path) {
+                continue;
+            }
+            let relative = Path::new(path.trim_start_matches("./").trim_start_matches('/'));
+            if relative
+                .components()
+                .any(|component| !matches!(component, std::path::Component::Normal(_)))
+            {
+                bail!(
+                    "{}: extra file {} must be inside the content directory",
+                    page.source_path,
+                    path
+                );
+            }
+            let source = content_dir.join(relative);
+            if !source.is_file() {
+                bail!(
+                    "{}: extra file {} not found in {}",
+                    page.source_path,
+                    path,
+                    content_dir.display()
+                );
+            }
+            let target = output_dir.join(relative);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&source, &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Render `page` with `template` and write it to its URL under `output_dir`
+fn render_page(
+    tera: &Tera,
+    site_ctx: &TeraContext,
+    vendor: &Vendor,
+    assets: &AssetFingerprints,
+    template: &str,
+    page: &Page,
+    output_dir: &Path,
+) -> Result<PathBuf> {
+    let root_path = root_path_for_url(&page.url);
+    let mut ctx = site_ctx.clone();
+    ctx.insert("current_page", page);
+    ctx.insert("content", &page.content_html);
+    ctx.insert("root_path", &root_path);
+    ctx.insert("current_url", &page.url);
+    ctx.insert("vendor", &vendor.urls(&root_path));
+    let needs = Needs::for_content(&page.content_html, vendor);
+    // Extra files count as libraries, so navigating to the page loads it in full
+    let mut libraries: Vec<&str> = needs.libraries();
+    libraries.extend(page.meta.extra_css.iter().map(String::as_str));
+    libraries.extend(page.meta.extra_js.iter().map(String::as_str));
+    ctx.insert("libraries", &libraries);
+    ctx.insert("needs", &needs);
+    // Copied to the output before rendering, so they can be fingerprinted
+    let href = |path: &String| -> Result<String> {
+        if is_external(path) {
+            Ok(path.clone())
+        } else {
+            Ok(format!("{}/{}", root_path, assets.url(path)?))
+        }
+    };
+    ctx.insert(
+        "extra_css",
+        &page
+            .meta
+            .extra_css
+            .iter()
+            .map(href)
+            .collect::<Result<Vec<_>>>()?,
+    );
+    ctx.insert(
+        "extra_js",
+        &page
+            .meta
+            .extra_js
+            .iter()
+            .map(href)
+            .collect::<Result<Vec<_>>>()?,
+    );
+
+    let render_out = tera.render(template, &ctx)?;
+    let page_path = output_dir.join(&page.url);
+    if let Some(parent) = page_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&page_path, render_out)?;
+    Ok(page_path)
+}
+
+/// Use `jobs` worker threads for image encoding and page rendering.
+/// Must be called once, before the first build.
+pub fn configure_jobs(jobs: Option<usize>) -> Result<()> {
+    let Some(jobs) = jobs else {
+        return Ok(());
+    };
+    if jobs == 0 {
+        bail!("--jobs must be at least 1");
+    }
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build_global()
+        .context("Failed to configure worker threads")?;
+    Ok(())
+}
+
+/// Rewrite `.md` links and wikilinks across all pages, report the links that
+/// cannot be resolved and return the `(source, target)` edges between pages
+fn resolve_internal_links(
+    broken_links: BrokenLinks,
+    content_dir: &Path,
+    images: &ImageManifest,
+    pages: &mut [Page],
+    posts: &mut [Page],
+) -> Result<Vec<(String, String)>> {
+    let index = LinkIndex::new(pages.iter().chain(posts.iter()), content_dir, Some(images));
+    let mut all_pages: Vec<&mut Page> = pages.iter_mut().chain(posts.iter_mut()).collect();
+    let (problems, edges) = index.resolve(&mut all_pages);
+
+    if problems.is_empty() {
+        return Ok(edges);
+    }
+    if broken_links == BrokenLinks::Error {
+        bail!(
+            "Found {} broken link(s):\n  {}",
+            problems.len(),
+            problems.join("\n  ")
+        );
+    }
+    for problem in &problems {
+        eprintln!("  {} {}", "Broken link:".yellow(), problem);
+    }
+    Ok(edges)
+}
+
+/// Fill in backlinks and related posts from the site-wide link graph, and
+/// write `graph.json` when enabled
+fn build_graph(
+    config: &Config,
+    output_dir: &Path,
+    edges: Vec<(String, String)>,
+    pages: &mut [Page],
+    posts: &mut [Page],
+) -> Result<()> {
+    let graph = SiteGraph::new(edges);
+    let mut all_pages: Vec<&mut Page> = pages.iter_mut().chain(posts.iter_mut()).collect();
+    graph.annotate(&mut all_pages, config.graph.related_posts.unwrap_or(3));
+
+    if config.graph.json.unwrap_or(false) {
+        let all_pages: Vec<&Page> = all_pages.iter().map(|page| &**page).collect();
+        let graph_path = output_dir.join("graph.json");
+        fs::write(&graph_path, graph.to_json(&all_pages)?)?;
+        println!(
+            "  {} {}",
+            "Generated link graph:".green(),
+            graph_path.display().to_string().green()
+        );
+    }
+    Ok(())
+}
+
 /// Very simple HTML tag stripper for search indexing
 fn strip_html_tags(html: &str) -> String {
     let mut result = String::new();
@@ -190,7 +548,12 @@ fn strip_html_tags(html: &str) -> String {
     result.split_whitespace().collect::<Vec<_>>().join(" ")
 }
 
-fn validate_output_dir(output_dir: &Path, content_dir: &Path, theme_dir: &Path) -> Result<()> {
+pub fn validate_output_dir(
+    output_dir: &Path,
+    content_dir: &Path,
+    theme_dir: &Path,
+    static_dir: &Path,
+) -> Result<()> {
     if output_dir.as_os_str().is_empty() {
         bail!("output_dir cannot be empty");
     }
@@ -212,6 +575,8 @@ fn validate_output_dir(output_dir: &Path, content_dir: &Path, theme_dir: &Path)
         absolutize_for_guard(&cwd, Path::new(".")),
         absolutize_for_guard(&cwd, content_dir),
         absolutize_for_guard(&cwd, theme_dir),
+        absolutize_for_guard(&cwd, static_dir),
+        absolutize_for_guard(&cwd, Path::new(CACHE_DIR)),
     ];
 
     if protected.iter().any(|path| path == &output_abs) {
@@ -231,21 +596,6 @@ fn validate_output_dir(output_dir: &Path, content_dir: &Path, theme_dir: &Path)
     Ok(())
 }
 
-fn root_path_for_url(url: &str) -> String {
-    let depth = Path::new(url)
-        .parent()
-        .map(|parent| parent.components().count())
-        .unwrap_or(0);
-
-    if depth == 0 {
-        ".".to_string()
-    } else {
-        std::iter::repeat_n("..", depth)
-            .collect::<Vec<_>>()
-            .join("/")
-    }
-}
-
 fn absolutize_for_guard(cwd: &Path, path: &Path) -> PathBuf {
     let joined = if path.is_absolute() {
         path.to_path_buf()
@@ -311,3 +661,150 @@ fn date_format_filter(
     // Return original if parsing fails
     Ok(Value::String(date_str.to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{test_dir, test_page};
+
+    fn validate(output_dir: &str) -> Result<()> {
+        validate_output_dir(
+            Path::new(output_dir),
+            Path::new("content"),
+            Path::new("theme"),
+            Path::new(STATIC_DIR),
+        )
+    }
+
+    #[test]
+    fn output_dir_cannot_be_a_project_directory() {
+        for output_dir in ["content", "theme", STATIC_DIR, CACHE_DIR, "./.mdbear-cache"] {
+            let err = validate(output_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("protected directory"),
+                "{}: {}",
+                output_dir,
+                err
+            );
+        }
+        for output_dir in ["", ".", "..", "../public"] {
+            assert!(validate(output_dir).is_err(), "{}", output_dir);
+        }
+        validate("public").unwrap();
+    }
+
+    #[test]
+    fn static_files_are_copied_verbatim_with_project_files_first() {
+        let dir = test_dir("static-files");
+        let theme_dir = dir.join("theme");
+        let static_dir = dir.join(STATIC_DIR);
+        let output_dir = dir.join("public");
+        for (path, contents) in [
+            ("theme/static/js/theme.js", "console.log(1);\n"),
+            ("theme/static/CNAME", "theme.example.com\n"),
+            ("static/CNAME", "blog.example.com\n"),
+            (
+                "static/.well-known/security.txt",
+                "Contact: mailto:security@example.com\n",
+            ),
+            ("static/print.css", "body {\n  color: black;\n}\n"),
+        ] {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        copy_static_files(&theme_dir, &static_dir, &output_dir).unwrap();
+
+        let output = |path: &str| fs::read_to_string(output_dir.join(path)).unwrap();
+        assert_eq!(output("CNAME"), "blog.example.com\n");
+        assert_eq!(output("js/theme.js"), "console.log(1);\n");
+        assert_eq!(
+            output(".well-known/security.txt"),
+            "Contact: mailto:security@example.com\n"
+        );
+        assert_eq!(output("print.css"), "body {\n  color: black;\n}\n");
+
+        // Either directory may be missing
+        fs::remove_dir_all(&output_dir).unwrap();
+        copy_static_files(&dir.join("missing"), &static_dir, &output_dir).unwrap();
+        assert_eq!(output("CNAME"), "blog.example.com\n");
+        copy_static_files(&theme_dir, &dir.join("missing"), &output_dir).unwrap();
+        assert_eq!(output("CNAME"), "theme.example.com\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn local_extra_files_are_copied_to_the_output() {
+        let dir = test_dir("extra-assets");
+        let content_dir = dir.join("content");
+        let output_dir = dir.join("public");
+        fs::create_dir_all(content_dir.join("extras")).unwrap();
+        fs::write(
+            content_dir.join("extras/chart.css"),
+            ".chart { color: red; }\n",
+        )
+        .unwrap();
+        fs::write(
+            content_dir.join("extras/chart.js"),
+            "console.log('chart');\n",
+        )
+        .unwrap();
+        let mut page = test_page("blog/post.md", "blog/post.html", "Post", "");
+        page.meta.extra_css = vec!["./extras/chart.css".to_string()];
+        page.meta.extra_js = vec![
+            "/extras/chart.js".to_string(),
+            "https://cdn.example.com/widget.js".to_string(),
+        ];
+
+        copy_extra_assets(&content_dir, &output_dir, std::iter::once(&page)).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(output_dir.join("extras/chart.css")).unwrap(),
+            ".chart { color: red; }\n"
+        );
+        assert_eq!(
+            fs::read_to_string(output_dir.join("extras/chart.js")).unwrap(),
+            "console.log('chart');\n"
+        );
+        assert!(!output_dir.join("widget.js").exists());
+
+        page.meta.extra_js = vec!["extras/missing.js".to_string()];
+        let err = copy_extra_assets(&content_dir, &output_dir, std::iter::once(&page))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("blog/post.md: extra file extras/missing.js not found"),
+            "{}",
+            err
+        );
+        page.meta.extra_js = vec!["../config.toml".to_string()];
+        let err = copy_extra_assets(&content_dir, &output_dir, std::iter::once(&page))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "blog/post.md: extra file ../config.toml must be inside the content directory"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_files_are_read_only_when_set() {
+        assert_eq!(read_include(None, "head_html").unwrap(), "");
+        let dir = test_dir("includes");
+        let path = dir.join("head.html");
+        fs::write(&path, "<meta name=\"x\">\n").unwrap();
+        assert_eq!(
+            read_include(path.to_str(), "head_html").unwrap(),
+            "<meta name=\"x\">\n"
+        );
+        let missing = dir.join("missing.html");
+        let err = read_include(missing.to_str(), "body_end_html").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Cannot read body_end_html file {}", missing.display())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
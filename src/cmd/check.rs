@@ -0,0 +1,389 @@
+use crate::cmd::build;
+use crate::links::{collect_ids, percent_decode};
+use crate::utils::Config;
+use anyhow::{Context, Result, bail};
+use colored::Colorize;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+struct Problem {
+    file: String,
+    line: usize,
+    message: String,
+}
+
+pub fn execute(config_path: &str) -> Result<()> {
+    let config_str = fs::read_to_string(config_path).context("Failed to read config file")?;
+    let config: Config = toml::from_str(&config_str).context("Failed to parse config")?;
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let output_dir =
+        std::env::temp_dir().join(format!("mdbear-check-{}-{}", std::process::id(), nanos));
+
+    let result = build::build_site(
+        &config,
+        &output_dir,
+        // Links are checked in the readable markup
+        &build::BuildOptions {
+            minify: Some(false),
+            ..Default::default()
+        },
+    )
+    .and_then(|_| {
+        println!(
+            "\n{} {}",
+            "Checking links in".cyan(),
+            output_dir.display().to_string().cyan()
+        );
+        check_output(&config, &output_dir)
+    });
+    fs::remove_dir_all(&output_dir).ok();
+    let (problems, checked) = result?;
+
+    for problem in &problems {
+        eprintln!(
+            "{}:{}: {}",
+            problem.file.bold(),
+            problem.line,
+            problem.message.red()
+        );
+    }
+
+    if !problems.is_empty() {
+        bail!(
+            "Found {} broken reference(s) in {} checked",
+            problems.len(),
+            checked
+        );
+    }
+
+    println!(
+        "{} {} {}",
+        "Check passed:".green().bold(),
+        checked.to_string().green(),
+        "references verified".green()
+    );
+    Ok(())
+}
+
+/// Crawl every HTML, CSS and feed file in `output_dir` and verify that each internal
+/// reference points to an existing file (and anchor, when it has a fragment).
+fn check_output(config: &Config, output_dir: &Path) -> Result<(Vec<Problem>, usize)> {
+    let attr_re = Regex::new(r#"\b(href|src|srcset|poster)="([^"]*)""#).unwrap();
+    let css_url_re = Regex::new(r#"url\(\s*["']?([^"')]+)["']?\s*\)"#).unwrap();
+    let feed_re = Regex::new(r#"<(link|loc|guid)>([^<]+)</|\bhref="([^"]*)""#).unwrap();
+    let site_url = config
+        .blog_url
+        .as_deref()
+        .unwrap_or("")
+        .trim_end_matches('/')
+        .to_string();
+
+    let mut problems = Vec::new();
+    let mut checked = 0;
+    let mut anchors: HashMap<PathBuf, HashSet<String>> = HashMap::new();
+
+    let mut files: Vec<PathBuf> = WalkDir::new(output_dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect();
+    files.sort();
+
+    for file in &files {
+        let ext = file
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        if !matches!(ext.as_str(), "html" | "css" | "xml") {
+            continue;
+        }
+        let content = fs::read_to_string(file)
+            .with_context(|| format!("Cannot read generated file: {:?}", file))?;
+        let relative = file
+            .strip_prefix(output_dir)
+            .unwrap_or(file)
+            .to_string_lossy()
+            .replace('\\', "/");
+
+        for (line_index, line) in content.lines().enumerate() {
+            let mut references = Vec::new();
+            match ext.as_str() {
+                "html" => {
+                    for caps in attr_re.captures_iter(line) {
+                        if &caps[1] == "srcset" {
+                            references.extend(
+                                caps[2]
+                                    .split(',')
+                                    .filter_map(|candidate| candidate.split_whitespace().next())
+                                    .map(str::to_string),
+                            );
+                        } else {
+                            references.push(caps[2].to_string());
+                        }
+                    }
+                }
+                "css" => {
+                    references.extend(css_url_re.captures_iter(line).map(|c| c[1].to_string()));
+                }
+                _ => {
+                    for caps in feed_re.captures_iter(line) {
+                        let url = caps.get(2).or_else(|| caps.get(3)).unwrap().as_str();
+                        // Feed URLs are absolute; only check those on this site
+                        if let Some(path) = strip_site_url(url, &site_url) {
+                            references.push(format!("/{}", path));
+                        }
+                    }
+                }
+            }
+
+            for reference in references {
+                checked += 1;
+                if let Some(message) = check_reference(output_dir, file, &reference, &mut anchors) {
+                    problems.push(Problem {
+                        file: relative.clone(),
+                        line: line_index + 1,
+                        message,
+                    });
+                }
+            }
+        }
+    }
+
+    Ok((problems, checked))
+}
+
+fn strip_site_url<'a>(url: &'a str, site_url: &str) -> Option<&'a str> {
+    if site_url.is_empty() {
+        return None;
+    }
+    url.trim()
+        .strip_prefix(site_url)
+        .map(|path| path.trim_start_matches('/'))
+}
+
+/// Returns a description of the problem if `reference` (found in `file`) is broken.
+fn check_reference(
+    output_dir: &Path,
+    file: &Path,
+    reference: &str,
+    anchors: &mut HashMap<PathBuf, HashSet<String>>,
+) -> Option<String> {
+    let reference = decode_entities(reference.trim());
+    if reference.is_empty()
+        || reference.starts_with("//")
+        || reference.starts_with("data:")
+        || reference.contains("://")
+        || reference.contains("${")
+        || reference.contains("{{")
+        || reference
+            .split_once(':')
+            .is_some_and(|(scheme, _)| !scheme.contains('/') && !scheme.contains('#'))
+    {
+        return None;
+    }
+
+    let (path, fragment) = match reference.split_once('#') {
+        Some((path, fragment)) => (path, fragment),
+        None => (reference.as_str(), ""),
+    };
+    let path = path.split('?').next().unwrap_or("");
+
+    let target = if path.is_empty() {
+        file.to_path_buf()
+    } else {
+        let base = if path.starts_with('/') {
+            output_dir.to_path_buf()
+        } else {
+            file.parent().unwrap_or(output_dir).to_path_buf()
+        };
+        let Some(resolved) = resolve_path(&base, &percent_decode(path)) else {
+            return Some(format!("`{}` points outside the site", reference));
+        };
+        if !resolved.starts_with(output_dir) {
+            return Some(format!("`{}` points outside the site", reference));
+        }
+        let resolved = if resolved.is_dir() {
+            resolved.join("index.html")
+        } else {
+            resolved
+        };
+        if !resolved.is_file() {
+            return Some(format!("`{}` does not exist", reference));
+        }
+        resolved
+    };
+
+    let is_html = target.extension().and_then(|e| e.to_str()) == Some("html");
+    if fragment.is_empty() || !is_html {
+        return None;
+    }
+    let ids = anchors.entry(target.clone()).or_insert_with(|| {
+        fs::read_to_string(&target)
+            .map(|html| collect_ids(&html))
+            .unwrap_or_default()
+    });
+    if ids.contains(&percent_decode(fragment)) {
+        None
+    } else {
+        Some(format!("`{}` has no anchor #{}", reference, fragment))
+    }
+}
+
+fn resolve_path(base: &Path, relative: &str) -> Option<PathBuf> {
+    let mut resolved = base.to_path_buf();
+    for segment in relative.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                if !resolved.pop() {
+                    return None;
+                }
+            }
+            other => resolved.push(other),
+        }
+    }
+    Some(resolved)
+}
+
+static ENTITY_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"&(#[xX][0-9a-fA-F]+|#[0-9]+|amp|lt|gt|quot|apos);").unwrap());
+
+/// Decode the HTML character references Tera and pulldown-cmark emit in attributes
+fn decode_entities(input: &str) -> String {
+    ENTITY_RE
+        .replace_all(input, |caps: &regex::Captures| {
+            let entity = &caps[1];
+            let decoded = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                    u32::from_str_radix(&entity[2..], 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                }
+                _ => entity[1..].parse().ok().and_then(char::from_u32),
+            };
+            decoded
+                .map(String::from)
+                .unwrap_or_else(|| caps[0].to_string())
+        })
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    /// Check a site made of `files`, as `(file, line, message)` problems
+    fn check(name: &str, files: &[(&str, &str)]) -> (Vec<(String, usize, String)>, usize) {
+        let dir = test_dir(name);
+        for (path, content) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        let config: Config = toml::from_str(
+            "site_icon = \"🐻\"\nsite_name = \"Site\"\nauthor = \"A\"\noutput_dir = \"public\"\nblog_url = \"https://example.com/\"\nnav = []\n",
+        )
+        .unwrap();
+        let (problems, checked) = check_output(&config, &dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let problems = problems
+            .into_iter()
+            .map(|problem| (problem.file, problem.line, problem.message))
+            .collect();
+        (problems, checked)
+    }
+
+    #[test]
+    fn internal_references_resolve_to_files_and_anchors() {
+        let (problems, checked) = check(
+            "check-valid",
+            &[
+                (
+                    "index.html",
+                    "<h2 id=\"café\">Café</h2>\n<a href=\"blog/\">Blog</a> <a href=\"#caf%C3%A9\">top</a>\n\
+                     <img src=\"a.png\" srcset=\"a.png 1x, a.png 2x\"> <a href=\"/index.html?x=1&amp;y=2#caf%C3%A9\">again</a>\n\
+                     <a href=\"https://elsewhere.org/x\">out</a> <a href=\"mailto:a@b.c\">mail</a> <img src=\"data:image/png;base64,AA\">\n",
+                ),
+                ("blog/index.html", "<a href=\"../index.html\">home</a>\n"),
+                ("a.png", ""),
+                ("style.css", "body { background: url('a.png') }\n"),
+                (
+                    "feed.xml",
+                    "<link>https://example.com/blog/</link>\n<link>https://elsewhere.org/</link>\n",
+                ),
+            ],
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(checked, 12);
+    }
+
+    #[test]
+    fn broken_references_are_reported_with_locations() {
+        let (problems, _) = check(
+            "check-broken",
+            &[
+                (
+                    "index.html",
+                    "<h2 id=\"top\">Top</h2>\n<a href=\"#nowhere\">x</a>\n<img src=\"missing.png\"> <a href=\"../up.html\">up</a>\n",
+                ),
+                ("style.css", "\n\nbody { background: url(\"gone.png\") }\n"),
+                ("feed.xml", "<link>https://example.com/old.html</link>\n"),
+            ],
+        );
+        assert_eq!(
+            problems,
+            [
+                (
+                    "feed.xml".to_string(),
+                    1,
+                    "`/old.html` does not exist".to_string()
+                ),
+                (
+                    "index.html".to_string(),
+                    2,
+                    "`#nowhere` has no anchor #nowhere".to_string()
+                ),
+                (
+                    "index.html".to_string(),
+                    3,
+                    "`missing.png` does not exist".to_string()
+                ),
+                (
+                    "index.html".to_string(),
+                    3,
+                    "`../up.html` points outside the site".to_string()
+                ),
+                (
+                    "style.css".to_string(),
+                    3,
+                    "`gone.png` does not exist".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn entities_are_decoded() {
+        assert_eq!(
+            decode_entities("a&amp;b &lt;&gt; &quot;&apos; &#233;&#xE9; &bogus;"),
+            "a&b <> \"' éé &bogus;"
+        );
+    }
+}
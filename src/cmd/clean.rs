@@ -0,0 +1,43 @@
+use crate::cmd::build::validate_output_dir;
+use crate::utils::{CACHE_DIR, Config, STATIC_DIR};
+use anyhow::{Context, Result};
+use colored::Colorize;
+use std::fs;
+use std::path::Path;
+
+pub fn execute(config_path: &str, cache: bool) -> Result<()> {
+    let config_str = fs::read_to_string(config_path).context("Failed to read config file")?;
+    let config: Config = toml::from_str(&config_str).context("Failed to parse config")?;
+    let output_dir = Path::new(&config.output_dir);
+    validate_output_dir(
+        output_dir,
+        Path::new("content"),
+        Path::new("theme"),
+        Path::new(STATIC_DIR),
+    )?;
+
+    let mut targets = vec![output_dir];
+    if cache {
+        targets.push(Path::new(CACHE_DIR));
+    }
+
+    let mut removed = 0;
+    for target in targets {
+        if !target.exists() {
+            continue;
+        }
+        fs::remove_dir_all(target)
+            .with_context(|| format!("Failed to remove {}", target.display()))?;
+        println!(
+            "{} {}",
+            "Removed".green(),
+            target.display().to_string().green()
+        );
+        removed += 1;
+    }
+
+    if removed == 0 {
+        println!("{}", "Nothing to clean".cyan());
+    }
+    Ok(())
+}
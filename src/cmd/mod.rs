@@ -1,4 +1,6 @@
 pub mod build;
+pub mod check;
+pub mod clean;
 pub mod init;
 pub mod serve;
 pub mod sync;
@@ -1,15 +1,21 @@
 use crate::cmd::build;
-use crate::utils::Config;
+use crate::utils::{Config, STATIC_DIR};
 use anyhow::{Context, Result};
 use colored::Colorize;
-use notify::{Config as NotifyConfig, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{Config as NotifyConfig, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use std::fs;
 use std::sync::mpsc;
 use std::time::Duration;
 
 pub async fn execute(port: u16, config_path: &str) -> Result<()> {
+    // Unminified and source-mapped, so that pages and stylesheets stay
+    // readable in the browser
+    let options = build::BuildOptions {
+        minify: Some(false),
+        source_maps: true,
+    };
     println!("{}", "Building...".cyan());
-    build::execute(config_path)?;
+    build::execute(config_path, &options)?;
 
     let config_str = fs::read_to_string(config_path).context("Failed to read config file")?;
     let config: Config = toml::from_str(&config_str).context("Failed to parse config")?;
@@ -54,6 +60,15 @@ pub async fn execute(port: u16, config_path: &str) -> Result<()> {
         );
     }
 
+    if std::path::Path::new(STATIC_DIR).exists() {
+        watcher.watch(std::path::Path::new(STATIC_DIR), RecursiveMode::Recursive)?;
+        println!(
+            "{} {}",
+            "✓".green(),
+            "Watching static directory for changes".green()
+        );
+    }
+
     if std::path::Path::new(config_path).exists() {
         watcher.watch(
             std::path::Path::new(config_path),
@@ -66,14 +81,31 @@ pub async fn execute(port: u16, config_path: &str) -> Result<()> {
         );
     }
 
+    // Include files may live outside the watched directories
+    for include in [&config.head_html, &config.body_end_html]
+        .into_iter()
+        .flatten()
+    {
+        let path = std::path::Path::new(include);
+        if path.is_file()
+            && !["content", "theme", STATIC_DIR]
+                .iter()
+                .any(|dir| path.starts_with(dir))
+        {
+            watcher.watch(path, RecursiveMode::NonRecursive)?;
+        }
+    }
+
     let config_path_for_thread = config_path.to_string();
     tokio::spawn(async move {
         loop {
             match rx.recv() {
                 Ok(event) => match event {
+                    // Reading theme and content files during a build must not trigger another one
+                    Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
                     Ok(_) => {
                         println!("\n{}", "🔄 Detected file change, rebuilding...".blue());
-                        if let Err(e) = build::execute(&config_path_for_thread) {
+                        if let Err(e) = build::execute(&config_path_for_thread, &options) {
                             eprintln!(
                                 "{} {}",
                                 "⚠️".yellow(),
@@ -107,7 +139,7 @@ pub async fn execute(port: u16, config_path: &str) -> Result<()> {
 
     println!(
         "{}",
-        "Watching for changes in content/, theme/, and config.toml...".cyan()
+        "Watching for changes in content/, theme/, static/ and config.toml...".cyan()
     );
 
     let routes = warp::fs::dir(output_dir);
@@ -0,0 +1,769 @@
+use crate::utils::{BuildConfig, root_path_for_url};
+use crate::vendor;
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use colored::Colorize;
+use regex::{Captures, Regex};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+/// Where the policy goes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Delivery {
+    Meta,    // a <meta http-equiv> tag at the top of every page
+    Headers, // a `_headers` file, as read by Netlify and Cloudflare Pages
+}
+
+/// Builds one Content-Security-Policy for the whole site from the final pages.
+/// The client-side navigation keeps the first page's policy while showing the
+/// others, so every page gets the hashes of all of them.
+pub struct ContentSecurityPolicy {
+    delivery: Option<Delivery>,
+    pinned: BTreeMap<String, String>, // `build.integrity`
+}
+
+/// What the policy has to allow, gathered from every page
+#[derive(Default)]
+struct Sources {
+    scripts: BTreeSet<String>,      // hashes of inline <script> blocks
+    styles: BTreeSet<String>,       // hashes of inline <style> blocks
+    script_attrs: BTreeSet<String>, // hashes of on* event handler attributes
+    style_attrs: BTreeSet<String>,  // hashes of style="" attributes
+    origins: BTreeMap<&'static str, BTreeSet<String>>, // directive -> external origins
+}
+
+impl ContentSecurityPolicy {
+    pub fn from_config(config: &BuildConfig) -> Result<Self> {
+        let delivery = match config.csp.as_deref() {
+            None => None,
+            Some("meta") => Some(Delivery::Meta),
+            Some("headers") => Some(Delivery::Headers),
+            Some(other) => bail!(
+                "Unknown build.csp '{}' (expected \"meta\" or \"headers\")",
+                other
+            ),
+        };
+        for (url, pinned) in &config.integrity {
+            if integrity_like(pinned, b"").is_none() {
+                bail!(
+                    "build.integrity for {} is not a sha256-, sha384- or sha512- value: '{}'",
+                    url,
+                    pinned
+                );
+            };
+            // The bundled copy is what the CDN should serve
+            if let Some(file) = vendor::bundled_copy(url)
+                && integrity_like(pinned, &file.data).as_ref() != Some(pinned)
+            {
+                bail!(
+                    "build.integrity for {} does not match the copy bundled with mdBear",
+                    url
+                );
+            }
+        }
+        Ok(ContentSecurityPolicy {
+            delivery,
+            pinned: config.integrity.clone(),
+        })
+    }
+
+    /// Add `integrity` to the external scripts and stylesheets of `pages`, then
+    /// hash their inline scripts, styles and style/event handler attributes and
+    /// write the policy as a meta tag in each page or to `output_dir/_headers`.
+    /// Must run after everything else that rewrites the pages.
+    pub fn apply(&self, pages: &[PathBuf], output_dir: &Path) -> Result<()> {
+        let Some(delivery) = self.delivery else {
+            return Ok(());
+        };
+
+        let mut integrity = Integrity {
+            pinned: &self.pinned,
+            used: BTreeSet::new(),
+        };
+        let mut sources = Sources::default();
+        let mut htmls = Vec::new();
+        for page in pages {
+            let html = fs::read_to_string(page)
+                .with_context(|| format!("Cannot read generated page: {:?}", page))?;
+            let html = add_integrity(&html, &mut integrity);
+            let html = move_placeholders(&html, page, output_dir)?;
+            sources.collect(&html);
+            htmls.push(html);
+        }
+
+        let policy = sources.policy();
+        let head_re = Regex::new(r"(?i)<head(\s[^>]*)?>").unwrap();
+        for (page, html) in pages.iter().zip(htmls) {
+            let html = match delivery {
+                Delivery::Meta => head_re
+                    .replace(&html, |caps: &Captures| {
+                        format!(
+                            "{}\n    <meta http-equiv=\"Content-Security-Policy\" content=\"{}\">",
+                            &caps[0], policy
+                        )
+                    })
+                    .into_owned(),
+                Delivery::Headers => html,
+            };
+            fs::write(page, html)?;
+        }
+        if delivery == Delivery::Headers {
+            // Keep rules from a `_headers` copied out of static/
+            let path = output_dir.join("_headers");
+            let mut headers = fs::read_to_string(&path).unwrap_or_default();
+            if !headers.is_empty() && !headers.ends_with("\n\n") {
+                headers.push_str(if headers.ends_with('\n') {
+                    "\n"
+                } else {
+                    "\n\n"
+                });
+            }
+            headers.push_str(&format!("/*\n  Content-Security-Policy: {}\n", policy));
+            fs::write(&path, headers)?;
+        }
+
+        println!(
+            "  {} {}",
+            "Content-Security-Policy:".green(),
+            format!(
+                "{} script and {} style hash(es), {} attribute hash(es), {}",
+                sources.scripts.len(),
+                sources.styles.len(),
+                sources.script_attrs.len() + sources.style_attrs.len(),
+                match delivery {
+                    Delivery::Meta => "in a meta tag on every page",
+                    Delivery::Headers => "in _headers",
+                }
+            )
+            .green()
+        );
+        if !integrity.used.is_empty() {
+            println!(
+                "  {} {}",
+                "Added integrity to".green(),
+                format!("{} external resource(s)", integrity.used.len()).green()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Sources {
+    fn collect(&mut self, html: &str) {
+        let mut markup_start = 0;
+        for caps in RAW_TEXT_RE.captures_iter(html) {
+            let whole = caps.get(0).unwrap();
+            self.collect_markup(&html[markup_start..whole.start()]);
+            markup_start = whole.end();
+            if let Some(body) = caps.get(2) {
+                let attrs = attributes(caps.get(1).map_or("", |m| m.as_str()));
+                match attrs.get("src") {
+                    Some(src) => self.add_origin("script-src", src),
+                    None => {
+                        self.scripts.insert(hash(body.as_str()));
+                    }
+                }
+            } else if let Some(body) = caps.get(4) {
+                self.styles.insert(hash(body.as_str()));
+            }
+        }
+        self.collect_markup(&html[markup_start..]);
+    }
+
+    /// Attributes of the tags outside <script> and <style>
+    fn collect_markup(&mut self, markup: &str) {
+        for tag in TAG_RE.captures_iter(markup) {
+            let name = tag[1].to_ascii_lowercase();
+            let attrs = attributes(&tag[2]);
+            for (key, value) in &attrs {
+                if key == "style" {
+                    self.style_attrs.insert(hash(value));
+                } else if key.starts_with("on") {
+                    self.script_attrs.insert(hash(value));
+                }
+            }
+            let url = |key: &str| attrs.get(key).map(String::as_str);
+            match name.as_str() {
+                "link" => {
+                    let rel = url("rel").unwrap_or("").to_ascii_lowercase();
+                    if rel.split_whitespace().any(|rel| rel == "stylesheet") {
+                        if let Some(href) = url("href") {
+                            // Stylesheets from a CDN load their fonts from it too
+                            self.add_origin("style-src", href);
+                            self.add_origin("font-src", href);
+                        }
+                    } else if rel.split_whitespace().any(|rel| rel == "icon")
+                        && let Some(href) = url("href")
+                    {
+                        self.add_origin("img-src", href);
+                    }
+                }
+                "img" => {
+                    if let Some(src) = url("src") {
+                        self.add_origin("img-src", src);
+                    }
+                    if let Some(srcset) = url("srcset") {
+                        for candidate in srcset.split(',') {
+                            if let Some(src) = candidate.split_whitespace().next() {
+                                self.add_origin("img-src", src);
+                            }
+                        }
+                    }
+                }
+                "iframe" | "video" | "audio" | "source" | "track" => {
+                    let directive = if name == "iframe" {
+                        "frame-src"
+                    } else {
+                        "media-src"
+                    };
+                    if let Some(src) = url("src") {
+                        self.add_origin(directive, src);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn add_origin(&mut self, directive: &'static str, url: &str) {
+        if let Some(origin) = origin(url) {
+            self.origins.entry(directive).or_default().insert(origin);
+        }
+    }
+
+    fn policy(&self) -> String {
+        let origins = |directive: &str| {
+            self.origins
+                .get(directive)
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+        };
+        let hashes = |set: &BTreeSet<String>| {
+            set.iter()
+                .map(|hash| format!("'sha256-{}'", hash))
+                .collect::<Vec<_>>()
+        };
+
+        let mut script_src = vec!["'self'".to_string()];
+        script_src.extend(hashes(&self.scripts));
+        if !self.script_attrs.is_empty() {
+            script_src.push("'unsafe-hashes'".to_string());
+            script_src.extend(hashes(&self.script_attrs));
+        }
+        script_src.extend(origins("script-src").into_iter().map(String::from));
+        let mut style_src = vec!["'self'".to_string()];
+        style_src.extend(hashes(&self.styles));
+        if !self.style_attrs.is_empty() {
+            style_src.push("'unsafe-hashes'".to_string());
+            style_src.extend(hashes(&self.style_attrs));
+        }
+        style_src.extend(origins("style-src").into_iter().map(String::from));
+
+        let mut directives = vec![
+            "default-src 'self'".to_string(),
+            format!("script-src {}", script_src.join(" ")),
+            format!("style-src {}", style_src.join(" ")),
+        ];
+        for (directive, base) in [
+            ("img-src", "'self' data:"),
+            ("font-src", "'self' data:"),
+            // Analytics scripts usually report back to where they came from
+            ("connect-src", "'self'"),
+            ("media-src", "'self'"),
+            ("frame-src", "'self'"),
+        ] {
+            let mut sources = origins(directive);
+            if directive == "connect-src" {
+                sources.extend(origins("script-src"));
+                sources.sort();
+                sources.dedup();
+            }
+            if sources.is_empty() && !base.contains("data:") {
+                continue; // default-src covers it
+            }
+            directives.push(
+                format!("{} {} {}", directive, base, sources.join(" "))
+                    .trim_end()
+                    .to_string(),
+            );
+        }
+        directives.extend([
+            "object-src 'none'".to_string(),
+            "base-uri 'self'".to_string(),
+            "form-action 'self'".to_string(),
+        ]);
+        directives.join("; ")
+    }
+}
+
+/// `<script ...>...</script>` and `<style ...>...</style>`, whose contents are
+/// not markup
+static RAW_TEXT_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?is)<script(\s[^>]*)?>(.*?)</script\s*>|<style(\s[^>]*)?>(.*?)</style\s*>")
+        .unwrap()
+});
+
+/// An opening tag with its attributes, which may be unquoted after minifying
+pub(crate) static TAG_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"<([a-zA-Z][a-zA-Z0-9-]*)((?:\s+[^\s"'>/=]+(?:\s*=\s*(?:"[^"]*"|'[^']*'|[^\s"'=<>`]+))?)*)\s*/?>"#,
+    )
+    .unwrap()
+});
+
+static ATTR_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"([^\s"'>/=]+)(?:\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'=<>`]+)))?"#).unwrap()
+});
+
+static ENTITY_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"&(#[xX][0-9a-fA-F]+|#[0-9]+|amp|lt|gt|quot|apos);").unwrap());
+
+/// Attribute names (lowercased) and their decoded values
+pub(crate) fn attributes(attrs: &str) -> BTreeMap<String, String> {
+    ATTR_RE
+        .captures_iter(attrs)
+        .map(|caps| {
+            let value = caps
+                .get(2)
+                .or(caps.get(3))
+                .or(caps.get(4))
+                .map_or("", |m| m.as_str());
+            (caps[1].to_ascii_lowercase(), decode_entities(value))
+        })
+        .collect()
+}
+
+/// Browsers hash attribute values after decoding character references
+fn decode_entities(value: &str) -> String {
+    ENTITY_RE
+        .replace_all(value, |caps: &Captures| {
+            let entity = &caps[1];
+            let decoded = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ => match entity[1..].strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => entity[1..].parse().ok(),
+                }
+                .and_then(char::from_u32),
+            };
+            decoded.map_or(caps[0].to_string(), String::from)
+        })
+        .into_owned()
+}
+
+/// `https://cdn.example.com` for an absolute URL, or a bare host for a
+/// scheme-relative one; `None` for files on this site
+fn origin(url: &str) -> Option<String> {
+    if let Some(rest) = url.strip_prefix("//") {
+        return rest.split(['/', '?', '#']).next().map(String::from);
+    }
+    let (scheme, rest) = url.split_once("://")?;
+    if !matches!(scheme, "http" | "https") {
+        return None;
+    }
+    let host = rest.split(['/', '?', '#']).next()?;
+    Some(format!("{}://{}", scheme, host))
+}
+
+fn hash(content: &str) -> String {
+    STANDARD.encode(Sha256::digest(content.as_bytes()))
+}
+
+/// The integrity value of `content` with the algorithm of `like`, e.g. a
+/// `sha384-` one for a `sha384-` value; `None` for other algorithms
+fn integrity_like(like: &str, content: &[u8]) -> Option<String> {
+    let (algorithm, _) = like.split_once('-')?;
+    let digest = match algorithm {
+        "sha256" => Sha256::digest(content).to_vec(),
+        "sha384" => Sha384::digest(content).to_vec(),
+        "sha512" => Sha512::digest(content).to_vec(),
+        _ => return None,
+    };
+    Some(format!("{}-{}", algorithm, STANDARD.encode(digest)))
+}
+
+/// Move the placeholder backgrounds of processed images out of their `style`
+/// attributes into a stylesheet for the page, `placeholders/<hash>.css`, so
+/// that the policy does not need a hash for every image on the site
+fn move_placeholders(html: &str, page: &Path, output_dir: &Path) -> Result<String> {
+    let mut rules = String::new();
+    let html = TAG_RE.replace_all(html, |tag: &Captures| {
+        let attrs = tag.get(2).unwrap();
+        let style = ATTR_RE
+            .captures_iter(attrs.as_str())
+            .find(|attr| attr[1].eq_ignore_ascii_case("style"));
+        let placeholder = tag[1].eq_ignore_ascii_case("img")
+            && attributes(attrs.as_str()).contains_key("data-dominant-color");
+        let Some(style) = style.filter(|_| placeholder) else {
+            return tag[0].to_string();
+        };
+        let index = rules.lines().count();
+        let value = style.get(2).or(style.get(3)).or(style.get(4));
+        rules.push_str(&format!(
+            "img[data-placeholder=\"{}\"] {{ {} }}\n",
+            index,
+            decode_entities(value.map_or("", |m| m.as_str()))
+        ));
+        let style = style.get(0).unwrap().range();
+        let start = attrs.start() - tag.get(0).unwrap().start();
+        format!(
+            "{}data-placeholder=\"{}\"{}",
+            &tag[0][..start + style.start],
+            index,
+            &tag[0][start + style.end..]
+        )
+    });
+    if rules.is_empty() {
+        return Ok(html.into_owned());
+    }
+
+    let file = format!(
+        "placeholders/{}.css",
+        &format!("{:x}", Sha256::digest(rules.as_bytes()))[..16]
+    );
+    fs::create_dir_all(output_dir.join("placeholders"))?;
+    fs::write(output_dir.join(&file), rules)?;
+    let url = page.strip_prefix(output_dir).unwrap_or(page);
+    let link = format!(
+        "    <link id=\"mdbear-placeholders\" rel=\"stylesheet\" href=\"{}/{}\">\n",
+        root_path_for_url(&url.to_string_lossy()),
+        file
+    );
+    Ok(match html.find("</head>") {
+        Some(end) => format!("{}{}{}", &html[..end], link, &html[end..]),
+        None => html.into_owned(),
+    })
+}
+
+/// Add `integrity` and `crossorigin` to external `<script src>` and
+/// `<link rel="stylesheet">` tags that have no `integrity` yet
+fn add_integrity(html: &str, integrity: &mut Integrity) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut markup_start = 0;
+    for caps in RAW_TEXT_RE.captures_iter(html) {
+        let whole = caps.get(0).unwrap();
+        out.push_str(&add_link_integrity(
+            &html[markup_start..whole.start()],
+            integrity,
+        ));
+        markup_start = whole.end();
+        match caps.get(1).filter(|_| caps.get(2).is_some()) {
+            Some(attrs) => {
+                let tag_end = attrs.end() - whole.start();
+                let attributes = attributes(attrs.as_str());
+                let addition = attributes
+                    .get("src")
+                    .and_then(|src| integrity_attributes(&attributes, src, integrity));
+                out.push_str(&whole.as_str()[..tag_end]);
+                out.push_str(addition.as_deref().unwrap_or(""));
+                out.push_str(&whole.as_str()[tag_end..]);
+            }
+            None => out.push_str(whole.as_str()),
+        }
+    }
+    out.push_str(&add_link_integrity(&html[markup_start..], integrity));
+    out
+}
+
+fn add_link_integrity(markup: &str, integrity: &mut Integrity) -> String {
+    TAG_RE
+        .replace_all(markup, |tag: &Captures| {
+            let attributes = attributes(&tag[2]);
+            let stylesheet = tag[1].eq_ignore_ascii_case("link")
+                && attributes.get("rel").is_some_and(|rel| {
+                    rel.split_whitespace()
+                        .any(|rel| rel.eq_ignore_ascii_case("stylesheet"))
+                });
+            let addition = attributes
+                .get("href")
+                .filter(|_| stylesheet)
+                .and_then(|href| integrity_attributes(&attributes, href, integrity));
+            let Some(addition) = addition else {
+                return tag[0].to_string();
+            };
+            let attrs_end = tag.get(2).unwrap().end() - tag.get(0).unwrap().start();
+            format!(
+                "{}{}{}",
+                &tag[0][..attrs_end],
+                addition,
+                &tag[0][attrs_end..]
+            )
+        })
+        .into_owned()
+}
+
+/// ` integrity="sha384-..." crossorigin="anonymous"` for an external `url`
+fn integrity_attributes(
+    attributes: &BTreeMap<String, String>,
+    url: &str,
+    integrity: &mut Integrity,
+) -> Option<String> {
+    if attributes.contains_key("integrity") {
+        return None;
+    }
+    let url = match url.strip_prefix("//") {
+        Some(rest) => format!("https://{}", rest),
+        None if origin(url).is_some() => url.to_string(),
+        None => return None,
+    };
+    let hash = integrity.get(&url)?;
+    let crossorigin = if attributes.contains_key("crossorigin") {
+        ""
+    } else {
+        " crossorigin=\"anonymous\""
+    };
+    Some(format!(" integrity=\"{}\"{}", hash, crossorigin))
+}
+
+/// Integrity values of external files: for the theme's libraries, hashed
+/// from the copies bundled with mdBear, and for others, as pinned under
+/// `build.integrity`. Nothing is fetched, so a changed CDN file fails to load
+/// rather than being trusted.
+struct Integrity<'a> {
+    pinned: &'a BTreeMap<String, String>,
+    used: BTreeSet<String>, // URLs given an integrity value in this build
+}
+
+impl Integrity<'_> {
+    fn get(&mut self, url: &str) -> Option<String> {
+        let integrity = match vendor::bundled_copy(url) {
+            Some(file) => integrity_like("sha384-", &file.data),
+            None => self.pinned.get(url).cloned(),
+        }?;
+        self.used.insert(url.to_string());
+        Some(integrity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    fn policy(toml: &str) -> Result<ContentSecurityPolicy> {
+        ContentSecurityPolicy::from_config(&toml::from_str(toml).unwrap())
+    }
+
+    /// Write `pages` under a fresh output directory and apply the policy to them
+    fn apply(name: &str, toml: &str, pages: &[(&str, &str)]) -> PathBuf {
+        let dir = test_dir(name);
+        let paths: Vec<PathBuf> = pages
+            .iter()
+            .map(|(path, html)| {
+                let path = dir.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, html).unwrap();
+                path
+            })
+            .collect();
+        policy(toml).unwrap().apply(&paths, &dir).unwrap();
+        dir
+    }
+
+    fn meta_policy(html: &str) -> &str {
+        html.split_once("<head>\n    <meta http-equiv=\"Content-Security-Policy\" content=\"")
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .expect("no CSP meta tag at the top of <head>")
+            .0
+    }
+
+    #[test]
+    fn inline_code_is_hashed_and_external_origins_allowed_on_every_page() {
+        let dir = apply(
+            "csp-meta",
+            "csp = \"meta\"",
+            &[
+                (
+                    "index.html",
+                    "<html><head><script>let a = 1;</script><style>p { color: red; }</style>\
+                     <link rel=\"stylesheet\" href=\"https://fonts.example.com/a.css\"></head>\
+                     <body><button onclick=\"go(&quot;home&quot;)\" style=\"color: red\">Go</button>\
+                     <img src=\"https://images.example.com/a.jpg\"></body></html>",
+                ),
+                (
+                    "blog/post.html",
+                    "<html><head><script src=\"//stats.example.com/s.js\"></script></head>\
+                     <body><iframe src=\"https://video.example.com/embed\"></iframe></body></html>",
+                ),
+            ],
+        );
+
+        let index = fs::read_to_string(dir.join("index.html")).unwrap();
+        let policy = meta_policy(&index);
+        assert_eq!(
+            policy,
+            format!(
+                "default-src 'self'; \
+                 script-src 'self' 'sha256-{}' 'unsafe-hashes' 'sha256-{}' stats.example.com; \
+                 style-src 'self' 'sha256-{}' 'unsafe-hashes' 'sha256-{}' https://fonts.example.com; \
+                 img-src 'self' data: https://images.example.com; \
+                 font-src 'self' data: https://fonts.example.com; \
+                 connect-src 'self' stats.example.com; \
+                 frame-src 'self' https://video.example.com; \
+                 object-src 'none'; base-uri 'self'; form-action 'self'",
+                hash("let a = 1;"),
+                hash("go(\"home\")"),
+                hash("p { color: red; }"),
+                hash("color: red"),
+            )
+        );
+        let post = fs::read_to_string(dir.join("blog/post.html")).unwrap();
+        assert_eq!(meta_policy(&post), policy);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn headers_policy_follows_the_static_rules() {
+        let dir = test_dir("csp-headers");
+        fs::write(
+            dir.join("_headers"),
+            "/assets/*\n  Cache-Control: max-age=31536000\n",
+        )
+        .unwrap();
+        let page = dir.join("index.html");
+        fs::write(
+            &page,
+            "<html><head><script>let a = 1;</script></head></html>",
+        )
+        .unwrap();
+
+        policy("csp = \"headers\"")
+            .unwrap()
+            .apply(std::slice::from_ref(&page), &dir)
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("_headers")).unwrap(),
+            format!(
+                "/assets/*\n  Cache-Control: max-age=31536000\n\n/*\n  Content-Security-Policy: \
+                 default-src 'self'; script-src 'self' 'sha256-{}'; style-src 'self'; \
+                 img-src 'self' data:; font-src 'self' data:; \
+                 object-src 'none'; base-uri 'self'; form-action 'self'\n",
+                hash("let a = 1;")
+            )
+        );
+        assert!(!fs::read_to_string(&page).unwrap().contains("http-equiv"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn image_placeholders_move_to_a_stylesheet() {
+        let dir = apply(
+            "csp-placeholders",
+            "csp = \"meta\"",
+            &[(
+                "blog/post.html",
+                "<html><head>\n</head><body>\
+                 <img src=\"../a.jpg\" style=\"background: #112233\" data-dominant-color=\"#112233\">\
+                 <img src=\"../b.jpg\" style=\"width: 50%\"></body></html>",
+            )],
+        );
+
+        let post = fs::read_to_string(dir.join("blog/post.html")).unwrap();
+        assert!(
+            post.contains("<img src=\"../a.jpg\" data-placeholder=\"0\" data-dominant-color"),
+            "{}",
+            post
+        );
+        let stylesheet = post
+            .split_once("<link id=\"mdbear-placeholders\" rel=\"stylesheet\" href=\"../")
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .expect("no placeholder stylesheet")
+            .0;
+        assert!(stylesheet.starts_with("placeholders/"), "{}", stylesheet);
+        assert_eq!(
+            fs::read_to_string(dir.join(stylesheet)).unwrap(),
+            "img[data-placeholder=\"0\"] { background: #112233 }\n"
+        );
+        // Other style attributes stay, with a hash each
+        let policy = meta_policy(&post);
+        assert!(policy.contains(&hash("width: 50%")), "{}", policy);
+        assert!(!policy.contains(&hash("background: #112233")), "{}", policy);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pinned_external_files_get_integrity() {
+        let chart = "https://cdn.example.com/chart.js";
+        let dir = apply(
+            "csp-integrity",
+            &format!(
+                "csp = \"meta\"\n\n[integrity]\n\"{}\" = \"sha384-pinned\"\n\"https://cdn.example.com/a.css\" = \"sha256-css\"",
+                chart
+            ),
+            &[(
+                "index.html",
+                "<html><head><script src=\"//cdn.example.com/chart.js\"></script>\
+                 <script src=\"https://cdn.example.com/other.js\"></script>\
+                 <link rel=\"stylesheet\" href=\"https://cdn.example.com/a.css\" crossorigin=\"use-credentials\">\
+                 <script src=\"https://cdn.example.com/chart.js\" integrity=\"sha512-own\"></script></head></html>",
+            )],
+        );
+
+        let index = fs::read_to_string(dir.join("index.html")).unwrap();
+        assert!(
+            index.contains("<script src=\"//cdn.example.com/chart.js\" integrity=\"sha384-pinned\" crossorigin=\"anonymous\"></script>"),
+            "{}",
+            index
+        );
+        assert!(
+            index.contains("<script src=\"https://cdn.example.com/other.js\"></script>"),
+            "{}",
+            index
+        );
+        assert!(
+            index.contains("href=\"https://cdn.example.com/a.css\" crossorigin=\"use-credentials\" integrity=\"sha256-css\">"),
+            "{}",
+            index
+        );
+        assert!(
+            index.contains(&format!(
+                "<script src=\"{}\" integrity=\"sha512-own\"></script>",
+                chart
+            )),
+            "{}",
+            index
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn settings_are_validated() {
+        let error = |toml: &str| policy(toml).err().unwrap().to_string();
+        assert_eq!(
+            error("csp = \"nonce\""),
+            "Unknown build.csp 'nonce' (expected \"meta\" or \"headers\")"
+        );
+        assert_eq!(
+            error("[integrity]\n\"https://cdn.example.com/a.js\" = \"md5-pinned\""),
+            "build.integrity for https://cdn.example.com/a.js is not a sha256-, sha384- or sha512- value: 'md5-pinned'"
+        );
+        assert!(policy("").unwrap().delivery.is_none());
+    }
+
+    #[test]
+    fn attributes_are_decoded_whether_quoted_or_not() {
+        let attrs =
+            attributes(" SRC=a.js data-x='&lt;b&gt;' title=\"caf&#233; &#x26; &bogus;\" defer");
+        assert_eq!(attrs["src"], "a.js");
+        assert_eq!(attrs["data-x"], "<b>");
+        assert_eq!(attrs["title"], "café & &bogus;");
+        assert_eq!(attrs["defer"], "");
+        assert_eq!(
+            origin("https://cdn.example.com:8443/a.js?v=1"),
+            Some("https://cdn.example.com:8443".to_string())
+        );
+        assert_eq!(origin("data:image/png;base64,AAAA"), None);
+        assert_eq!(origin("../a.js"), None);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::images::decode_oriented;
+use crate::utils::Config;
+use anyhow::{Context, Result, bail};
+use colored::Colorize;
+use image::codecs::ico::{IcoEncoder, IcoFrame};
+use image::imageops::{self, Lanczos3};
+use image::{DynamicImage, ExtendedColorType, GenericImageView, ImageFormat, Rgba, RgbaImage};
+use serde::Serialize;
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+
+/// Sizes packed into `favicon.ico`
+const ICO_SIZES: [u32; 3] = [16, 32, 48];
+/// `icon-<size>.png` files, listed in `site.webmanifest` for Android
+const MANIFEST_SIZES: [u32; 2] = [192, 512];
+const APPLE_TOUCH_SIZE: u32 = 180;
+
+/// A `<link>` tag for `base.html`, from the `icon_links` template variable
+#[derive(Serialize, Debug, Clone)]
+pub struct IconLink {
+    pub rel: &'static str,
+    pub href: String, // relative to the site root
+    pub sizes: Option<String>,
+    #[serde(rename = "type")]
+    pub mime: Option<&'static str>,
+}
+
+/// Whether `site_icon` names an image file rather than an emoji
+fn is_icon_file(site_icon: &str) -> bool {
+    Path::new(site_icon).extension().is_some_and(|ext| {
+        ImageFormat::from_extension(ext).is_some() || ext.eq_ignore_ascii_case("svg")
+    })
+}
+
+/// When `site_icon` is an image file, write `favicon.ico`, the PNG touch icons
+/// and `site.webmanifest` to `output_dir` and return the tags linking them.
+/// An emoji `site_icon` returns no tags, and the theme shows it as an SVG.
+pub fn generate_site_icons(config: &Config, output_dir: &Path) -> Result<Vec<IconLink>> {
+    if !is_icon_file(&config.site_icon) {
+        return Ok(Vec::new());
+    }
+    let source = Path::new(&config.site_icon);
+    if source
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"))
+    {
+        bail!(
+            "site_icon {} is an SVG, which cannot be turned into favicon.ico and PNG icons; use a PNG of at least 512x512",
+            source.display()
+        );
+    }
+    let bytes =
+        fs::read(source).with_context(|| format!("Cannot read site_icon {}", source.display()))?;
+    let image = decode_oriented(&bytes)
+        .with_context(|| format!("Cannot decode site_icon {}", source.display()))?;
+    let (width, height) = image.dimensions();
+    if width.min(height) < 512 {
+        eprintln!(
+            "{} {}",
+            "Warning: site_icon".yellow(),
+            format!(
+                "{} is {}x{}; icons are scaled up to 512x512 and may look blurry",
+                source.display(),
+                width,
+                height
+            )
+            .yellow()
+        );
+    }
+
+    let frames = ICO_SIZES
+        .iter()
+        .map(|&size| {
+            let icon = square(&image, size);
+            IcoFrame::as_png(icon.as_raw(), size, size, ExtendedColorType::Rgba8)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    IcoEncoder::new(fs::File::create(output_dir.join("favicon.ico"))?).encode_images(&frames)?;
+
+    let mut links = vec![IconLink {
+        rel: "icon",
+        href: "favicon.ico".to_string(),
+        sizes: Some(
+            ICO_SIZES
+                .iter()
+                .map(|size| format!("{0}x{0}", size))
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+        mime: None,
+    }];
+    let mut manifest_icons = Vec::new();
+    for size in MANIFEST_SIZES {
+        let name = format!("icon-{}.png", size);
+        square(&image, size).save(output_dir.join(&name))?;
+        manifest_icons.push(json!({
+            "src": name,
+            "sizes": format!("{0}x{0}", size),
+            "type": "image/png",
+        }));
+        links.push(IconLink {
+            rel: "icon",
+            href: name,
+            sizes: Some(format!("{0}x{0}", size)),
+            mime: Some("image/png"),
+        });
+    }
+
+    // iOS shows transparent areas as black, so the touch icon gets a white background
+    let mut touch = RgbaImage::from_pixel(
+        APPLE_TOUCH_SIZE,
+        APPLE_TOUCH_SIZE,
+        Rgba([255, 255, 255, 255]),
+    );
+    imageops::overlay(&mut touch, &square(&image, APPLE_TOUCH_SIZE), 0, 0);
+    DynamicImage::ImageRgba8(touch)
+        .to_rgb8()
+        .save(output_dir.join("apple-touch-icon.png"))?;
+    links.push(IconLink {
+        rel: "apple-touch-icon",
+        href: "apple-touch-icon.png".to_string(),
+        sizes: Some(format!("{0}x{0}", APPLE_TOUCH_SIZE)),
+        mime: None,
+    });
+
+    let manifest = json!({
+        "name": config.site_name,
+        "short_name": config.site_name,
+        "icons": manifest_icons,
+    });
+    fs::write(
+        output_dir.join("site.webmanifest"),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+    links.push(IconLink {
+        rel: "manifest",
+        href: "site.webmanifest".to_string(),
+        sizes: None,
+        mime: None,
+    });
+
+    println!(
+        "  {} {}",
+        "Generated site icons from".green(),
+        source.display().to_string().green()
+    );
+    Ok(links)
+}
+
+/// `image` scaled to fit a transparent `size` x `size` square, centered
+fn square(image: &DynamicImage, size: u32) -> RgbaImage {
+    let scaled = image.resize(size, size, Lanczos3).to_rgba8();
+    let mut canvas = RgbaImage::new(size, size);
+    let x = (size - scaled.width()) / 2;
+    let y = (size - scaled.height()) / 2;
+    imageops::overlay(&mut canvas, &scaled, x.into(), y.into());
+    canvas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    fn config(site_icon: &Path) -> Config {
+        toml::from_str(&format!(
+            "site_icon = {:?}\nsite_name = \"Site\"\nauthor = \"A\"\noutput_dir = \"public\"\nnav = []\n",
+            site_icon.display().to_string()
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn image_icons_become_favicons_touch_icons_and_a_manifest() {
+        let dir = test_dir("favicons");
+        let source = dir.join("icon.png");
+        RgbaImage::from_pixel(600, 300, Rgba([200, 0, 0, 255]))
+            .save(&source)
+            .unwrap();
+
+        let links = generate_site_icons(&config(&source), &dir).unwrap();
+
+        let links = links
+            .iter()
+            .map(|link| {
+                (
+                    link.rel,
+                    link.href.as_str(),
+                    link.sizes.as_deref(),
+                    link.mime,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            links,
+            [
+                ("icon", "favicon.ico", Some("16x16 32x32 48x48"), None),
+                ("icon", "icon-192.png", Some("192x192"), Some("image/png")),
+                ("icon", "icon-512.png", Some("512x512"), Some("image/png")),
+                (
+                    "apple-touch-icon",
+                    "apple-touch-icon.png",
+                    Some("180x180"),
+                    None
+                ),
+                ("manifest", "site.webmanifest", None, None),
+            ]
+        );
+        for (name, size) in [
+            ("icon-192.png", 192),
+            ("icon-512.png", 512),
+            ("apple-touch-icon.png", 180),
+            ("favicon.ico", 48),
+        ] {
+            assert_eq!(
+                image::image_dimensions(dir.join(name)).unwrap(),
+                (size, size),
+                "{}",
+                name
+            );
+        }
+        // Wide icons are centered on a transparent square, or a white one for iOS
+        let icon = image::open(dir.join("icon-512.png")).unwrap().to_rgba8();
+        assert_eq!(icon.get_pixel(0, 0), &Rgba([0, 0, 0, 0]));
+        assert_eq!(icon.get_pixel(256, 256), &Rgba([200, 0, 0, 255]));
+        let touch = image::open(dir.join("apple-touch-icon.png"))
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(touch.get_pixel(0, 0), &Rgba([255, 255, 255, 255]));
+
+        let manifest: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("site.webmanifest")).unwrap())
+                .unwrap();
+        assert_eq!(manifest["name"], "Site");
+        assert_eq!(manifest["icons"][1]["src"], "icon-512.png");
+        assert_eq!(manifest["icons"][1]["sizes"], "512x512");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn emoji_icons_need_no_files_and_svg_icons_are_rejected() {
+        let dir = test_dir("favicons-emoji");
+        assert!(
+            generate_site_icons(&config(Path::new("🐻")), &dir)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        let err = generate_site_icons(&config(Path::new("icon.svg")), &dir).unwrap_err();
+        assert!(err.to_string().contains("is an SVG"), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
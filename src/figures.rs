@@ -0,0 +1,224 @@
+use crate::utils::FiguresConfig;
+use anyhow::{Result, bail};
+use colored::Colorize;
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CaptionSource {
+    Title,
+    Alt,
+    None,
+}
+
+/// Validated `[figures]` settings
+#[derive(Debug, Clone)]
+pub struct Figures {
+    caption: CaptionSource,
+    numbering: bool,
+    label: String,
+}
+
+impl Default for Figures {
+    fn default() -> Self {
+        Figures {
+            caption: CaptionSource::Title,
+            numbering: false,
+            label: "Figure".to_string(),
+        }
+    }
+}
+
+impl Figures {
+    pub fn from_config(config: &FiguresConfig) -> Result<Self> {
+        let caption = match config.caption.as_deref().unwrap_or("title") {
+            "title" => CaptionSource::Title,
+            "alt" => CaptionSource::Alt,
+            "none" => CaptionSource::None,
+            other => bail!(
+                "Unknown [figures] caption `{}` (expected title, alt or none)",
+                other
+            ),
+        };
+        Ok(Figures {
+            caption,
+            numbering: config.numbering.unwrap_or(false),
+            label: config
+                .label
+                .clone()
+                .unwrap_or_else(|| Figures::default().label),
+        })
+    }
+
+    /// Wrap every image that sits alone in a paragraph in a `<figure>`, captioned
+    /// by its title or alt text. With numbering, captioned or labelled figures
+    /// (`![alt](img.png "caption") {#fig:name}`) are numbered and `[@fig:name]`
+    /// becomes a link to the figure.
+    pub fn render(&self, html: &str, source_path: &str) -> String {
+        let figure_re = Regex::new(
+            r#"<p><img src="([^"]*)" alt="([^"]*)"(?: title="([^"]*)")? />(?:\s*\{#fig:([A-Za-z0-9_-]+)\})?</p>"#,
+        )
+        .unwrap();
+        let mut count = 0;
+        let mut labels: HashMap<String, usize> = HashMap::new();
+
+        let html = figure_re
+            .replace_all(html, |caps: &Captures| {
+                let (src, alt) = (&caps[1], &caps[2]);
+                let title = caps.get(3).map(|m| m.as_str()).unwrap_or_default();
+                let label = caps.get(4).map(|m| m.as_str());
+                let caption = match self.caption {
+                    CaptionSource::Title => title,
+                    CaptionSource::Alt => alt,
+                    CaptionSource::None => "",
+                };
+                // The title becomes the caption, so it is not repeated as a tooltip
+                let img = if self.caption == CaptionSource::Title || title.is_empty() {
+                    format!("<img src=\"{}\" alt=\"{}\" />", src, alt)
+                } else {
+                    format!(
+                        "<img src=\"{}\" alt=\"{}\" title=\"{}\" />",
+                        src, alt, title
+                    )
+                };
+
+                let number =
+                    (self.numbering && (!caption.is_empty() || label.is_some())).then(|| {
+                        count += 1;
+                        count
+                    });
+                let id = match (label, number) {
+                    (Some(label), _) => format!(" id=\"fig-{}\"", label),
+                    (None, Some(number)) => format!(" id=\"figure-{}\"", number),
+                    (None, None) => String::new(),
+                };
+                if let (Some(label), Some(number)) = (label, number)
+                    && labels.insert(label.to_string(), number).is_some()
+                {
+                    eprintln!(
+                        "{} content/{}: figure label `fig:{}` is used more than once",
+                        "Warning:".yellow(),
+                        source_path,
+                        label
+                    );
+                }
+
+                let figcaption = match number {
+                    Some(number) if caption.is_empty() => format!(
+                        "<figcaption><span class=\"figure-number\">{} {}</span></figcaption>",
+                        self.label, number
+                    ),
+                    Some(number) => format!(
+                        "<figcaption><span class=\"figure-number\">{} {}:</span> {}</figcaption>",
+                        self.label, number, caption
+                    ),
+                    None if caption.is_empty() => String::new(),
+                    None => format!("<figcaption>{}</figcaption>", caption),
+                };
+                format!(
+                    "<figure class=\"markdown-figure\"{}>{}{}</figure>",
+                    id, img, figcaption
+                )
+            })
+            .to_string();
+
+        if !self.numbering {
+            return html;
+        }
+
+        // Skip code so that examples of the syntax are left alone
+        let reference_re =
+            Regex::new(r"(?s)<code[^>]*>.*?</code>|\[@fig:([A-Za-z0-9_-]+)\]").unwrap();
+        reference_re
+            .replace_all(&html, |caps: &Captures| {
+                let Some(label) = caps.get(1).map(|m| m.as_str()) else {
+                    return caps[0].to_string();
+                };
+                match labels.get(label) {
+                    Some(number) => format!(
+                        "<a class=\"figure-ref\" href=\"#fig-{}\">{} {}</a>",
+                        label, self.label, number
+                    ),
+                    None => {
+                        eprintln!(
+                            "{} content/{}: `[@fig:{}]` matches no figure on the page",
+                            "Warning:".yellow(),
+                            source_path,
+                            label
+                        );
+                        caps[0].to_string()
+                    }
+                }
+            })
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn figures(caption: &str, numbering: bool, label: Option<&str>) -> Figures {
+        Figures::from_config(&FiguresConfig {
+            caption: Some(caption.to_string()),
+            numbering: Some(numbering),
+            label: label.map(str::to_string),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn standalone_images_become_captioned_figures() {
+        let html = "<p><img src=\"a.png\" alt=\"Alt\" title=\"Title\" /></p>\n\
+                    <p><img src=\"b.png\" alt=\"Inline\" /> and text</p>\n";
+        assert_eq!(
+            figures("title", false, None).render(html, "page.md"),
+            "<figure class=\"markdown-figure\"><img src=\"a.png\" alt=\"Alt\" /><figcaption>Title</figcaption></figure>\n\
+             <p><img src=\"b.png\" alt=\"Inline\" /> and text</p>\n"
+        );
+        assert!(
+            figures("alt", false, None)
+                .render(html, "page.md")
+                .starts_with("<figure class=\"markdown-figure\"><img src=\"a.png\" alt=\"Alt\" title=\"Title\" /><figcaption>Alt</figcaption></figure>")
+        );
+        assert!(
+            figures("none", false, None)
+                .render(html, "page.md")
+                .starts_with("<figure class=\"markdown-figure\"><img src=\"a.png\" alt=\"Alt\" title=\"Title\" /></figure>")
+        );
+    }
+
+    #[test]
+    fn numbered_figures_can_be_referenced() {
+        let html = concat!(
+            "<p>As [@fig:squash] shows, unlike <code>[@fig:squash]</code></p>\n",
+            "<p><img src=\"a.png\" alt=\"A\" /></p>\n",
+            "<p><img src=\"b.png\" alt=\"B\" title=\"Seen from above\" /> {#fig:squash}</p>\n",
+            "<p><img src=\"c.png\" alt=\"C\" /> {#fig:other}</p>\n",
+            "<p>[@fig:missing]</p>\n",
+        );
+        assert_eq!(
+            figures("title", true, Some("图")).render(html, "page.md"),
+            concat!(
+                "<p>As <a class=\"figure-ref\" href=\"#fig-squash\">图 1</a> shows, unlike <code>[@fig:squash]</code></p>\n",
+                "<figure class=\"markdown-figure\"><img src=\"a.png\" alt=\"A\" /></figure>\n",
+                "<figure class=\"markdown-figure\" id=\"fig-squash\"><img src=\"b.png\" alt=\"B\" /><figcaption><span class=\"figure-number\">图 1:</span> Seen from above</figcaption></figure>\n",
+                "<figure class=\"markdown-figure\" id=\"fig-other\"><img src=\"c.png\" alt=\"C\" /><figcaption><span class=\"figure-number\">图 2</span></figcaption></figure>\n",
+                "<p>[@fig:missing]</p>\n",
+            )
+        );
+    }
+
+    #[test]
+    fn caption_setting_is_validated() {
+        let err = Figures::from_config(&FiguresConfig {
+            caption: Some("tooltip".to_string()),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown [figures] caption `tooltip` (expected title, alt or none)"
+        );
+    }
+}
@@ -0,0 +1,994 @@
+use crate::links::resolve_source_path;
+use crate::utils::FontsConfig;
+use allsorts::binary::read::ReadScope;
+use allsorts::font::Font;
+use allsorts::font_data::FontData;
+use allsorts::tables::cmap::CmapSubtable;
+use allsorts::tables::{FontTableProvider, OpenTypeData, OpenTypeFont};
+use anyhow::{Context, Result, bail};
+use colored::Colorize;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Punctuation that shows up in prose even when the pages do not use it yet
+const COMMON_PUNCTUATION: &str =
+    "\u{2013}\u{2014}\u{2018}\u{2019}\u{201c}\u{201d}\u{2022}\u{2026}\u{20ac}\u{2122}";
+
+/// Tags with a one-byte code in the WOFF2 table directory; others are spelled out
+const KNOWN_TAGS: [&[u8; 4]; 13] = [
+    b"cmap", b"head", b"hhea", b"hmtx", b"maxp", b"name", b"OS/2", b"post", b"cvt ", b"fpgm",
+    b"glyf", b"loca", b"prep",
+];
+
+/// Subsets the WOFF2 fonts that the site's stylesheets load to the characters
+/// of the rendered pages, plus a configurable safety margin
+pub struct FontSubsetter {
+    keep: BTreeSet<char>,
+}
+
+impl FontSubsetter {
+    pub fn from_config(config: &FontsConfig) -> Result<Self> {
+        let mut keep: BTreeSet<char> = match config.safety_margin.as_deref().unwrap_or("ascii") {
+            "none" => BTreeSet::new(),
+            "ascii" => (' '..='~').collect(),
+            "latin1" => (' '..='~')
+                .chain('\u{a0}'..='\u{ff}')
+                .chain(COMMON_PUNCTUATION.chars())
+                .collect(),
+            other => bail!(
+                "Unknown fonts.safety_margin '{}' (expected \"none\", \"ascii\" or \"latin1\")",
+                other
+            ),
+        };
+        keep.extend(config.extra_characters.iter().flat_map(|s| s.chars()));
+        Ok(FontSubsetter { keep })
+    }
+
+    /// Subset every font loaded by an `@font-face` rule of a stylesheet in
+    /// `output_dir` (the theme's, compiled Sass, `static/` and per-page
+    /// `extra_css` alike) to the characters used in `pages`, write it next to
+    /// the original as `<name>.subset.woff2` and point the rules at it. The
+    /// full fonts are removed from the output. The bundled libraries in
+    /// `vendor/` are left alone, as their fonts draw icons and math that the
+    /// page text does not contain.
+    pub fn apply(&self, pages: &[PathBuf], output_dir: &Path) -> Result<()> {
+        let mut stylesheets = Vec::new();
+        for entry in WalkDir::new(output_dir).sort_by_file_name() {
+            let entry = entry?;
+            let relative = entry
+                .path()
+                .strip_prefix(output_dir)?
+                .to_string_lossy()
+                .replace('\\', "/");
+            if entry.file_type().is_file()
+                && relative.ends_with(".css")
+                && !relative.starts_with("vendor/")
+            {
+                let css = fs::read_to_string(entry.path())
+                    .with_context(|| format!("Cannot read stylesheet: {:?}", entry.path()))?;
+                stylesheets.push((relative, css));
+            }
+        }
+
+        let mut chars = self.keep.clone();
+        for (_, css) in &stylesheets {
+            chars.extend(css.chars());
+        }
+        for page in pages {
+            let html = fs::read_to_string(page)
+                .with_context(|| format!("Cannot read generated page: {:?}", page))?;
+            chars.extend(page_chars(&html));
+        }
+        chars.retain(|ch| !ch.is_control());
+
+        let font_face_re = Regex::new(r"@font-face\s*\{[^}]*\}").unwrap();
+        let url_re = Regex::new(r#"url\(\s*["']?([^"')]+\.woff2)["']?\s*\)"#).unwrap();
+        // Output paths of the fonts a stylesheet's `url()` points at
+        let font_path = |stylesheet: &str, url: &str| {
+            if url.starts_with("//") || url.starts_with("data:") || url.contains("://") {
+                return None;
+            }
+            resolve_source_path(stylesheet, url).filter(|path| !path.starts_with("vendor/"))
+        };
+        let fonts: BTreeSet<String> = stylesheets
+            .iter()
+            .flat_map(|(name, css)| {
+                font_face_re
+                    .find_iter(css)
+                    .flat_map(|rule| url_re.captures_iter(rule.as_str()))
+                    .filter_map(|caps| font_path(name, &caps[1]))
+            })
+            .filter(|path| output_dir.join(path).is_file())
+            .collect();
+
+        let mut renamed = BTreeSet::new();
+        for path in &fonts {
+            let source = output_dir.join(path);
+            let original = fs::read(&source)?;
+            let Some(subset) = subset_woff2(&original, &chars)
+                .with_context(|| format!("Failed to subset font {}", path))?
+            else {
+                eprintln!(
+                    "{} {}",
+                    "Warning: not subsetting".yellow(),
+                    format!(
+                        "{} (only TrueType fonts without color layers are supported)",
+                        path
+                    )
+                    .yellow()
+                );
+                continue;
+            };
+            let subset_path = subset_name(path);
+            fs::write(output_dir.join(&subset_path), &subset)?;
+            fs::remove_file(&source)?;
+            println!(
+                "  {} {} {}",
+                "Subset font:".green(),
+                subset_path.green(),
+                format!(
+                    "({} characters, {} KB -> {} KB)",
+                    chars.len(),
+                    original.len().div_ceil(1024),
+                    subset.len().div_ceil(1024)
+                )
+                .green()
+            );
+            renamed.insert(path.clone());
+        }
+
+        for (name, css) in &stylesheets {
+            let rewritten = font_face_re.replace_all(css, |rule: &regex::Captures| {
+                url_re
+                    .replace_all(&rule[0], |caps: &regex::Captures| {
+                        match font_path(name, &caps[1]) {
+                            Some(path) if renamed.contains(&path) => {
+                                caps[0].replace(&caps[1], &subset_name(&caps[1]))
+                            }
+                            _ => caps[0].to_string(),
+                        }
+                    })
+                    .to_string()
+            });
+            if rewritten != *css {
+                fs::write(output_dir.join(name), rewritten.as_ref())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `fonts/a.woff2` becomes `fonts/a.subset.woff2`
+fn subset_name(path: &str) -> String {
+    format!(
+        "{}.subset.woff2",
+        path.strip_suffix(".woff2").unwrap_or(path)
+    )
+}
+
+/// Every character of a rendered page, including inline scripts (whose
+/// strings may be shown to readers) and characters written as entities
+fn page_chars(html: &str) -> impl Iterator<Item = char> + '_ {
+    let entity_re = Regex::new(r"&#(x[0-9a-fA-F]+|[0-9]+);").unwrap();
+    let entities: Vec<char> = entity_re
+        .captures_iter(html)
+        .filter_map(|caps| match caps[1].strip_prefix('x') {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => caps[1].parse().ok(),
+        })
+        .filter_map(char::from_u32)
+        .collect();
+    html.chars().chain(entities).chain(['\u{a0}'])
+}
+
+/// Keep the outlines of the glyphs for `chars` and empty the others. Glyph ids
+/// do not change, so the layout tables (kerning, ligatures) are kept as they
+/// are, along with every glyph they or a composite glyph can still turn the
+/// kept ones into. Returns `None` for fonts without TrueType outlines and for
+/// color fonts.
+fn subset_woff2(data: &[u8], chars: &BTreeSet<char>) -> Result<Option<Vec<u8>>> {
+    let font_data = ReadScope::new(data).read::<FontData<'_>>()?;
+    let font = Font::new(font_data.table_provider(0)?)?;
+    let provider = &font.font_table_provider;
+    let mut tables = BTreeMap::new();
+    for tag in provider.table_tags().unwrap_or_default() {
+        if let Some(table) = provider.table_data(tag)? {
+            tables.insert(tag.to_be_bytes(), table.into_owned());
+        }
+    }
+    tables.remove(b"DSIG"); // the signature no longer matches
+    let (Some(head), Some(maxp), Some(loca), Some(glyf)) = (
+        tables.get(b"head"),
+        tables.get(b"maxp"),
+        tables.get(b"loca"),
+        tables.get(b"glyf"),
+    ) else {
+        return Ok(None);
+    };
+    // Color layers are glyphs of their own that no character maps to
+    if tables.contains_key(b"COLR") {
+        return Ok(None);
+    }
+
+    let num_glyphs = read_u16(maxp, 4).context("Bad maxp table")?;
+    let long_loca = read_u16(head, 50).context("Bad head table")? == 1;
+    let offsets: Vec<usize> = (0..=usize::from(num_glyphs))
+        .map(|gid| match long_loca {
+            true => read_u32(loca, 4 * gid).map(|offset| offset as usize),
+            false => read_u16(loca, 2 * gid).map(|offset| 2 * usize::from(offset)),
+        })
+        .collect::<Option<_>>()
+        .context("Bad loca table")?;
+    let glyph = |gid: u16| {
+        let gid = usize::from(gid);
+        offsets
+            .get(gid..gid + 2)
+            .and_then(|range| glyf.get(range[0]..range[1]))
+            .unwrap_or_default()
+    };
+
+    // Start from the glyphs of the used characters and .notdef
+    let cmap = ReadScope::new(font.cmap_subtable_data()).read::<CmapSubtable<'_>>()?;
+    let mut mappings = BTreeMap::new();
+    cmap.mappings_fn(|code, gid| {
+        if char::from_u32(code).is_some_and(|ch| chars.contains(&ch)) {
+            mappings.insert(code, gid);
+        }
+    })?;
+    let mut unused: BTreeSet<u16> = (1..num_glyphs).collect();
+    for gid in mappings.values() {
+        unused.remove(gid);
+    }
+    // Keep what substitutions of kept glyphs turn into, and the glyphs
+    // composite glyphs are drawn from, until nothing changes
+    let substitutions = match tables.get(b"GSUB") {
+        Some(gsub) => gsub_substitutions(gsub).context("Bad GSUB table")?,
+        None => Vec::new(),
+    };
+    keep_reachable(&mut unused, num_glyphs, glyph, &substitutions)
+        .context("Bad composite glyph")?;
+
+    let mut new_glyf = Vec::new();
+    let mut new_loca = Vec::new();
+    for gid in 0..num_glyphs {
+        new_loca.extend((new_glyf.len() as u32).to_be_bytes());
+        if !unused.contains(&gid) {
+            new_glyf.extend_from_slice(glyph(gid));
+            new_glyf.resize(new_glyf.len().next_multiple_of(4), 0);
+        }
+    }
+    new_loca.extend((new_glyf.len() as u32).to_be_bytes());
+    let mut new_head = head.clone();
+    new_head[50..52].copy_from_slice(&1u16.to_be_bytes()); // long loca offsets
+    // Version 3 of the post table has no glyph names, which browsers never use
+    let mut post = tables
+        .get(b"post")
+        .and_then(|post| post.get(..32))
+        .context("Bad post table")?
+        .to_vec();
+    post[..4].copy_from_slice(&0x00030000u32.to_be_bytes());
+
+    tables.insert(*b"head", new_head);
+    tables.insert(*b"loca", new_loca);
+    tables.insert(*b"glyf", new_glyf);
+    tables.insert(*b"cmap", cmap_table(&mappings));
+    tables.insert(*b"post", post);
+    encode_woff2(&build_sfnt(&tables)).map(Some)
+}
+
+/// Take out of `unused` every glyph that the kept ones can turn into through
+/// `substitutions` or are drawn from as composite glyphs, until nothing changes
+fn keep_reachable<'a>(
+    unused: &mut BTreeSet<u16>,
+    num_glyphs: u16,
+    glyph: impl Fn(u16) -> &'a [u8],
+    substitutions: &[(Vec<u16>, Vec<u16>)],
+) -> Option<()> {
+    let mut pending: Vec<u16> = (0..num_glyphs)
+        .filter(|gid| !unused.contains(gid))
+        .collect();
+    while !pending.is_empty() {
+        while let Some(gid) = pending.pop() {
+            for component in components(glyph(gid))? {
+                if unused.remove(&component) {
+                    pending.push(component);
+                }
+            }
+        }
+        for (inputs, outputs) in substitutions {
+            if inputs.iter().all(|gid| !unused.contains(gid)) {
+                pending.extend(outputs.iter().copied().filter(|gid| unused.remove(gid)));
+            }
+        }
+    }
+    Some(())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// A count followed by that many 16-bit values
+fn read_u16_array(data: &[u8], offset: usize) -> Option<Vec<u16>> {
+    (0..usize::from(read_u16(data, offset)?))
+        .map(|i| read_u16(data, offset + 2 + 2 * i))
+        .collect()
+}
+
+/// Glyphs a composite glyph is built from; none for simple glyphs
+fn components(glyph: &[u8]) -> Option<Vec<u16>> {
+    const ARGS_ARE_WORDS: u16 = 0x0001;
+    const HAS_SCALE: u16 = 0x0008;
+    const MORE_COMPONENTS: u16 = 0x0020;
+    const HAS_X_AND_Y_SCALE: u16 = 0x0040;
+    const HAS_TWO_BY_TWO: u16 = 0x0080;
+
+    let mut components = Vec::new();
+    if glyph.is_empty() || read_u16(glyph, 0)? as i16 >= 0 {
+        return Some(components);
+    }
+    let mut offset = 10;
+    loop {
+        let flags = read_u16(glyph, offset)?;
+        components.push(read_u16(glyph, offset + 2)?);
+        offset += if flags & ARGS_ARE_WORDS != 0 { 8 } else { 6 };
+        offset += match flags {
+            _ if flags & HAS_SCALE != 0 => 2,
+            _ if flags & HAS_X_AND_Y_SCALE != 0 => 4,
+            _ if flags & HAS_TWO_BY_TWO != 0 => 8,
+            _ => 0,
+        };
+        if flags & MORE_COMPONENTS == 0 {
+            return Some(components);
+        }
+    }
+}
+
+/// The substitutions of a GSUB table as `(inputs, outputs)` glyph pairs,
+/// ignoring the context they apply in
+fn gsub_substitutions(gsub: &[u8]) -> Option<Vec<(Vec<u16>, Vec<u16>)>> {
+    let lookup_list = usize::from(read_u16(gsub, 8)?);
+    let mut substitutions = Vec::new();
+    for lookup in read_u16_array(gsub, lookup_list)? {
+        let lookup = lookup_list + usize::from(lookup);
+        let kind = read_u16(gsub, lookup)?;
+        for subtable in read_u16_array(gsub, lookup + 4)? {
+            lookup_substitutions(
+                gsub,
+                kind,
+                lookup + usize::from(subtable),
+                &mut substitutions,
+            )?;
+        }
+    }
+    Some(substitutions)
+}
+
+fn lookup_substitutions(
+    gsub: &[u8],
+    kind: u16,
+    subtable: usize,
+    substitutions: &mut Vec<(Vec<u16>, Vec<u16>)>,
+) -> Option<()> {
+    let offsets = |offset: usize| -> Option<Vec<usize>> {
+        Some(
+            read_u16_array(gsub, offset)?
+                .into_iter()
+                .map(|relative| subtable + usize::from(relative))
+                .collect(),
+        )
+    };
+    let coverage = || {
+        let offset = subtable + usize::from(read_u16(gsub, subtable + 2)?);
+        Some(coverage_glyphs(gsub, offset)?.collect::<Vec<_>>())
+    };
+    match (kind, read_u16(gsub, subtable)?) {
+        // Single: each covered glyph plus a delta, or the substitute at its index
+        (1, 1) => {
+            let delta = read_u16(gsub, subtable + 4)?;
+            for gid in coverage()? {
+                substitutions.push((vec![gid], vec![gid.wrapping_add(delta)]));
+            }
+        }
+        (1, 2) => {
+            let substitutes = read_u16_array(gsub, subtable + 4)?;
+            for (gid, substitute) in coverage()?.into_iter().zip(substitutes) {
+                substitutions.push((vec![gid], vec![substitute]));
+            }
+        }
+        // Multiple and alternate: a glyph sequence per covered glyph
+        (2 | 3, 1) => {
+            for (gid, sequence) in coverage()?.into_iter().zip(offsets(subtable + 4)?) {
+                substitutions.push((vec![gid], read_u16_array(gsub, sequence)?));
+            }
+        }
+        // Ligature: a set per covered first glyph, each ligature listing the
+        // glyph it becomes and the other glyphs it joins
+        (4, 1) => {
+            for (first, set) in coverage()?.into_iter().zip(offsets(subtable + 4)?) {
+                for ligature in read_u16_array(gsub, set)? {
+                    let ligature = set + usize::from(ligature);
+                    let count = usize::from(read_u16(gsub, ligature + 2)?);
+                    let mut inputs = vec![first];
+                    for i in 1..count {
+                        inputs.push(read_u16(gsub, ligature + 2 + 2 * i)?);
+                    }
+                    substitutions.push((inputs, vec![read_u16(gsub, ligature)?]));
+                }
+            }
+        }
+        // Contextual lookups only apply other lookups
+        (5 | 6, _) => {}
+        (7, 1) => {
+            let extension = subtable + read_u32(gsub, subtable + 4)? as usize;
+            lookup_substitutions(
+                gsub,
+                read_u16(gsub, subtable + 2)?,
+                extension,
+                substitutions,
+            )?;
+        }
+        // Reverse chaining: the substitutes follow the backtrack and lookahead coverages
+        (8, 1) => {
+            let lookahead = subtable + 6 + 2 * usize::from(read_u16(gsub, subtable + 4)?);
+            let substitutes = lookahead + 2 + 2 * usize::from(read_u16(gsub, lookahead)?);
+            let substitutes = read_u16_array(gsub, substitutes)?;
+            for (gid, substitute) in coverage()?.into_iter().zip(substitutes) {
+                substitutions.push((vec![gid], vec![substitute]));
+            }
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+fn coverage_glyphs(gsub: &[u8], coverage: usize) -> Option<impl Iterator<Item = u16>> {
+    let ranges: Vec<(u16, u16)> = match read_u16(gsub, coverage)? {
+        1 => read_u16_array(gsub, coverage + 2)?
+            .into_iter()
+            .map(|gid| (gid, gid))
+            .collect(),
+        2 => (0..usize::from(read_u16(gsub, coverage + 2)?))
+            .map(|i| {
+                let range = coverage + 4 + 6 * i;
+                Some((read_u16(gsub, range)?, read_u16(gsub, range + 2)?))
+            })
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+    Some(ranges.into_iter().flat_map(|(start, end)| start..=end))
+}
+
+/// A cmap with a format 4 subtable for the Basic Multilingual Plane, plus a
+/// format 12 one when characters beyond it are mapped
+fn cmap_table(mappings: &BTreeMap<u32, u16>) -> Vec<u8> {
+    // Runs of consecutive characters mapped to consecutive glyphs
+    let mut runs: Vec<(u32, u32, u16)> = Vec::new();
+    for (&code, &gid) in mappings {
+        match runs.last_mut() {
+            Some((start, end, first))
+                if *end + 1 == code
+                    && u32::from(*first) + (code - *start) == u32::from(gid)
+                    && (code > 0xffff) == (*start > 0xffff) =>
+            {
+                *end = code
+            }
+            _ => runs.push((code, code, gid)),
+        }
+    }
+
+    let mut bmp: Vec<(u16, u16, u16)> = runs
+        .iter()
+        .filter(|(start, _, _)| *start < 0xffff)
+        .map(|&(start, end, gid)| {
+            let end = end.min(0xfffe);
+            (start as u16, end as u16, gid.wrapping_sub(start as u16))
+        })
+        .collect();
+    bmp.push((0xffff, 0xffff, 1)); // the required last segment, mapping to glyph 0
+    let seg_count = bmp.len() as u16;
+    let search_range = 2 * (1 << seg_count.ilog2());
+    let mut format4 = Vec::new();
+    for value in [
+        4,
+        16 + 8 * seg_count,
+        0,
+        2 * seg_count,
+        search_range,
+        seg_count.ilog2() as u16,
+        2 * seg_count - search_range,
+    ] {
+        format4.extend(value.to_be_bytes());
+    }
+    bmp.iter()
+        .for_each(|(_, end, _)| format4.extend(end.to_be_bytes()));
+    format4.extend(0u16.to_be_bytes());
+    bmp.iter()
+        .for_each(|(start, _, _)| format4.extend(start.to_be_bytes()));
+    bmp.iter()
+        .for_each(|(_, _, delta)| format4.extend(delta.to_be_bytes()));
+    bmp.iter().for_each(|_| format4.extend(0u16.to_be_bytes()));
+
+    let mut subtables = vec![(1u16, format4)];
+    if runs.iter().any(|(_, end, _)| *end > 0xffff) {
+        let mut format12 = Vec::new();
+        format12.extend(12u16.to_be_bytes());
+        format12.extend(0u16.to_be_bytes());
+        format12.extend((16 + 12 * runs.len() as u32).to_be_bytes());
+        format12.extend(0u32.to_be_bytes());
+        format12.extend((runs.len() as u32).to_be_bytes());
+        for (start, end, gid) in &runs {
+            format12.extend(start.to_be_bytes());
+            format12.extend(end.to_be_bytes());
+            format12.extend(u32::from(*gid).to_be_bytes());
+        }
+        subtables.push((10, format12));
+    }
+
+    let mut cmap = Vec::new();
+    cmap.extend(0u16.to_be_bytes());
+    cmap.extend((subtables.len() as u16).to_be_bytes());
+    let mut offset = 4 + 8 * subtables.len() as u32;
+    for (encoding, subtable) in &subtables {
+        cmap.extend(3u16.to_be_bytes()); // Windows platform
+        cmap.extend(encoding.to_be_bytes());
+        cmap.extend(offset.to_be_bytes());
+        offset += subtable.len() as u32;
+    }
+    subtables
+        .iter()
+        .for_each(|(_, subtable)| cmap.extend(subtable));
+    cmap
+}
+
+/// Lay TrueType tables out as an sfnt font, with their checksums
+fn build_sfnt(tables: &BTreeMap<[u8; 4], Vec<u8>>) -> Vec<u8> {
+    let checksum = |data: &[u8]| {
+        data.chunks(4).fold(0u32, |sum, chunk| {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            sum.wrapping_add(u32::from_be_bytes(word))
+        })
+    };
+    let num_tables = tables.len() as u16;
+    let entry_selector = num_tables.ilog2() as u16;
+    let search_range = 16 * (1 << entry_selector);
+
+    let mut sfnt = Vec::new();
+    sfnt.extend(0x00010000u32.to_be_bytes());
+    for value in [
+        num_tables,
+        search_range,
+        entry_selector,
+        16 * num_tables - search_range,
+    ] {
+        sfnt.extend(value.to_be_bytes());
+    }
+    let mut offset = 12 + 16 * tables.len();
+    let mut head_offset = 0;
+    for (tag, data) in tables {
+        let mut data = data.clone();
+        if tag == b"head" {
+            head_offset = offset;
+            data[8..12].fill(0); // checksumAdjustment is computed last
+        }
+        sfnt.extend(tag);
+        sfnt.extend(checksum(&data).to_be_bytes());
+        sfnt.extend((offset as u32).to_be_bytes());
+        sfnt.extend((data.len() as u32).to_be_bytes());
+        offset += data.len().next_multiple_of(4);
+    }
+    for (tag, data) in tables {
+        let start = sfnt.len();
+        sfnt.extend(data);
+        if tag == b"head" {
+            sfnt[start + 8..start + 12].fill(0);
+        }
+        sfnt.resize(sfnt.len().next_multiple_of(4), 0);
+    }
+    let adjustment = 0xb1b0afbau32.wrapping_sub(checksum(&sfnt));
+    sfnt[head_offset + 8..head_offset + 12].copy_from_slice(&adjustment.to_be_bytes());
+    sfnt
+}
+
+/// Wrap an sfnt font in WOFF2 without the glyf/loca transforms: the tables are
+/// stored as they are and compressed together with Brotli
+fn encode_woff2(sfnt: &[u8]) -> Result<Vec<u8>> {
+    let font = ReadScope::new(sfnt).read::<OpenTypeFont<'_>>()?;
+    let OpenTypeData::Single(tables) = font.data else {
+        bail!("Font collections are not supported");
+    };
+
+    let mut directory = Vec::new();
+    let mut stream = Vec::new();
+    let mut sfnt_size = 12 + 16 * tables.table_records.len() as u32;
+    for record in tables.table_records.iter() {
+        let tag = record.table_tag.to_be_bytes();
+        let data = sfnt
+            .get(record.offset as usize..)
+            .and_then(|rest| rest.get(..record.length as usize))
+            .context("Font table out of bounds")?;
+        // Transform version 3 is the null transform for glyf and loca, 0 for the rest
+        let transform = if &tag == b"glyf" || &tag == b"loca" {
+            0xc0
+        } else {
+            0
+        };
+        match KNOWN_TAGS.iter().position(|known| **known == tag) {
+            Some(index) => directory.push(index as u8 | transform),
+            None => {
+                directory.push(63 | transform);
+                directory.extend(tag);
+            }
+        }
+        push_base128(&mut directory, record.length);
+        stream.extend_from_slice(data);
+        sfnt_size += record.length.next_multiple_of(4);
+    }
+
+    let mut compressed = Vec::new();
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 11, 22);
+        writer.write_all(&stream)?;
+    }
+
+    let mut woff2 = Vec::new();
+    woff2.extend(b"wOF2");
+    woff2.extend(tables.sfnt_version.to_be_bytes());
+    woff2.extend(0u32.to_be_bytes()); // total length, filled in below
+    woff2.extend((tables.table_records.len() as u16).to_be_bytes());
+    woff2.extend(0u16.to_be_bytes());
+    woff2.extend(sfnt_size.to_be_bytes());
+    woff2.extend((compressed.len() as u32).to_be_bytes());
+    woff2.extend(1u16.to_be_bytes()); // font version
+    woff2.extend(0u16.to_be_bytes());
+    woff2.extend([0; 20]); // no metadata or private data
+    woff2.extend(directory);
+    woff2.extend(compressed);
+    woff2.resize(woff2.len().next_multiple_of(4), 0);
+    let length = (woff2.len() as u32).to_be_bytes();
+    woff2[8..12].copy_from_slice(&length);
+    Ok(woff2)
+}
+
+/// WOFF2's UIntBase128: big-endian groups of 7 bits, high bit set on all but the last
+fn push_base128(out: &mut Vec<u8>, value: u32) {
+    let mut groups = vec![(value & 0x7f) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        groups.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.extend(groups.iter().rev());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use allsorts::font::MatchingPresentation;
+    use allsorts::tables::cmap::{Cmap, EncodingId, PlatformId};
+    use allsorts::tag;
+
+    fn checksum(data: &[u8]) -> u32 {
+        data.chunks(4).fold(0u32, |sum, chunk| {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            sum.wrapping_add(u32::from_be_bytes(word))
+        })
+    }
+
+    fn bytes(words: &[u16]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn base128_uses_as_few_bytes_as_possible() {
+        for (value, expected) in [
+            (0, vec![0x00]),
+            (127, vec![0x7f]),
+            (128, vec![0x81, 0x00]),
+            (16383, vec![0xff, 0x7f]),
+            (16384, vec![0x81, 0x80, 0x00]),
+            (u32::MAX, vec![0x8f, 0xff, 0xff, 0xff, 0x7f]),
+        ] {
+            let mut out = Vec::new();
+            push_base128(&mut out, value);
+            assert_eq!(out, expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn sfnt_tables_are_aligned_and_checksummed() {
+        let mut head = vec![0; 54];
+        head[8..12].copy_from_slice(&0xdeadbeefu32.to_be_bytes()); // stale adjustment
+        head[12..16].copy_from_slice(&0x5f0f3cf5u32.to_be_bytes());
+        let tables = BTreeMap::from([
+            (*b"head", head),
+            (*b"name", b"odd length".to_vec()),
+            (*b"post", vec![0, 3, 0, 0, 1, 2, 3]),
+        ]);
+        let sfnt = build_sfnt(&tables);
+
+        let font = ReadScope::new(&sfnt).read::<OpenTypeFont<'_>>().unwrap();
+        let OpenTypeData::Single(offset_table) = font.data else {
+            panic!("not a single font");
+        };
+        assert_eq!(
+            (
+                offset_table.search_range,
+                offset_table.entry_selector,
+                offset_table.range_shift
+            ),
+            (32, 1, 16)
+        );
+        assert_eq!(offset_table.table_records.len(), 3);
+        for record in offset_table.table_records.iter() {
+            assert_eq!(record.offset % 4, 0);
+            let mut data = sfnt[record.offset as usize..][..record.length as usize].to_vec();
+            let mut expected = tables[&record.table_tag.to_be_bytes()].clone();
+            if record.table_tag == tag::HEAD {
+                // The table's checksum is taken before checksumAdjustment is set
+                data[8..12].fill(0);
+                expected[8..12].fill(0);
+            }
+            assert_eq!(data, expected);
+            assert_eq!(record.checksum, checksum(&data));
+        }
+        assert_eq!(checksum(&sfnt), 0xb1b0afba);
+    }
+
+    #[test]
+    fn cmap_maps_characters_in_and_beyond_the_bmp() {
+        let mappings =
+            BTreeMap::from([(0x41, 3), (0x42, 4), (0x43, 5), (0x4e00, 9), (0x1f600, 12)]);
+        let cmap = cmap_table(&mappings);
+        let cmap = ReadScope::new(&cmap).read::<Cmap<'_>>().unwrap();
+        for (encoding, codes) in [
+            (
+                EncodingId::WINDOWS_UNICODE_BMP_UCS2,
+                &[0x41, 0x42, 0x43, 0x4e00][..],
+            ),
+            (
+                EncodingId::WINDOWS_UNICODE_UCS4,
+                &[0x41, 0x4e00, 0x1f600][..],
+            ),
+        ] {
+            let record = cmap.find_subtable(PlatformId::WINDOWS, encoding).unwrap();
+            let subtable = cmap
+                .scope
+                .offset(record.offset as usize)
+                .read::<CmapSubtable<'_>>()
+                .unwrap();
+            for code in codes {
+                assert_eq!(subtable.map_glyph(*code).unwrap(), Some(mappings[code]));
+            }
+            assert_eq!(subtable.map_glyph(0x44).unwrap().unwrap_or(0), 0);
+        }
+    }
+
+    /// A GSUB table with a ligature lookup (10 + 11 -> 20) and a single
+    /// substitution lookup (20 -> 21) using a range coverage
+    fn gsub() -> Vec<u8> {
+        bytes(&[
+            1, 0, 0, 0, 10, // header, lookup list at 10
+            2, 6, 38, // lookup list
+            4, 0, 1, 8, // ligature lookup
+            1, 8, 1, 14, // ligature substitution
+            1, 1, 10, // coverage: glyph 10
+            1, 4, // ligature set
+            20, 2, 11, // ligature
+            1, 0, 1, 8, // single lookup
+            2, 8, 1, 21, // single substitution, format 2
+            2, 1, 20, 20, 0, // coverage: glyphs 20 to 20
+        ])
+    }
+
+    #[test]
+    fn gsub_substitutions_are_read_from_every_lookup() {
+        assert_eq!(
+            gsub_substitutions(&gsub()).unwrap(),
+            vec![(vec![10, 11], vec![20]), (vec![20], vec![21])]
+        );
+    }
+
+    #[test]
+    fn kept_glyphs_keep_what_they_can_become_and_are_drawn_from() {
+        let substitutions = gsub_substitutions(&gsub()).unwrap();
+        // Glyph 21 is a composite of glyph 25
+        let composite = bytes(&[0xffff, 0, 0, 0, 0, 0, 25, 0]);
+        let glyph = |gid: u16| match gid {
+            21 => composite.as_slice(),
+            _ => &[][..],
+        };
+        let closure = |kept: &[u16]| {
+            let mut unused: BTreeSet<u16> = (1..30).filter(|gid| !kept.contains(gid)).collect();
+            keep_reachable(&mut unused, 30, glyph, &substitutions).unwrap();
+            (1..30)
+                .filter(|gid| !unused.contains(gid))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(closure(&[10, 11]), vec![10, 11, 20, 21, 25]);
+        // A ligature needs all of its glyphs
+        assert_eq!(closure(&[10]), vec![10]);
+        assert_eq!(closure(&[20]), vec![20, 21, 25]);
+    }
+
+    #[test]
+    fn subset_font_reads_back_with_the_same_glyph_ids() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("defaults/theme/fonts/HarmonyOS_Sans_Regular.woff2");
+        let original = fs::read(path).unwrap();
+        let subset = subset_woff2(&original, &"Hi!".chars().collect())
+            .unwrap()
+            .unwrap();
+        assert_eq!(&subset[..4], b"wOF2");
+        assert_eq!(
+            u32::from_be_bytes(subset[8..12].try_into().unwrap()) as usize,
+            subset.len()
+        );
+
+        let read = |data: &[u8]| {
+            let font_data = ReadScope::new(data).read::<FontData<'_>>().unwrap();
+            let mut font = Font::new(font_data.table_provider(0).unwrap()).unwrap();
+            let glyphs: Vec<u16> = "Hi!z"
+                .chars()
+                .map(|ch| {
+                    font.lookup_glyph_index(ch, MatchingPresentation::NotRequired, None)
+                        .0
+                })
+                .collect();
+            (font.num_glyphs(), glyphs)
+        };
+        let (num_glyphs, glyphs) = read(&original);
+        assert!(glyphs.iter().all(|&gid| gid != 0));
+        assert_eq!(
+            read(&subset),
+            (num_glyphs, vec![glyphs[0], glyphs[1], glyphs[2], 0])
+        );
+    }
+
+    fn bundled_font() -> Vec<u8> {
+        fs::read(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("defaults/theme/fonts/HarmonyOS_Sans_Regular.woff2"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn fonts_of_every_stylesheet_but_the_libraries_are_subset() {
+        let dir = crate::utils::test_dir("fonts");
+        let font = bundled_font();
+        let write = |path: &str, contents: &[u8]| {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        };
+        write(
+            "style.css",
+            b"@font-face { font-family: Sans; src: url(\"fonts/sans.woff2\") format(\"woff2\"); }\n.a { background: url(fonts/sans.woff2); }",
+        );
+        write(
+            "css/extra.css",
+            b"@font-face { font-family: Extra; src: url('../fonts/extra.woff2'); }",
+        );
+        write(
+            "vendor/katex/katex.min.css",
+            b"@font-face { font-family: KaTeX_Main; src: url(fonts/KaTeX_Main-Regular.woff2); }",
+        );
+        for font_path in [
+            "fonts/sans.woff2",
+            "fonts/extra.woff2",
+            "vendor/katex/fonts/KaTeX_Main-Regular.woff2",
+        ] {
+            write(font_path, &font);
+        }
+        write("index.html", "<p>Vibe &#x14b;</p>".as_bytes());
+
+        let subsetter = FontSubsetter::from_config(&FontsConfig {
+            safety_margin: Some("none".to_string()),
+            extra_characters: Some("Ŋ".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        subsetter.apply(&[dir.join("index.html")], &dir).unwrap();
+
+        // Only `@font-face` rules point at the subset
+        assert_eq!(
+            fs::read_to_string(dir.join("style.css")).unwrap(),
+            "@font-face { font-family: Sans; src: url(\"fonts/sans.subset.woff2\") format(\"woff2\"); }\n.a { background: url(fonts/sans.woff2); }"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("css/extra.css")).unwrap(),
+            "@font-face { font-family: Extra; src: url('../fonts/extra.subset.woff2'); }"
+        );
+        assert!(!dir.join("fonts/sans.woff2").exists());
+        assert!(!dir.join("fonts/extra.woff2").exists());
+        assert_eq!(
+            fs::read(dir.join("vendor/katex/fonts/KaTeX_Main-Regular.woff2")).unwrap(),
+            font
+        );
+
+        // Text from the pages, entities included, and the extra characters are kept
+        let subset = fs::read(dir.join("fonts/sans.subset.woff2")).unwrap();
+        let glyphs = |data: &[u8], text: &str| {
+            let font_data = ReadScope::new(data).read::<FontData<'_>>().unwrap();
+            let mut font = Font::new(font_data.table_provider(0).unwrap()).unwrap();
+            text.chars()
+                .map(|ch| {
+                    font.lookup_glyph_index(ch, MatchingPresentation::NotRequired, None)
+                        .0
+                })
+                .collect::<Vec<_>>()
+        };
+        assert!(glyphs(&font, "~").iter().all(|&glyph| glyph != 0));
+        let kept = glyphs(&subset, "VibeŊŋ~");
+        assert!(kept[..6].iter().all(|&glyph| glyph != 0), "{:?}", kept);
+        assert_eq!(kept[6], 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn subset_text_lays_out_like_the_full_font() {
+        use allsorts::glyph_position::{GlyphLayout, TextDirection};
+        use allsorts::gsub::FeatureMask;
+
+        let text = "AVATAR Type: office, Wave.";
+        let shaped = |data: &[u8], kerning: bool| {
+            let font_data = ReadScope::new(data).read::<FontData<'_>>().unwrap();
+            let mut font = Font::new(font_data.table_provider(0).unwrap()).unwrap();
+            let raw = font.map_glyphs(text, tag::LATN, MatchingPresentation::NotRequired);
+            let infos = font
+                .shape(
+                    raw,
+                    tag::LATN,
+                    None,
+                    FeatureMask::default(),
+                    &[],
+                    None,
+                    kerning,
+                )
+                .unwrap();
+            let positions = GlyphLayout::new(&mut font, &infos, TextDirection::LeftToRight, false)
+                .glyph_positions()
+                .unwrap();
+            infos
+                .iter()
+                .zip(positions)
+                .map(|(info, position)| (info.glyph.glyph_index, position.hori_advance))
+                .collect::<Vec<_>>()
+        };
+        let original = bundled_font();
+        let subset = subset_woff2(&original, &text.chars().collect())
+            .unwrap()
+            .unwrap();
+        // The kerning and ligature tables are kept whole, so only the outlines shrink
+        assert!(subset.len() < original.len() / 2);
+        assert_ne!(shaped(&original, true), shaped(&original, false));
+        assert_eq!(shaped(&subset, true), shaped(&original, true));
+    }
+
+    #[test]
+    fn safety_margin_is_validated() {
+        let err = FontSubsetter::from_config(&FontsConfig {
+            safety_margin: Some("latin2".to_string()),
+            ..Default::default()
+        })
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Unknown fonts.safety_margin 'latin2' (expected \"none\", \"ascii\" or \"latin1\")"
+        );
+    }
+}
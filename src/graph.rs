@@ -0,0 +1,360 @@
+use crate::utils::{Page, PageRef, strip_html_tags};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Site-wide graph of internal links between pages, keyed by content source path
+pub struct SiteGraph {
+    links: BTreeSet<(String, String)>,
+}
+
+#[derive(Serialize)]
+struct GraphJson<'a> {
+    nodes: Vec<GraphNode<'a>>,
+    links: Vec<GraphLink<'a>>,
+}
+
+#[derive(Serialize)]
+struct GraphNode<'a> {
+    id: &'a str,
+    title: &'a str,
+    kind: &'static str,
+    tags: &'a [String],
+}
+
+#[derive(Serialize)]
+struct GraphLink<'a> {
+    source: &'a str,
+    target: &'a str,
+}
+
+impl SiteGraph {
+    pub fn new(links: impl IntoIterator<Item = (String, String)>) -> Self {
+        SiteGraph {
+            links: links
+                .into_iter()
+                .filter(|(source, target)| source != target)
+                .collect(),
+        }
+    }
+
+    /// Fill in `backlinks` and `related` on every page. Related entries are
+    /// always blog posts, ranked by shared tags and text similarity.
+    pub fn annotate(&self, pages: &mut [&mut Page], related_count: usize) {
+        let refs: HashMap<String, PageRef> = pages
+            .iter()
+            .map(|page| (page.source_path.clone(), page_ref(page)))
+            .collect();
+
+        let mut incoming: HashMap<&str, Vec<PageRef>> = HashMap::new();
+        for (source, target) in &self.links {
+            if let Some(source_ref) = refs.get(source) {
+                incoming
+                    .entry(target.as_str())
+                    .or_default()
+                    .push(source_ref.clone());
+            }
+        }
+
+        let vectors = tf_idf_vectors(pages);
+        let related: Vec<Vec<PageRef>> = (0..pages.len())
+            .map(|index| {
+                if related_count == 0 {
+                    return Vec::new();
+                }
+                let mut scored: Vec<(f64, usize)> = (0..pages.len())
+                    .filter(|&other| other != index && is_post(pages[other]))
+                    .map(|other| {
+                        let shared_tags = shared_tags(pages[index], pages[other]) as f64;
+                        (
+                            shared_tags + cosine(&vectors[index], &vectors[other]),
+                            other,
+                        )
+                    })
+                    .filter(|(score, _)| *score > 0.0)
+                    .collect();
+                // Highest score first; ties fall back to newest date, then URL
+                scored.sort_by(|a, b| {
+                    b.0.total_cmp(&a.0)
+                        .then_with(|| pages[b.1].meta.date.cmp(&pages[a.1].meta.date))
+                        .then_with(|| pages[a.1].url.cmp(&pages[b.1].url))
+                });
+                scored
+                    .into_iter()
+                    .take(related_count)
+                    .map(|(_, other)| page_ref(pages[other]))
+                    .collect()
+            })
+            .collect();
+
+        for (page, related) in pages.iter_mut().zip(related) {
+            page.backlinks = incoming
+                .remove(page.source_path.as_str())
+                .unwrap_or_default();
+            page.related = related;
+        }
+    }
+
+    /// Serialize the graph as `{ nodes, links }` keyed by page URL, the shape
+    /// most force-directed graph libraries expect.
+    pub fn to_json(&self, pages: &[&Page]) -> serde_json::Result<String> {
+        let urls: HashMap<&str, &str> = pages
+            .iter()
+            .map(|page| (page.source_path.as_str(), page.url.as_str()))
+            .collect();
+        let nodes = pages
+            .iter()
+            .map(|page| GraphNode {
+                id: &page.url,
+                title: page.meta.title.as_deref().unwrap_or(&page.slug),
+                kind: if is_post(page) { "post" } else { "page" },
+                tags: &page.meta.tags,
+            })
+            .collect();
+        let links = self
+            .links
+            .iter()
+            .filter_map(|(source, target)| {
+                Some(GraphLink {
+                    source: urls.get(source.as_str())?,
+                    target: urls.get(target.as_str())?,
+                })
+            })
+            .collect();
+        serde_json::to_string_pretty(&GraphJson { nodes, links })
+    }
+}
+
+fn page_ref(page: &Page) -> PageRef {
+    PageRef {
+        title: page.meta.title.clone().unwrap_or_else(|| page.slug.clone()),
+        url: page.url.clone(),
+    }
+}
+
+fn is_post(page: &Page) -> bool {
+    page.source_path.starts_with("blog/")
+}
+
+fn shared_tags(a: &Page, b: &Page) -> usize {
+    let a_tags: HashSet<String> = a.meta.tags.iter().map(|t| t.to_lowercase()).collect();
+    b.meta
+        .tags
+        .iter()
+        .filter(|t| a_tags.contains(&t.to_lowercase()))
+        .count()
+}
+
+/// Unit-length TF-IDF vectors of each page's plain text
+fn tf_idf_vectors(pages: &[&mut Page]) -> Vec<HashMap<String, f64>> {
+    let term_counts: Vec<HashMap<String, f64>> = pages
+        .iter()
+        .map(|page| {
+            let mut counts = HashMap::new();
+            for term in tokenize(&strip_html_tags(&page.content_html)) {
+                *counts.entry(term).or_insert(0.0) += 1.0;
+            }
+            counts
+        })
+        .collect();
+
+    let mut document_frequency: HashMap<&str, f64> = HashMap::new();
+    for counts in &term_counts {
+        for term in counts.keys() {
+            *document_frequency.entry(term).or_insert(0.0) += 1.0;
+        }
+    }
+
+    let total = pages.len() as f64;
+    term_counts
+        .iter()
+        .map(|counts| {
+            let mut vector: HashMap<String, f64> = counts
+                .iter()
+                .map(|(term, count)| {
+                    let idf = (total / document_frequency[term.as_str()]).ln();
+                    (term.clone(), count * idf)
+                })
+                .filter(|(_, weight)| *weight > 0.0)
+                .collect();
+            let norm = vector.values().map(|w| w * w).sum::<f64>().sqrt();
+            if norm > 0.0 {
+                vector.values_mut().for_each(|w| *w /= norm);
+            }
+            vector
+        })
+        .collect()
+}
+
+fn cosine(a: &HashMap<String, f64>, b: &HashMap<String, f64>) -> f64 {
+    let (small, large) = if a.len() < b.len() { (a, b) } else { (b, a) };
+    small
+        .iter()
+        .filter_map(|(term, weight)| large.get(term).map(|other| weight * other))
+        .sum()
+}
+
+/// Lowercased words of two or more characters; CJK runs are split into
+/// character bigrams since they have no spaces between words.
+fn tokenize(text: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    for word in text
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+    {
+        let chars: Vec<char> = word.chars().collect();
+        if chars.iter().any(|c| is_cjk(*c)) {
+            terms.extend(chars.windows(2).map(|pair| pair.iter().collect::<String>()));
+        } else if chars.len() > 1 {
+            terms.push(word.to_string());
+        }
+    }
+    terms
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x30FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xAC00..=0xD7AF | 0xF900..=0xFAFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_page;
+
+    fn post(slug: &str, tags: &[&str], date: &str, text: &str) -> Page {
+        let mut page = test_page(
+            &format!("blog/{}.md", slug),
+            &format!("blog/{}.html", slug),
+            slug,
+            text,
+        );
+        page.meta.tags = tags.iter().map(|tag| tag.to_string()).collect();
+        page.meta.date = Some(date.to_string());
+        page
+    }
+
+    fn link(source: &str, target: &str) -> (String, String) {
+        (source.to_string(), target.to_string())
+    }
+
+    fn titles(refs: &[PageRef]) -> Vec<&str> {
+        refs.iter()
+            .map(|page_ref| page_ref.title.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn backlinks_list_each_linking_page_once() {
+        let mut pages = [
+            test_page("index.md", "index.html", "Home", ""),
+            test_page("about.md", "about.html", "About", ""),
+            post("first", &[], "2026-01-01", ""),
+        ];
+        let graph = SiteGraph::new([
+            link("about.md", "index.md"),
+            link("about.md", "index.md"),
+            link("blog/first.md", "index.md"),
+            link("index.md", "index.md"),
+            link("index.md", "about.md"),
+        ]);
+        let mut refs: Vec<&mut Page> = pages.iter_mut().collect();
+        graph.annotate(&mut refs, 3);
+
+        assert_eq!(
+            pages[0].backlinks,
+            [
+                PageRef {
+                    title: "About".to_string(),
+                    url: "about.html".to_string()
+                },
+                PageRef {
+                    title: "first".to_string(),
+                    url: "blog/first.html".to_string()
+                },
+            ]
+        );
+        assert_eq!(titles(&pages[1].backlinks), ["Home"]);
+        assert!(pages[2].backlinks.is_empty());
+    }
+
+    #[test]
+    fn related_posts_rank_by_tags_then_text() {
+        let mut pages = [
+            post(
+                "layout",
+                &["layout", "notes"],
+                "2026-01-01",
+                "<p>Margins and grids</p>",
+            ),
+            post(
+                "margins",
+                &["Notes", "layout"],
+                "2026-02-01",
+                "<p>Writing in the margins</p>",
+            ),
+            post("tagged", &["notes"], "2026-03-01", "<p>Unrelated words</p>"),
+            post(
+                "similar",
+                &[],
+                "2026-04-01",
+                "<p>Margins and grids again</p>",
+            ),
+            post("newer", &["notes"], "2026-05-01", "<p>Other words</p>"),
+            post("alone", &[], "2026-06-01", "<p>Nothing shared</p>"),
+            test_page(
+                "about.md",
+                "about.html",
+                "About",
+                "<p>Margins and grids</p>",
+            ),
+        ];
+        let graph = SiteGraph::new([]);
+        let mut refs: Vec<&mut Page> = pages.iter_mut().collect();
+        graph.annotate(&mut refs, 3);
+
+        // Two shared tags first, then one shared tag by date, then text alone
+        assert_eq!(titles(&pages[0].related), ["margins", "newer", "tagged"]);
+        assert_eq!(titles(&pages[3].related), ["layout", "margins"]);
+        assert!(pages[5].related.is_empty());
+        // Pages are never related, but get related posts
+        assert_eq!(titles(&pages[6].related), ["layout", "similar", "margins"]);
+
+        let mut refs: Vec<&mut Page> = pages.iter_mut().collect();
+        graph.annotate(&mut refs, 0);
+        assert!(pages.iter().all(|page| page.related.is_empty()));
+    }
+
+    #[test]
+    fn graph_json_uses_page_urls() {
+        let mut page = test_page("about.md", "about.html", "About", "");
+        page.meta.tags = vec!["site".to_string()];
+        let pages = [page, post("first", &[], "2026-01-01", "")];
+        let graph = SiteGraph::new([
+            link("about.md", "blog/first.md"),
+            link("about.md", "missing.md"),
+        ]);
+        let json: serde_json::Value =
+            serde_json::from_str(&graph.to_json(&pages.iter().collect::<Vec<_>>()).unwrap())
+                .unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "nodes": [
+                    { "id": "about.html", "title": "About", "kind": "page", "tags": ["site"] },
+                    { "id": "blog/first.html", "title": "first", "kind": "post", "tags": [] },
+                ],
+                "links": [{ "source": "about.html", "target": "blog/first.html" }],
+            })
+        );
+    }
+
+    #[test]
+    fn words_and_cjk_bigrams_are_terms() {
+        assert_eq!(
+            tokenize("A Rust-based 静态网站"),
+            ["rust", "based", "静态", "态网", "网站"]
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use crate::utils::DefaultAssets;
+use anyhow::{Context, Result};
+use colored::Colorize;
+use rayon::prelude::*;
+use regex::{Captures, Regex};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+
+const ATTRIBUTION: &str = "<!-- Font Awesome Free by @fontawesome - https://fontawesome.com License - https://fontawesome.com/license/free -->";
+
+static ICON_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"<i class="fa-(solid|regular|brands) fa-([a-z0-9-]+)((?: [^"]*)?)"([^>]*)></i>"#)
+        .unwrap()
+});
+
+/// Replaces Font Awesome `<i class="fa-solid fa-name">` tags in rendered pages
+/// with `<svg><use>` references to a sprite inlined at the top of `<body>`,
+/// holding only the icons that page uses. This includes icons in templates and
+/// in script strings. The symbols come from Font Awesome Free's own SVGs,
+/// installed in `theme/vendor/fontawesome/svgs/`; without them pages keep the
+/// `<i>` tags and load the icon font, and icon names are checked against the
+/// copy bundled with mdBear.
+pub struct IconSprite {
+    svgs_dir: Option<PathBuf>,
+    bundled: bool, // whether the binary has Font Awesome's SVGs to check names against
+    symbols: Mutex<HashMap<(String, String), Option<Symbol>>>,
+}
+
+#[derive(Clone)]
+struct Symbol {
+    view_box: String,
+    body: String,
+}
+
+impl IconSprite {
+    /// `svgs_dir` holds Font Awesome's `solid/`, `regular/` and `brands/` folders
+    pub fn new(svgs_dir: Option<PathBuf>) -> Self {
+        IconSprite {
+            svgs_dir,
+            bundled: DefaultAssets::iter()
+                .any(|file| file.starts_with("theme/vendor/fontawesome/svgs/")),
+            symbols: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether pages load the icon font, as there are no SVGs to inline
+    pub fn needs_font(&self) -> bool {
+        self.svgs_dir.is_none()
+    }
+
+    /// Rewrite every page in `pages`, then warn once per unknown icon with the
+    /// pages it appears on, relative to `output_dir`. Without SVGs to inline,
+    /// the pages are only checked for unknown icons.
+    pub fn apply(&self, pages: &[PathBuf], output_dir: &Path) -> Result<()> {
+        let results: Vec<_> = pages
+            .par_iter()
+            .map(|page| match self.svgs_dir {
+                Some(_) => self.apply_to(page),
+                None => self.check(page),
+            })
+            .collect();
+
+        let mut icons = BTreeSet::new();
+        let mut unknown: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (page, result) in pages.iter().zip(results) {
+            let (used, missing) = result?;
+            icons.extend(used);
+            let relative = page
+                .strip_prefix(output_dir)
+                .unwrap_or(page)
+                .to_string_lossy()
+                .replace('\\', "/");
+            for icon in missing {
+                unknown.entry(icon).or_default().push(relative.clone());
+            }
+        }
+
+        for (icon, pages) in &unknown {
+            eprintln!(
+                "{} {} {}",
+                "Warning: unknown Font Awesome icon".yellow(),
+                icon.yellow().bold(),
+                format!("(used in {})", pages.join(", ")).yellow()
+            );
+        }
+        if self.svgs_dir.is_some() {
+            println!(
+                "{} {} {}",
+                "Inlined".cyan(),
+                icons.len().to_string().cyan(),
+                "Font Awesome icon(s)".cyan()
+            );
+        }
+        Ok(())
+    }
+
+    /// Like `apply_to`, but leaves the page as it is and looks icons up in the
+    /// bundled SVGs
+    fn check(&self, page: &Path) -> Result<(BTreeSet<String>, BTreeSet<String>)> {
+        let html = fs::read_to_string(page)
+            .with_context(|| format!("Cannot read generated page: {:?}", page))?;
+        let mut used = BTreeSet::new();
+        let mut missing = BTreeSet::new();
+        for caps in ICON_RE.captures_iter(&html) {
+            let (style, name) = (&caps[1], &caps[2]);
+            let file = format!("theme/vendor/fontawesome/svgs/{}/{}.svg", style, name);
+            if self.bundled && DefaultAssets::get(&file).is_none() {
+                missing.insert(format!("fa-{} fa-{}", style, name));
+            } else {
+                used.insert(format!("fa-{}-{}", style, name));
+            }
+        }
+        Ok((used, missing))
+    }
+
+    /// Returns the icons the page uses and the ones Font Awesome does not define
+    fn apply_to(&self, page: &Path) -> Result<(BTreeSet<String>, BTreeSet<String>)> {
+        let html = fs::read_to_string(page)
+            .with_context(|| format!("Cannot read generated page: {:?}", page))?;
+        let mut used = BTreeMap::new();
+        let mut missing = BTreeSet::new();
+        let html = ICON_RE
+            .replace_all(&html, |caps: &Captures| {
+                let (style, name) = (&caps[1], &caps[2]);
+                let Some(symbol) = self.symbol(style, name) else {
+                    missing.insert(format!("fa-{} fa-{}", style, name));
+                    return caps[0].to_string();
+                };
+                let id = format!("fa-{}-{}", style, name);
+                let hidden = if caps[4].contains("aria-hidden") {
+                    ""
+                } else {
+                    " aria-hidden=\"true\""
+                };
+                let svg = format!(
+                    "<svg class=\"fa-icon fa-{} fa-{}{}\" viewBox=\"{}\"{}{}><use href=\"#{}\"></use></svg>",
+                    style, name, &caps[3], symbol.view_box, &caps[4], hidden, id
+                );
+                used.insert(id, symbol);
+                svg
+            })
+            .to_string();
+
+        let html = if used.is_empty() {
+            html
+        } else {
+            let symbols: String = used
+                .iter()
+                .map(|(id, symbol)| {
+                    format!(
+                        "<symbol id=\"{}\" viewBox=\"{}\">{}</symbol>",
+                        id, symbol.view_box, symbol.body
+                    )
+                })
+                .collect();
+            let sprite = format!(
+                "<svg id=\"mdbear-icons\" xmlns=\"http://www.w3.org/2000/svg\" style=\"display: none\">{}{}</svg>",
+                ATTRIBUTION, symbols
+            );
+            let body_re = Regex::new(r"<body[^>]*>").unwrap();
+            body_re
+                .replace(&html, |caps: &Captures| {
+                    format!("{}\n    {}", &caps[0], sprite)
+                })
+                .to_string()
+        };
+        fs::write(page, html)?;
+
+        Ok((used.into_keys().collect(), missing))
+    }
+
+    /// Load `<svgs_dir>/<style>/<name>.svg`, once per icon
+    fn symbol(&self, style: &str, name: &str) -> Option<Symbol> {
+        let key = (style.to_string(), name.to_string());
+        if let Some(symbol) = self.symbols.lock().unwrap().get(&key) {
+            return symbol.clone();
+        }
+        let path = self
+            .svgs_dir
+            .as_ref()?
+            .join(style)
+            .join(format!("{}.svg", name));
+        let symbol = fs::read_to_string(path)
+            .ok()
+            .and_then(|svg| parse_svg(&svg));
+        self.symbols.lock().unwrap().insert(key, symbol.clone());
+        symbol
+    }
+}
+
+/// The `viewBox` and drawing elements of an icon's SVG file, without comments
+fn parse_svg(svg: &str) -> Option<Symbol> {
+    let svg_re = Regex::new(r#"(?s)<svg[^>]*\bviewBox="([^"]+)"[^>]*>(.*)</svg>"#).unwrap();
+    let comment_re = Regex::new(r"(?s)<!--.*?-->").unwrap();
+    let caps = svg_re.captures(svg)?;
+    Some(Symbol {
+        view_box: caps[1].to_string(),
+        body: comment_re.replace_all(&caps[2], "").trim().to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn used_icons_are_inlined_from_their_svgs() {
+        let dir = test_dir("icons");
+        let svgs = dir.join("svgs");
+        for (icon, path) in [
+            ("solid/house", "M0 0h1"),
+            ("regular/copy", "M0 0h2"),
+            ("solid/pen-nib", "M0 0h3"),
+        ] {
+            write(
+                &svgs.join(format!("{}.svg", icon)),
+                &format!(
+                    "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 512 512\"><!--! Font Awesome Free 6.5.2 --><path d=\"{}\"/></svg>",
+                    path
+                ),
+            );
+        }
+        let page = dir.join("page.html");
+        write(
+            &page,
+            "<body class=\"page\">\n<i class=\"fa-solid fa-house big\" title=\"Home\"></i> <i class=\"fa-solid fa-unicorn-horn\"></i>\n<script>copy.innerHTML = '<i class=\"fa-regular fa-copy\" aria-hidden=\"true\"></i>';</script></body>",
+        );
+
+        let sprite = IconSprite::new(Some(svgs));
+        assert!(!sprite.needs_font());
+        let (used, missing) = sprite.apply_to(&page).unwrap();
+
+        assert_eq!(Vec::from_iter(used), ["fa-regular-copy", "fa-solid-house"]);
+        assert_eq!(Vec::from_iter(missing), ["fa-solid fa-unicorn-horn"]);
+        assert_eq!(
+            fs::read_to_string(&page).unwrap(),
+            format!(
+                "<body class=\"page\">\n    <svg id=\"mdbear-icons\" xmlns=\"http://www.w3.org/2000/svg\" style=\"display: none\">{}{}{}</svg>\n{}{}\n{}</body>",
+                ATTRIBUTION,
+                "<symbol id=\"fa-regular-copy\" viewBox=\"0 0 512 512\"><path d=\"M0 0h2\"/></symbol>",
+                "<symbol id=\"fa-solid-house\" viewBox=\"0 0 512 512\"><path d=\"M0 0h1\"/></symbol>",
+                "<svg class=\"fa-icon fa-solid fa-house big\" viewBox=\"0 0 512 512\" title=\"Home\" aria-hidden=\"true\"><use href=\"#fa-solid-house\"></use></svg> ",
+                "<i class=\"fa-solid fa-unicorn-horn\"></i>",
+                "<script>copy.innerHTML = '<svg class=\"fa-icon fa-regular fa-copy\" viewBox=\"0 0 512 512\" aria-hidden=\"true\"><use href=\"#fa-regular-copy\"></use></svg>';</script>",
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pages_without_svgs_keep_their_icon_tags() {
+        let dir = test_dir("icons-font");
+        let page = dir.join("page.html");
+        let html = "<body><i class=\"fa-solid fa-house\"></i><i class=\"fa-solid fa-unicorn-horn\"></i></body>";
+        write(&page, html);
+
+        let sprite = IconSprite::new(None);
+        assert!(sprite.needs_font());
+        sprite.apply(std::slice::from_ref(&page), &dir).unwrap();
+        assert_eq!(fs::read_to_string(&page).unwrap(), html);
+
+        // Names are checked against the bundled SVGs, when there are any
+        let checked = IconSprite {
+            bundled: true,
+            ..IconSprite::new(None)
+        };
+        let (_, missing) = checked.check(&page).unwrap();
+        assert!(missing.contains("fa-solid fa-unicorn-horn"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
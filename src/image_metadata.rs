@@ -0,0 +1,209 @@
+use anyhow::Result;
+use exif::{Exif, Field, In, Reader, Tag};
+use img_parts::jpeg::markers;
+use img_parts::webp::CHUNK_XMP;
+use img_parts::{Bytes, DynImage, ImageEXIF};
+use std::io::Cursor;
+
+/// EXIF fields kept by `[images] keep_copyright`
+const COPYRIGHT_TAGS: [Tag; 2] = [Tag::Copyright, Tag::Artist];
+
+/// What an encoded image carries besides pixels
+pub struct Metadata {
+    pub orientation: u32, // EXIF orientation, 1 when missing
+    pub has_gps: bool,
+    pub copyright: Option<Vec<u8>>, // minimal EXIF block with only the copyright fields
+}
+
+/// Read the EXIF block of a JPEG, PNG or WebP image. Other formats, and images
+/// without EXIF, report no metadata.
+pub fn read_metadata(bytes: &[u8]) -> Metadata {
+    let Some(exif) = read_exif(bytes) else {
+        return Metadata {
+            orientation: 1,
+            has_gps: false,
+            copyright: None,
+        };
+    };
+    Metadata {
+        orientation: exif
+            .get_field(Tag::Orientation, In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+            .unwrap_or(1),
+        has_gps: exif
+            .fields()
+            .any(|field| field.tag.context() == exif::Context::Gps),
+        copyright: minimal_exif(&exif, &COPYRIGHT_TAGS),
+    }
+}
+
+/// Minimal EXIF block for a file whose pixels are passed through as they are:
+/// the orientation, which is not baked into them, plus the copyright fields
+/// when `keep_copyright` is set
+pub fn passthrough_exif(bytes: &[u8], keep_copyright: bool) -> Option<Vec<u8>> {
+    let exif = read_exif(bytes)?;
+    let mut tags = vec![Tag::Orientation];
+    if keep_copyright {
+        tags.extend(COPYRIGHT_TAGS);
+    }
+    minimal_exif(&exif, &tags)
+}
+
+fn read_exif(bytes: &[u8]) -> Option<Exif> {
+    let image = DynImage::from_bytes(Bytes::copy_from_slice(bytes)).ok()??;
+    let raw = image.exif()?;
+    Reader::new().read_raw(raw.to_vec()).ok()
+}
+
+fn minimal_exif(exif: &Exif, tags: &[Tag]) -> Option<Vec<u8>> {
+    let fields: Vec<&Field> = tags
+        .iter()
+        .filter_map(|tag| exif.get_field(*tag, In::PRIMARY))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut block = Cursor::new(Vec::new());
+    writer.write(&mut block, exif.little_endian()).ok()?;
+    Some(block.into_inner())
+}
+
+/// Remove EXIF, XMP, IPTC and text metadata from a JPEG, PNG or WebP image
+/// without re-encoding it, then embed `exif` if given. Color profiles are kept.
+/// Returns `None` when the format is not supported or nothing changed.
+pub fn replace_metadata(bytes: &[u8], exif: Option<&[u8]>) -> Result<Option<Vec<u8>>> {
+    let Some(mut image) = DynImage::from_bytes(Bytes::copy_from_slice(bytes))? else {
+        return Ok(None);
+    };
+    match &mut image {
+        // EXIF and XMP both live in APP1 segments, IPTC in APP13
+        DynImage::Jpeg(jpeg) => jpeg.segments_mut().retain(|segment| {
+            !matches!(
+                segment.marker(),
+                markers::APP1 | markers::APP13 | markers::COM
+            )
+        }),
+        // XMP is stored in an iTXt chunk
+        DynImage::Png(png) => {
+            for kind in [*b"tEXt", *b"zTXt", *b"iTXt", *b"tIME"] {
+                png.remove_chunks_by_type(kind);
+            }
+        }
+        DynImage::WebP(webp) => webp.remove_chunks_by_id(CHUNK_XMP),
+    }
+    image.set_exif(exif.map(Bytes::copy_from_slice));
+
+    let mut stripped = Vec::with_capacity(bytes.len());
+    image.encoder().write_to(&mut stripped)?;
+    Ok((stripped != bytes).then_some(stripped))
+}
+
+/// A 40x20 phone photo to display rotated by 90 degrees, with a copyright and
+/// a location in its EXIF block
+#[cfg(test)]
+pub(crate) fn test_phone_photo() -> Vec<u8> {
+    use exif::Value;
+    let fields = [
+        Field {
+            tag: Tag::Orientation,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![6]),
+        },
+        Field {
+            tag: Tag::Copyright,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![b"Magic Squash".to_vec()]),
+        },
+        Field {
+            tag: Tag::GPSLatitudeRef,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![b"N".to_vec()]),
+        },
+    ];
+    let mut jpeg = Vec::new();
+    image::DynamicImage::new_rgb8(40, 20)
+        .write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)
+        .unwrap();
+    let mut writer = exif::experimental::Writer::new();
+    fields.iter().for_each(|field| writer.push_field(field));
+    let mut block = Cursor::new(Vec::new());
+    writer.write(&mut block, false).unwrap();
+    let mut photo = DynImage::from_bytes(Bytes::from(jpeg)).unwrap().unwrap();
+    photo.set_exif(Some(Bytes::from(block.into_inner())));
+    photo.encoder().bytes().to_vec()
+}
+
+/// Numbers of the EXIF tags in `bytes`, sorted
+#[cfg(test)]
+pub(crate) fn test_exif_tags(bytes: &[u8]) -> Vec<u16> {
+    let Some(exif) = read_exif(bytes) else {
+        return Vec::new();
+    };
+    let mut tags: Vec<u16> = exif.fields().map(|field| field.tag.number()).collect();
+    tags.sort();
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_reports_orientation_location_and_copyright() {
+        let photo = test_phone_photo();
+        let metadata = read_metadata(&photo);
+        assert_eq!(metadata.orientation, 6);
+        assert!(metadata.has_gps);
+
+        let copyright = metadata.copyright.unwrap();
+        let exif = Reader::new().read_raw(copyright).unwrap();
+        assert_eq!(
+            exif.fields().map(|field| field.tag).collect::<Vec<_>>(),
+            [Tag::Copyright]
+        );
+
+        let plain = read_metadata(b"not an image");
+        assert_eq!(plain.orientation, 1);
+        assert!(!plain.has_gps && plain.copyright.is_none());
+    }
+
+    #[test]
+    fn passed_through_files_keep_their_orientation() {
+        let photo = test_phone_photo();
+        let stripped = |keep_copyright| {
+            let exif = passthrough_exif(&photo, keep_copyright);
+            replace_metadata(&photo, exif.as_deref()).unwrap().unwrap()
+        };
+
+        assert_eq!(
+            test_exif_tags(&stripped(false)),
+            [Tag::Orientation.number()]
+        );
+        let kept = stripped(true);
+        assert_eq!(
+            test_exif_tags(&kept),
+            [Tag::Orientation.number(), Tag::Copyright.number()]
+        );
+        assert!(!read_metadata(&kept).has_gps);
+        assert!(kept.len() < photo.len());
+    }
+
+    #[test]
+    fn unchanged_and_unsupported_files_are_left_alone() {
+        let mut jpeg = Vec::new();
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)
+            .unwrap();
+        assert!(replace_metadata(&jpeg, None).unwrap().is_none());
+
+        let mut gif = Vec::new();
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut Cursor::new(&mut gif), image::ImageFormat::Gif)
+            .unwrap();
+        assert!(replace_metadata(&gif, None).unwrap().is_none());
+    }
+}
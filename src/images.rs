@@ -0,0 +1,1794 @@
+use crate::image_metadata::{passthrough_exif, read_metadata, replace_metadata};
+use crate::links::resolve_source_path;
+use crate::utils::ImagesConfig;
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use colored::Colorize;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::Triangle;
+use image::{
+    AnimationDecoder, DynamicImage, GenericImageView, ImageDecoder, ImageEncoder, ImageFormat,
+    ImageReader, imageops::Lanczos3,
+};
+use rayon::prelude::*;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufReader, BufWriter, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use tera::Value;
+use walkdir::WalkDir;
+
+const DEFAULT_MAX_WIDTH: u32 = 1201;
+const DEFAULT_QUALITY: u8 = 80;
+const DEFAULT_WIDTHS: [u32; 2] = [480, 960];
+// The text column is at most 42rem wide
+const DEFAULT_SIZES: &str = "(min-width: 42rem) 42rem, 100vw";
+
+/// Result of running one source image through the pipeline
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessedImage {
+    pub src: String, // full-size file in the fallback (last) format, e.g. "assets/photo.webp"
+    pub width: u32,
+    pub height: u32,
+    pub variants: Vec<ImageVariant>, // every file written, grouped by format in order of preference
+    pub placeholder: Placeholder,
+}
+
+/// Shown behind an image while it loads
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Placeholder {
+    pub color: String,        // average color, e.g. "#a1b2c3"
+    pub lqip: Option<String>, // tiny WebP data URI; omitted for images with transparency
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageVariant {
+    pub path: String,
+    pub mime: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ProcessedImage {
+    /// Variants grouped by MIME type, in order of preference, smallest first
+    pub fn sources(&self) -> Vec<(&str, Vec<&ImageVariant>)> {
+        let mut sources: Vec<(&str, Vec<&ImageVariant>)> = Vec::new();
+        for variant in &self.variants {
+            match sources.last_mut() {
+                Some((mime, variants)) if *mime == variant.mime => variants.push(variant),
+                _ => sources.push((&variant.mime, vec![variant])),
+            }
+        }
+        sources
+    }
+}
+
+/// Processed images keyed by their source path relative to the content directory,
+/// e.g. "assets/images/photo.jpg"
+#[derive(Debug, Clone, Default)]
+pub struct ImageManifest {
+    images: HashMap<String, ProcessedImage>,
+    sizes: String,
+    placeholder: PlaceholderMode,
+}
+
+impl ImageManifest {
+    pub fn get(&self, source: &str) -> Option<&ProcessedImage> {
+        self.images.get(source)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum PlaceholderMode {
+    #[default]
+    Lqip,
+    Color,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    WebP,
+    Avif,
+    Original,
+}
+
+/// `ImagesConfig` with defaults applied and globs compiled
+struct Settings {
+    max_width: u32,
+    quality: u8,
+    formats: Vec<OutputFormat>,
+    widths: Vec<u32>,
+    sizes: String,
+    placeholder: PlaceholderMode,
+    keep_original: bool,
+    keep_copyright: bool,
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl Settings {
+    fn from_config(config: &ImagesConfig) -> Result<Self> {
+        let quality = config.quality.unwrap_or(DEFAULT_QUALITY);
+        if !(1..=100).contains(&quality) {
+            bail!(
+                "[images] quality must be between 1 and 100, got {}",
+                quality
+            );
+        }
+        let max_width = config.max_width.unwrap_or(DEFAULT_MAX_WIDTH);
+        if max_width == 0 {
+            bail!("[images] max_width must be greater than 0");
+        }
+
+        let formats = match &config.formats {
+            Some(formats) => formats
+                .iter()
+                .map(|format| match format.to_lowercase().as_str() {
+                    "webp" => Ok(OutputFormat::WebP),
+                    "avif" => Ok(OutputFormat::Avif),
+                    "original" => Ok(OutputFormat::Original),
+                    other => bail!(
+                        "Unknown image format `{}` in [images] formats (expected webp, avif or original)",
+                        other
+                    ),
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => vec![OutputFormat::WebP],
+        };
+        if formats.is_empty() {
+            bail!("[images] formats cannot be empty");
+        }
+
+        Ok(Settings {
+            max_width,
+            quality,
+            formats,
+            widths: config.widths.clone().unwrap_or(DEFAULT_WIDTHS.to_vec()),
+            sizes: config.sizes.clone().unwrap_or(DEFAULT_SIZES.to_string()),
+            placeholder: match config.placeholder.as_deref().unwrap_or("lqip") {
+                "lqip" => PlaceholderMode::Lqip,
+                "color" => PlaceholderMode::Color,
+                "none" => PlaceholderMode::None,
+                other => bail!(
+                    "Unknown [images] placeholder `{}` (expected lqip, color or none)",
+                    other
+                ),
+            },
+            keep_original: config.keep_original.unwrap_or(false),
+            keep_copyright: config.keep_copyright.unwrap_or(false),
+            include: compile_globs(config.include.as_deref())?,
+            exclude: compile_globs(config.exclude.as_deref())?,
+        })
+    }
+
+    /// Everything that affects the encoded files, for cache keys. Bump the
+    /// version whenever the encoding itself changes.
+    fn fingerprint(&self) -> String {
+        format!(
+            "v5:{}:{}:{:?}:{:?}:{}:{}",
+            self.max_width,
+            self.quality,
+            self.formats,
+            self.widths,
+            self.keep_original,
+            self.keep_copyright
+        )
+    }
+
+    fn matches(&self, source: &str) -> bool {
+        self.include.as_ref().is_none_or(|set| set.is_match(source))
+            && !self
+                .exclude
+                .as_ref()
+                .is_some_and(|set| set.is_match(source))
+    }
+}
+
+fn compile_globs(patterns: Option<&[String]>) -> Result<Option<GlobSet>> {
+    let Some(patterns) = patterns else {
+        return Ok(None);
+    };
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern.trim_start_matches('/'))
+            .with_context(|| format!("Invalid glob `{}` in [images]", pattern))?;
+        builder.add(glob);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Resize and re-encode every image under `output_dir/assets` according to `config`.
+/// Encoded variants are upright and carry no metadata. Files passed through as
+/// they are (the kept original, excluded images and those that fail to decode)
+/// have their metadata stripped without re-encoding, keeping only the EXIF
+/// orientation. With a `cache_dir`, processed files are reused across builds and
+/// entries no longer referenced by any image are removed afterwards.
+pub fn process_images(
+    output_dir: &Path,
+    config: &ImagesConfig,
+    cache_dir: Option<&Path>,
+) -> Result<ImageManifest> {
+    let settings = Settings::from_config(config)?;
+    let mut manifest = ImageManifest {
+        images: HashMap::new(),
+        sizes: settings.sizes.clone(),
+        placeholder: settings.placeholder,
+    };
+    let cache_dir = cache_dir.map(|dir| dir.join("images"));
+    let mut used_entries = HashSet::new();
+    let mut reused = 0;
+    let mut with_gps = Vec::new();
+
+    // Encode in parallel, then report and collect in path order so output stays deterministic
+    let images = collect_images(output_dir, &settings)?;
+    let images = skip_clashing_outputs(output_dir, images, &settings)?;
+    let results: Vec<_> = images
+        .par_iter()
+        .map(|(path, source)| process_cached(path, source, &settings, cache_dir.as_deref()))
+        .collect();
+
+    let mut passthrough = Vec::new();
+    for ((path, source), result) in images.iter().zip(results) {
+        match result {
+            Ok(Processed {
+                image,
+                key,
+                cached,
+                has_gps,
+            }) => {
+                if has_gps {
+                    with_gps.push(source.clone());
+                }
+                if cached {
+                    reused += 1;
+                } else {
+                    println!(
+                        "{} {} -> {} ({}x{})",
+                        "Converted:".cyan(),
+                        source,
+                        image
+                            .variants
+                            .iter()
+                            .map(|v| v.path.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        image.width,
+                        image.height
+                    );
+                }
+                used_entries.insert(key);
+                manifest.images.insert(source.clone(), image);
+            }
+            Err(e) => {
+                eprintln!(
+                    "{} {}: {:#}",
+                    "Warning: could not convert".yellow(),
+                    path.display(),
+                    e
+                );
+                passthrough.push((path.clone(), source.clone()));
+            }
+        }
+    }
+
+    passthrough.extend(collect_passthrough(output_dir, &images)?);
+    passthrough.sort();
+    let results: Vec<_> = passthrough
+        .par_iter()
+        .map(|(path, _)| strip_in_place(path, settings.keep_copyright))
+        .collect();
+    for ((_, source), result) in passthrough.iter().zip(results) {
+        match result {
+            Ok(true) => with_gps.push(source.clone()),
+            Ok(false) => {}
+            Err(e) => eprintln!(
+                "{} {}: {:#}",
+                "Warning: could not strip metadata from".yellow(),
+                source,
+                e
+            ),
+        }
+    }
+    with_gps.sort();
+
+    if let Some(cache_dir) = &cache_dir {
+        if reused > 0 {
+            println!(
+                "{} {} {}",
+                "Reused".cyan(),
+                reused.to_string().cyan(),
+                "cached image(s)".cyan()
+            );
+        }
+        remove_stale_entries(cache_dir, &used_entries)?;
+    }
+
+    for source in &with_gps {
+        eprintln!(
+            "{} {} {}",
+            "Warning:".yellow(),
+            source,
+            "contained GPS coordinates; they were removed from the output, but the file in content/ still has them"
+                .yellow()
+        );
+    }
+    Ok(manifest)
+}
+
+/// Converted files are named after the source without its extension. A source
+/// whose converted file is already there, such as a WebP committed next to its
+/// PNG, is left as it is with a warning and the existing file is kept. Two
+/// sources in a folder that differ only in extension fail the build.
+fn skip_clashing_outputs(
+    output_dir: &Path,
+    images: Vec<(PathBuf, String)>,
+    settings: &Settings,
+) -> Result<Vec<(PathBuf, String)>> {
+    let relative = |path: &Path| {
+        path.strip_prefix(output_dir)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .replace('\\', "/")
+    };
+    let targets = |path: &Path| -> Vec<PathBuf> {
+        settings
+            .formats
+            .iter()
+            .filter_map(|format| match format {
+                OutputFormat::WebP => Some(path.with_extension("webp")),
+                OutputFormat::Avif => Some(path.with_extension("avif")),
+                OutputFormat::Original => None,
+            })
+            .collect()
+    };
+
+    let mut kept = Vec::new();
+    for (path, source) in images {
+        match targets(&path).into_iter().find(|target| target.exists()) {
+            Some(existing) => eprintln!(
+                "{} {} {}",
+                "Warning: not converting".yellow(),
+                source,
+                format!(
+                    "as {} already exists; it is kept and the image is copied as it is",
+                    relative(&existing)
+                )
+                .yellow()
+            ),
+            None => kept.push((path, source)),
+        }
+    }
+
+    let mut seen: HashMap<PathBuf, &str> = HashMap::new();
+    for (path, source) in &kept {
+        for target in targets(path) {
+            if let Some(other) = seen.insert(target.clone(), source) {
+                bail!(
+                    "Images {} and {} would both be converted to {}; rename one of them",
+                    other,
+                    source,
+                    relative(&target)
+                );
+            }
+        }
+    }
+    Ok(kept)
+}
+
+/// JPEG, PNG and WebP files under `output_dir/assets` that are not in `images`,
+/// which are copied to the output as they are
+fn collect_passthrough(
+    output_dir: &Path,
+    images: &[(PathBuf, String)],
+) -> Result<Vec<(PathBuf, String)>> {
+    let assets_dir = output_dir.join("assets");
+    if !assets_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let processed: HashSet<&PathBuf> = images.iter().map(|(path, _)| path).collect();
+    let mut files = Vec::new();
+    for entry in WalkDir::new(&assets_dir).sort_by_file_name() {
+        let entry = entry?;
+        let ext = entry
+            .path()
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase())
+            .unwrap_or_default();
+        if !entry.file_type().is_file()
+            || !matches!(ext.as_str(), "png" | "jpg" | "jpeg" | "webp")
+            || processed.contains(&entry.path().to_path_buf())
+        {
+            continue;
+        }
+        let path = entry.into_path();
+        let source = path
+            .strip_prefix(output_dir)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .replace('\\', "/");
+        files.push((path, source));
+    }
+    Ok(files)
+}
+
+/// Strip the metadata of an image without re-encoding it, keeping the EXIF
+/// orientation (and copyright fields when configured). Returns whether it had
+/// GPS data.
+fn strip_in_place(path: &Path, keep_copyright: bool) -> Result<bool> {
+    let bytes = fs::read(path)?;
+    let exif = passthrough_exif(&bytes, keep_copyright);
+    if let Some(stripped) = replace_metadata(&bytes, exif.as_deref())? {
+        fs::write(path, stripped)?;
+    }
+    Ok(read_metadata(&bytes).has_gps)
+}
+
+/// Images under `output_dir/assets` selected by the include/exclude globs, as
+/// `(path, source)` pairs where `source` is relative to `output_dir`
+fn collect_images(output_dir: &Path, settings: &Settings) -> Result<Vec<(PathBuf, String)>> {
+    let assets_dir = output_dir.join("assets");
+    if !assets_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut images = Vec::new();
+    for entry in WalkDir::new(&assets_dir).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.into_path();
+
+        // Only process common image extensions
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase())
+            .unwrap_or_default();
+        if !matches!(ext.as_str(), "png" | "jpg" | "jpeg" | "gif") {
+            continue;
+        }
+        // Encoding would keep only the first frame, so animations are copied as they are
+        if ext == "gif" && is_animated_gif(&path) {
+            continue;
+        }
+
+        let Ok(relative) = path.strip_prefix(output_dir) else {
+            continue;
+        };
+        let source = relative.to_string_lossy().replace('\\', "/");
+        if settings.matches(&source) {
+            images.push((path, source));
+        }
+    }
+    Ok(images)
+}
+
+/// Whether the GIF at `path` has more than one frame
+fn is_animated_gif(path: &Path) -> bool {
+    let Ok(file) = fs::File::open(path) else {
+        return false;
+    };
+    let Ok(decoder) = GifDecoder::new(BufReader::new(file)) else {
+        return false;
+    };
+    decoder.into_frames().take(2).count() > 1
+}
+
+struct Processed {
+    image: ProcessedImage,
+    key: String,
+    cached: bool,
+    has_gps: bool, // whether the source had GPS data, which the output never keeps
+}
+
+/// What is stored next to the cached files in `<cache>/images/<key>/entry.json`
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    image: ProcessedImage,
+    files: Vec<String>, // names of the files written next to the source image
+}
+
+fn process_cached(
+    path: &Path,
+    source: &str,
+    settings: &Settings,
+    cache_dir: Option<&Path>,
+) -> Result<Processed> {
+    let bytes = fs::read(path)?;
+    let has_gps = read_metadata(&bytes).has_gps;
+    let mut hasher = Sha256::new();
+    hasher.update(settings.fingerprint());
+    hasher.update(source);
+    hasher.update(&bytes);
+    let key = format!("{:x}", hasher.finalize());
+
+    let Some(cache_dir) = cache_dir else {
+        let (image, _) = process_image(path, source, settings)?;
+        return Ok(Processed {
+            image,
+            key,
+            cached: false,
+            has_gps,
+        });
+    };
+
+    let entry_dir = cache_dir.join(&key);
+    if let Some(image) = restore_entry(&entry_dir, path, settings) {
+        return Ok(Processed {
+            image,
+            key,
+            cached: true,
+            has_gps,
+        });
+    }
+
+    let (image, files) = process_image(path, source, settings)?;
+    if let Err(e) = store_entry(&entry_dir, path, &image, files) {
+        eprintln!(
+            "{} {}: {:#}",
+            "Warning: could not cache".yellow(),
+            source,
+            e
+        );
+    }
+    Ok(Processed {
+        image,
+        key,
+        cached: false,
+        has_gps,
+    })
+}
+
+/// Copy a cached entry's files next to `path`. Returns `None` when the entry
+/// is missing or incomplete, in which case the image is processed again.
+fn restore_entry(entry_dir: &Path, path: &Path, settings: &Settings) -> Option<ProcessedImage> {
+    let entry: CacheEntry =
+        serde_json::from_str(&fs::read_to_string(entry_dir.join("entry.json")).ok()?).ok()?;
+    for file in &entry.files {
+        fs::copy(entry_dir.join(file), path.with_file_name(file)).ok()?;
+    }
+    if !settings.keep_original && !settings.formats.contains(&OutputFormat::Original) {
+        fs::remove_file(path).ok()?;
+    }
+    Some(entry.image)
+}
+
+fn store_entry(
+    entry_dir: &Path,
+    path: &Path,
+    image: &ProcessedImage,
+    files: Vec<String>,
+) -> Result<()> {
+    if entry_dir.exists() {
+        fs::remove_dir_all(entry_dir)?;
+    }
+    fs::create_dir_all(entry_dir)?;
+    for file in &files {
+        fs::copy(path.with_file_name(file), entry_dir.join(file))?;
+    }
+    // Written last so that an interrupted build leaves an entry that is ignored
+    let entry = CacheEntry {
+        image: image.clone(),
+        files,
+    };
+    fs::write(entry_dir.join("entry.json"), serde_json::to_string(&entry)?)?;
+    Ok(())
+}
+
+fn remove_stale_entries(cache_dir: &Path, used: &HashSet<String>) -> Result<()> {
+    if !cache_dir.exists() {
+        return Ok(());
+    }
+    let mut removed = 0;
+    for entry in fs::read_dir(cache_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !used.contains(&name) {
+            fs::remove_dir_all(entry.path())?;
+            removed += 1;
+        }
+    }
+    if removed > 0 {
+        println!(
+            "{} {} {}",
+            "Removed".cyan(),
+            removed.to_string().cyan(),
+            "stale image cache entries".cyan()
+        );
+    }
+    Ok(())
+}
+
+/// Encode every variant of one image next to `path`. Also returns the names of
+/// the files that were written, which is what the cache has to store.
+fn process_image(
+    path: &Path,
+    source: &str,
+    settings: &Settings,
+) -> Result<(ProcessedImage, Vec<String>)> {
+    let bytes = fs::read(path)?;
+    let img = decode_oriented(&bytes)?;
+    let (width, height) = img.dimensions();
+    let resized: DynamicImage = if width > settings.max_width {
+        img.resize(
+            settings.max_width,
+            scaled_height(width, height, settings.max_width),
+            Lanczos3,
+        )
+    } else {
+        img
+    };
+    let (full_width, full_height) = resized.dimensions();
+
+    let (source_dir, file_name) = source.rsplit_once('/').unwrap_or(("", source));
+    let (stem, source_ext) = file_name.rsplit_once('.').unwrap_or((file_name, ""));
+    let sibling = |name: &str| {
+        if source_dir.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", source_dir, name)
+        }
+    };
+
+    // Smaller copies for `srcset`; the full-size file is always added last
+    let mut widths: Vec<u32> = settings
+        .widths
+        .iter()
+        .copied()
+        .filter(|w| *w > 0 && *w < full_width)
+        .collect();
+    widths.sort_unstable();
+    widths.dedup();
+    let scaled: Vec<DynamicImage> = widths
+        .iter()
+        .map(|&w| resized.resize(w, scaled_height(full_width, full_height, w), Lanczos3))
+        .collect();
+
+    let mut variants = Vec::new();
+    let mut written = Vec::new();
+    for format in &settings.formats {
+        let ext = match format {
+            OutputFormat::WebP => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Original => source_ext,
+        };
+        let mime = match format {
+            OutputFormat::WebP => "image/webp".to_string(),
+            OutputFormat::Avif => "image/avif".to_string(),
+            OutputFormat::Original => ImageFormat::from_path(path)?.to_mime_type().to_string(),
+        };
+
+        for img in &scaled {
+            let name = format!("{}-{}w.{}", stem, img.width(), ext);
+            write_variant(img, *format, &path.with_file_name(&name), settings.quality)?;
+            written.push(name.clone());
+            variants.push(ImageVariant {
+                path: sibling(&name),
+                mime: mime.clone(),
+                width: img.width(),
+                height: img.height(),
+            });
+        }
+
+        let name = match format {
+            // The untouched source file already is the original
+            OutputFormat::Original if full_width == width => file_name.to_string(),
+            // A kept original wider than max_width stays out of `srcset`, in
+            // favor of a copy scaled down like the other formats
+            OutputFormat::Original if settings.keep_original => {
+                let name = format!("{}-{}w.{}", stem, full_width, ext);
+                write_variant(
+                    &resized,
+                    *format,
+                    &path.with_file_name(&name),
+                    settings.quality,
+                )?;
+                written.push(name.clone());
+                name
+            }
+            _ => {
+                let name = format!("{}.{}", stem, ext);
+                write_variant(
+                    &resized,
+                    *format,
+                    &path.with_file_name(&name),
+                    settings.quality,
+                )?;
+                written.push(name.clone());
+                name
+            }
+        };
+        variants.push(ImageVariant {
+            path: sibling(&name),
+            mime,
+            width: full_width,
+            height: full_height,
+        });
+    }
+
+    // Encoders write no metadata, so carry the kept copyright fields over
+    if settings.keep_copyright
+        && let Some(copyright) = read_metadata(&bytes).copyright
+    {
+        for name in &written {
+            embed_exif(&path.with_file_name(name), &copyright)?;
+        }
+    }
+
+    if !settings.keep_original && !settings.formats.contains(&OutputFormat::Original) {
+        fs::remove_file(path)?;
+    } else if !written.iter().any(|name| name == file_name) {
+        // The source file is passed through; it is cached too, as stripped
+        strip_in_place(path, settings.keep_copyright)?;
+        written.push(file_name.to_string());
+    }
+
+    let image = ProcessedImage {
+        src: variants.last().map(|v| v.path.clone()).unwrap_or_default(),
+        width: full_width,
+        height: full_height,
+        variants,
+        placeholder: placeholder(&resized)?,
+    };
+    Ok((image, written))
+}
+
+/// Decode an image and turn it upright according to its EXIF orientation
+pub(crate) fn decode_oriented(bytes: &[u8]) -> Result<DynamicImage> {
+    let mut decoder = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+/// Set the EXIF block of an encoded file; formats without EXIF support are skipped
+fn embed_exif(path: &Path, exif: &[u8]) -> Result<()> {
+    if let Some(bytes) = replace_metadata(&fs::read(path)?, Some(exif))? {
+        fs::write(path, bytes)?;
+    }
+    Ok(())
+}
+
+/// Average color plus a 16px preview that browsers blur when scaling it up
+fn placeholder(img: &DynamicImage) -> Result<Placeholder> {
+    let thumb = img.thumbnail(16, 16);
+    let average = thumb.resize_exact(1, 1, Triangle).to_rgb8();
+    let [r, g, b] = average.get_pixel(0, 0).0;
+    let transparent = thumb.color().has_alpha() && thumb.to_rgba8().pixels().any(|p| p.0[3] < 255);
+    let lqip = if transparent {
+        None
+    } else {
+        Some(format!(
+            "data:image/webp;base64,{}",
+            BASE64.encode(encode_webp(&thumb, 50)?)
+        ))
+    };
+    Ok(Placeholder {
+        color: format!("#{:02x}{:02x}{:02x}", r, g, b),
+        lqip,
+    })
+}
+
+/// `<cache>/resized/<file>.json`, the `ResizedImage` stored for a cached file
+fn sidecar_path(cached: &Path) -> PathBuf {
+    let mut path = cached.as_os_str().to_owned();
+    path.push(".json");
+    PathBuf::from(path)
+}
+
+/// Copy a cached resized file to `output_path`. Returns `None` when the file
+/// or its sidecar is missing, in which case the image is resized again.
+fn restore_resized(cached: &Path, output_path: &Path) -> Option<ResizedImage> {
+    let resized = serde_json::from_str(&fs::read_to_string(sidecar_path(cached)).ok()?).ok()?;
+    fs::copy(cached, output_path).ok()?;
+    Some(resized)
+}
+
+fn scaled_height(width: u32, height: u32, new_width: u32) -> u32 {
+    (height as u64 * new_width as u64 / width as u64).max(1) as u32
+}
+
+fn write_variant(img: &DynamicImage, format: OutputFormat, path: &Path, quality: u8) -> Result<()> {
+    match format {
+        OutputFormat::WebP => fs::write(path, encode_webp(img, quality)?)?,
+        OutputFormat::Avif => {
+            let writer = BufWriter::new(fs::File::create(path)?);
+            let rgba = img.to_rgba8();
+            AvifEncoder::new_with_speed_quality(writer, 6, quality).write_image(
+                &rgba,
+                rgba.width(),
+                rgba.height(),
+                image::ExtendedColorType::Rgba8,
+            )?;
+        }
+        OutputFormat::Original => write_original_format(img, path, quality)?,
+    }
+    Ok(())
+}
+
+fn encode_webp(img: &DynamicImage, quality: u8) -> Result<Vec<u8>> {
+    let memory = if img.color().has_alpha() {
+        let rgba = img.to_rgba8();
+        webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+            .encode_simple(false, quality as f32)
+    } else {
+        let rgb = img.to_rgb8();
+        webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height())
+            .encode_simple(false, quality as f32)
+    };
+    memory
+        .map(|memory| memory.to_vec())
+        .map_err(|e| anyhow::anyhow!("WebP encoding failed: {:?}", e))
+}
+
+fn write_original_format(img: &DynamicImage, path: &Path, quality: u8) -> Result<()> {
+    let format = ImageFormat::from_path(path)?;
+    if format == ImageFormat::WebP {
+        return Ok(fs::write(path, encode_webp(img, quality)?)?);
+    }
+    let mut writer = BufWriter::new(fs::File::create(path)?);
+    if format == ImageFormat::Jpeg {
+        img.to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut writer, quality))?;
+    } else {
+        img.write_to(&mut writer, format)?;
+    }
+    Ok(())
+}
+
+static IMG_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"<img\b([^>]*?)(\s*/?)>"#).unwrap());
+
+/// Point `<img>` tags at the files produced by the image pipeline, adding
+/// `srcset`/`sizes`, intrinsic dimensions and lazy loading. Images encoded to
+/// several formats are wrapped in a `<picture>` with one `<source>` per format.
+/// `page_source` is the page's path relative to the content directory, which
+/// mirrors the output layout, so relative `src` values resolve the same way.
+pub fn rewrite_image_sources(
+    html: &str,
+    images: Option<&ImageManifest>,
+    page_source: &str,
+) -> String {
+    let Some(images) = images else {
+        return html.to_string();
+    };
+    IMG_RE
+        .replace_all(html, |caps: &Captures| {
+            let attrs = &caps[1];
+            let Some(src) = attr_value(attrs, "src") else {
+                return caps[0].to_string();
+            };
+            let Some(processed) = find_image(images, page_source, &src) else {
+                return caps[0].to_string();
+            };
+            render_img(attrs, &caps[2], &src, processed, images)
+        })
+        .to_string()
+}
+
+static SRC_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(\ssrc\s*=\s*)(["'])[^"']*(["'])"#).unwrap());
+
+fn render_img(
+    attrs: &str,
+    closing: &str,
+    src: &str,
+    processed: &ProcessedImage,
+    images: &ImageManifest,
+) -> String {
+    // Variants live next to the source, so reuse the directory part of `src`
+    let dir = src
+        .split(['?', '#'])
+        .next()
+        .and_then(|path| path.rfind('/').map(|index| &src[..=index]))
+        .unwrap_or("");
+    let url = |path: &str| format!("{}{}", dir, path.rsplit('/').next().unwrap_or(path));
+    let suffix = src
+        .find(['?', '#'])
+        .map(|index| &src[index..])
+        .unwrap_or("");
+
+    let mut attrs = SRC_RE
+        .replace(attrs, |caps: &Captures| {
+            format!(
+                "{}{}{}{}{}",
+                &caps[1],
+                &caps[2],
+                url(&processed.src),
+                suffix,
+                &caps[3]
+            )
+        })
+        .to_string();
+    // Authors who wrote their own `srcset` keep full control
+    if attr_value(&attrs, "srcset").is_some() {
+        return format!("<img{}{}>", attrs, closing);
+    }
+
+    let declared_width = attr_value(&attrs, "width").and_then(|w| w.trim().parse::<u32>().ok());
+    let sizes = match declared_width {
+        Some(width) => format!("{}px", width),
+        None => images.sizes.clone(),
+    };
+    let sources = processed.sources();
+    let srcset = |variants: &[&ImageVariant]| {
+        variants
+            .iter()
+            .map(|v| format!("{} {}w", url(&v.path), v.width))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let (_, fallback) = sources.last().expect("processed images have variants");
+    if fallback.len() > 1 {
+        attrs.push_str(&format!(
+            " srcset=\"{}\" sizes=\"{}\"",
+            srcset(fallback),
+            sizes
+        ));
+    }
+    match (declared_width, attr_value(&attrs, "height")) {
+        (None, None) => attrs.push_str(&format!(
+            " width=\"{}\" height=\"{}\"",
+            processed.width, processed.height
+        )),
+        (Some(width), None) => attrs.push_str(&format!(
+            " height=\"{}\"",
+            scaled_height(processed.width, processed.height, width)
+        )),
+        _ => {}
+    }
+    if attr_value(&attrs, "loading").is_none() {
+        attrs.push_str(" loading=\"lazy\"");
+    }
+    if attr_value(&attrs, "decoding").is_none() {
+        attrs.push_str(" decoding=\"async\"");
+    }
+    if images.placeholder != PlaceholderMode::None && attr_value(&attrs, "style").is_none() {
+        attrs.push_str(&format!(
+            " style=\"{}\" data-dominant-color=\"{}\"",
+            placeholder_style(&processed.placeholder, images.placeholder),
+            processed.placeholder.color
+        ));
+    }
+
+    let img = format!("<img{}{}>", attrs, closing);
+    if sources.len() == 1 {
+        return img;
+    }
+    let mut picture = String::from("<picture>");
+    for (mime, variants) in &sources[..sources.len() - 1] {
+        picture.push_str(&format!(
+            "<source type=\"{}\" srcset=\"{}\" sizes=\"{}\">",
+            mime,
+            srcset(variants),
+            sizes
+        ));
+    }
+    picture.push_str(&img);
+    picture.push_str("</picture>");
+    picture
+}
+
+fn placeholder_style(placeholder: &Placeholder, mode: PlaceholderMode) -> String {
+    match (&placeholder.lqip, mode) {
+        (Some(lqip), PlaceholderMode::Lqip) => format!(
+            "background: {} url({}) center / cover no-repeat",
+            placeholder.color, lqip
+        ),
+        _ => format!("background-color: {}", placeholder.color),
+    }
+}
+
+static ATTR_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"\s([a-zA-Z_:][-a-zA-Z0-9_:.]*)(?:\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+)))?"#)
+        .unwrap()
+});
+
+/// Value of attribute `name` in the attribute list of an HTML tag
+fn attr_value(attrs: &str, name: &str) -> Option<String> {
+    ATTR_RE
+        .captures_iter(attrs)
+        .find(|caps| caps[1].eq_ignore_ascii_case(name))
+        .map(|caps| {
+            caps.get(2)
+                .or_else(|| caps.get(3))
+                .or_else(|| caps.get(4))
+                .map(|m| m.as_str().to_string())
+                .unwrap_or_default()
+        })
+}
+
+/// Look up the processed image an `src` attribute on `page_source` refers to
+pub fn find_image<'a>(
+    images: &'a ImageManifest,
+    page_source: &str,
+    src: &str,
+) -> Option<&'a ProcessedImage> {
+    if src.is_empty() || src.starts_with("//") || src.contains("://") || src.starts_with("data:") {
+        return None;
+    }
+    let path = src.split(['?', '#']).next().unwrap_or("");
+    let source = resolve_source_path(page_source, path)?;
+    images.get(&source)
+}
+
+/// Result of a `resize_image` call from a template
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResizedImage {
+    pub url: String, // relative to the site root, e.g. "processed_images/1f2e3d4c5b6a7980.webp"
+    pub width: u32,
+    pub height: u32,
+    pub orig_width: u32,
+    pub orig_height: u32,
+    pub placeholder: Placeholder,
+    pub style: String, // inline style that shows the placeholder, for `<img style="...">`
+}
+
+impl ResizedImage {
+    fn new(file_name: String, resized: &DynamicImage, original: (u32, u32)) -> Result<Self> {
+        let placeholder = placeholder(resized)?;
+        Ok(ResizedImage {
+            url: format!("processed_images/{}", file_name),
+            width: resized.width(),
+            height: resized.height(),
+            orig_width: original.0,
+            orig_height: original.1,
+            style: placeholder_style(&placeholder, PlaceholderMode::Lqip),
+            placeholder,
+        })
+    }
+}
+
+type ResizeResult = std::result::Result<ResizedImage, String>;
+
+/// Backs the `resize_image` Tera function: resizes images from the content
+/// directory on demand, once per distinct call, reusing files from earlier builds
+pub struct ImageResizer {
+    content_dir: PathBuf,
+    output_dir: PathBuf,
+    cache_dir: Option<PathBuf>,
+    quality: u8,
+    keep_copyright: bool,
+    results: Mutex<HashMap<String, Arc<OnceLock<ResizeResult>>>>,
+    used: Mutex<HashSet<String>>,
+}
+
+impl ImageResizer {
+    pub fn new(
+        content_dir: &Path,
+        output_dir: &Path,
+        cache_dir: Option<&Path>,
+        config: &ImagesConfig,
+    ) -> Self {
+        ImageResizer {
+            content_dir: content_dir.to_path_buf(),
+            output_dir: output_dir.to_path_buf(),
+            cache_dir: cache_dir.map(|dir| dir.join("resized")),
+            quality: config.quality.unwrap_or(DEFAULT_QUALITY).clamp(1, 100),
+            keep_copyright: config.keep_copyright.unwrap_or(false),
+            results: Mutex::new(HashMap::new()),
+            used: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// `resize_image(path, width, height, op, format, quality)` for templates.
+    /// `path` is relative to the content directory, e.g. `post.meta.cover`.
+    pub fn tera_function(self: &Arc<Self>) -> impl tera::Function + use<> {
+        let resizer = Arc::clone(self);
+        move |args: &HashMap<String, Value>| -> tera::Result<Value> {
+            let string = |name: &str| match args.get(name) {
+                None | Some(Value::Null) => Ok(None),
+                Some(Value::String(value)) => Ok(Some(value.as_str())),
+                Some(_) => Err(tera::Error::msg(format!(
+                    "resize_image: `{}` must be a string",
+                    name
+                ))),
+            };
+            let number = |name: &str| match args.get(name) {
+                None | Some(Value::Null) => Ok(None),
+                Some(value) => value
+                    .as_u64()
+                    .filter(|n| *n > 0 && *n <= u32::MAX as u64)
+                    .map(|n| Some(n as u32))
+                    .ok_or_else(|| {
+                        tera::Error::msg(format!(
+                            "resize_image: `{}` must be a positive integer",
+                            name
+                        ))
+                    }),
+            };
+
+            let path = string("path")?
+                .ok_or_else(|| tera::Error::msg("resize_image: `path` is required"))?;
+            let request = ResizeRequest {
+                width: number("width")?,
+                height: number("height")?,
+                op: string("op")?.map(str::to_lowercase),
+                format: string("format")?.map(str::to_lowercase),
+                quality: number("quality")?.map(|q| q.min(100) as u8),
+            };
+            let image = resizer
+                .resize(path, &request)
+                .map_err(|e| tera::Error::msg(format!("resize_image(path=\"{}\"): {}", path, e)))?;
+            Ok(tera::to_value(image)?)
+        }
+    }
+
+    fn resize(&self, path: &str, request: &ResizeRequest) -> ResizeResult {
+        let memo_key = format!("{}|{:?}", path, request);
+        let cell = {
+            let mut results = self.results.lock().unwrap();
+            Arc::clone(results.entry(memo_key).or_default())
+        };
+        cell.get_or_init(|| {
+            self.resize_uncached(path, request)
+                .map_err(|e| format!("{:#}", e))
+        })
+        .clone()
+    }
+
+    fn resize_uncached(&self, path: &str, request: &ResizeRequest) -> Result<ResizedImage> {
+        let Some(source) = resolve_source_path("", path.trim()) else {
+            bail!("path points outside the content directory");
+        };
+        let source_path = self.content_dir.join(&source);
+        let bytes =
+            fs::read(&source_path).with_context(|| format!("cannot read content/{}", source))?;
+
+        let op = match (request.op.as_deref(), request.width, request.height) {
+            (Some(op), _, _) => op,
+            (None, Some(_), Some(_)) => "fill",
+            (None, Some(_), None) => "fit_width",
+            (None, None, Some(_)) => "fit_height",
+            (None, None, None) => bail!("`width` or `height` is required"),
+        };
+        let quality = request.quality.unwrap_or(self.quality);
+        let format = request.format.as_deref().unwrap_or("webp");
+        let (ext, format) = match format {
+            "webp" => ("webp", OutputFormat::WebP),
+            "avif" => ("avif", OutputFormat::Avif),
+            // `Original` encodes according to the file extension
+            "jpg" | "jpeg" => ("jpg", OutputFormat::Original),
+            "png" => ("png", OutputFormat::Original),
+            other => bail!(
+                "unknown format `{}` (expected webp, avif, jpg or png)",
+                other
+            ),
+        };
+
+        let mut hasher = Sha256::new();
+        hasher.update(&bytes);
+        hasher.update(format!(
+            "v2:{}:{:?}:{:?}:{}:{}:{}",
+            op, request.width, request.height, ext, quality, self.keep_copyright
+        ));
+        let hash = format!("{:x}", hasher.finalize());
+        let file_name = format!("{}.{}", &hash[..16], ext);
+        let output_path = self.output_dir.join("processed_images").join(&file_name);
+        fs::create_dir_all(self.output_dir.join("processed_images"))?;
+        self.used.lock().unwrap().insert(file_name.clone());
+
+        let metadata = read_metadata(&bytes);
+        let (orig_width, orig_height) = match ImageReader::new(Cursor::new(&bytes))
+            .with_guessed_format()?
+            .into_dimensions()?
+        {
+            // Orientations 5-8 rotate by 90 degrees
+            (width, height) if metadata.orientation >= 5 => (height, width),
+            dimensions => dimensions,
+        };
+        let cached = self.cache_dir.as_ref().map(|dir| dir.join(&file_name));
+        // The result is cached next to the file, so a hit never decodes it
+        // (which the image crate cannot do for AVIF)
+        if let Some(cached) = &cached
+            && let Some(resized) = restore_resized(cached, &output_path)
+        {
+            return Ok(resized);
+        }
+
+        let img = decode_oriented(&bytes)?;
+        let resized = match (op, request.width, request.height) {
+            ("fill", Some(w), Some(h)) => img.resize_to_fill(w, h, Lanczos3),
+            ("scale", Some(w), Some(h)) => img.resize_exact(w, h, Lanczos3),
+            // Fitting never enlarges the image
+            ("fit", Some(w), Some(h)) if w < orig_width || h < orig_height => {
+                img.resize(w, h, Lanczos3)
+            }
+            ("fit_width", Some(w), _) if w < orig_width => {
+                img.resize(w, scaled_height(orig_width, orig_height, w), Lanczos3)
+            }
+            ("fit_height", _, Some(h)) if h < orig_height => {
+                img.resize(scaled_height(orig_height, orig_width, h), h, Lanczos3)
+            }
+            ("fit", Some(_), Some(_)) | ("fit_width", Some(_), _) | ("fit_height", _, Some(_)) => {
+                img
+            }
+            ("fill" | "scale" | "fit", _, _) => {
+                bail!("op `{}` needs both `width` and `height`", op)
+            }
+            ("fit_width", _, _) => bail!("op `fit_width` needs `width`"),
+            ("fit_height", _, _) => bail!("op `fit_height` needs `height`"),
+            (other, _, _) => bail!(
+                "unknown op `{}` (expected fill, fit, fit_width, fit_height or scale)",
+                other
+            ),
+        };
+
+        write_variant(&resized, format, &output_path, quality)?;
+        if self.keep_copyright
+            && let Some(copyright) = &metadata.copyright
+        {
+            embed_exif(&output_path, copyright)?;
+        }
+        let resized = ResizedImage::new(file_name, &resized, (orig_width, orig_height))?;
+        if let Some(cached) = &cached {
+            fs::create_dir_all(cached.parent().unwrap())?;
+            fs::copy(&output_path, cached)?;
+            // Written last so that an interrupted build leaves a file that is ignored
+            fs::write(sidecar_path(cached), serde_json::to_string(&resized)?)?;
+        }
+        Ok(resized)
+    }
+
+    /// Delete cached resized images that no template asked for in this build
+    pub fn remove_stale_cache(&self) -> Result<()> {
+        let Some(cache_dir) = &self.cache_dir else {
+            return Ok(());
+        };
+        if !cache_dir.exists() {
+            return Ok(());
+        }
+        let used = self.used.lock().unwrap();
+        for entry in fs::read_dir(cache_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !used.contains(name.strip_suffix(".json").unwrap_or(&name)) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ResizeRequest {
+    width: Option<u32>,
+    height: Option<u32>,
+    op: Option<String>,
+    format: Option<String>,
+    quality: Option<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_metadata::{test_exif_tags, test_phone_photo};
+    use crate::utils::test_dir;
+    use exif::Tag;
+
+    fn config(toml: &str) -> ImagesConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    /// A `width`x`height` gradient, encoded according to the extension of `path`
+    fn write_image(path: &Path, width: u32, height: u32) -> Vec<u8> {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x * 255 / width) as u8, (y * 255 / height) as u8, 128])
+        })
+        .save(path)
+        .unwrap();
+        fs::read(path).unwrap()
+    }
+
+    fn dimensions(path: &Path) -> (u32, u32) {
+        image::image_dimensions(path).unwrap()
+    }
+
+    /// A manifest with `assets/photo.jpg`, converted to 960x640 WebP (and AVIF)
+    /// files with 480w copies
+    fn manifest(avif: bool, placeholder: PlaceholderMode) -> ImageManifest {
+        let variant = |path: &str, width| ImageVariant {
+            path: path.to_string(),
+            mime: format!("image/{}", path.rsplit('.').next().unwrap()),
+            width,
+            height: width * 2 / 3,
+        };
+        let formats: &[&str] = if avif { &["avif", "webp"] } else { &["webp"] };
+        let image = ProcessedImage {
+            src: "assets/photo.webp".to_string(),
+            width: 960,
+            height: 640,
+            variants: formats
+                .iter()
+                .flat_map(|ext| {
+                    [
+                        variant(&format!("assets/photo-480w.{}", ext), 480),
+                        variant(&format!("assets/photo.{}", ext), 960),
+                    ]
+                })
+                .collect(),
+            placeholder: Placeholder {
+                color: "#112233".to_string(),
+                lqip: Some("data:image/webp;base64,AAAA".to_string()),
+            },
+        };
+        ImageManifest {
+            images: HashMap::from([("assets/photo.jpg".to_string(), image)]),
+            sizes: DEFAULT_SIZES.to_string(),
+            placeholder,
+        }
+    }
+
+    #[test]
+    fn image_settings_are_validated() {
+        for (toml, message) in [
+            (
+                "quality = 0",
+                "[images] quality must be between 1 and 100, got 0",
+            ),
+            ("max_width = 0", "[images] max_width must be greater than 0"),
+            (
+                "formats = [\"gif\"]",
+                "Unknown image format `gif` in [images] formats (expected webp, avif or original)",
+            ),
+            ("formats = []", "[images] formats cannot be empty"),
+            (
+                "placeholder = \"blur\"",
+                "Unknown [images] placeholder `blur` (expected lqip, color or none)",
+            ),
+            ("exclude = [\"[\"]", "Invalid glob `[` in [images]"),
+        ] {
+            let err = Settings::from_config(&config(toml)).err().unwrap();
+            assert_eq!(err.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn images_follow_the_configured_settings() {
+        let dir = test_dir("image-settings");
+        let images = dir.join("assets/images");
+        let source = write_image(&images.join("photo.jpg"), 100, 50);
+        let raw = write_image(&dir.join("assets/raw/untouched.jpg"), 100, 50);
+        let settings = "max_width = 64\nwidths = [32]\nformats = [\"original\", \"webp\"]\nkeep_original = true\nexclude = [\"assets/raw/**\"]";
+
+        let manifest = process_images(&dir, &config(settings), None).unwrap();
+
+        // The kept original is too wide, so a scaled copy stands in for it
+        assert_eq!(fs::read(images.join("photo.jpg")).unwrap(), source);
+        assert_eq!(dimensions(&images.join("photo-64w.jpg")), (64, 32));
+        assert_eq!(dimensions(&images.join("photo-32w.jpg")), (32, 16));
+        assert_eq!(dimensions(&images.join("photo.webp")), (64, 32));
+        assert_eq!(dimensions(&images.join("photo-32w.webp")), (32, 16));
+        assert_eq!(fs::read(dir.join("assets/raw/untouched.jpg")).unwrap(), raw);
+        assert!(!dir.join("assets/raw/untouched.webp").exists());
+        assert!(manifest.get("assets/raw/untouched.jpg").is_none());
+
+        let image = manifest.get("assets/images/photo.jpg").unwrap();
+        assert_eq!(
+            (image.src.as_str(), image.width),
+            ("assets/images/photo.webp", 64)
+        );
+        let variants: Vec<(&str, &str)> = image
+            .variants
+            .iter()
+            .map(|v| (v.path.as_str(), v.mime.as_str()))
+            .collect();
+        assert_eq!(
+            variants,
+            [
+                ("assets/images/photo-32w.jpg", "image/jpeg"),
+                ("assets/images/photo-64w.jpg", "image/jpeg"),
+                ("assets/images/photo-32w.webp", "image/webp"),
+                ("assets/images/photo.webp", "image/webp"),
+            ]
+        );
+
+        // Once the preferred format comes last, the scaled original is the fallback
+        fs::remove_dir_all(&dir).unwrap();
+        write_image(&images.join("photo.jpg"), 100, 50);
+        let manifest = process_images(
+            &dir,
+            &config(&settings.replace("\"original\", \"webp\"", "\"webp\", \"original\"")),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            manifest.get("assets/images/photo.jpg").unwrap().src,
+            "assets/images/photo-64w.jpg"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sources_are_removed_unless_kept() {
+        let dir = test_dir("image-sources");
+        let images = dir.join("assets/images");
+        write_image(&images.join("small.png"), 40, 20);
+
+        let manifest = process_images(&dir, &ImagesConfig::default(), None).unwrap();
+
+        assert!(!images.join("small.png").exists());
+        assert_eq!(dimensions(&images.join("small.webp")), (40, 20));
+        let image = manifest.get("assets/images/small.png").unwrap();
+        // Default widths are all wider than the image
+        assert_eq!(image.variants.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn images_whose_outputs_clash_are_not_converted() {
+        let dir = test_dir("image-clash");
+        let images = dir.join("assets/images");
+        write_image(&images.join("photo.jpg"), 40, 20);
+        write_image(&images.join("photo.png"), 40, 20);
+
+        let err = process_images(&dir, &ImagesConfig::default(), None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Images assets/images/photo.jpg and assets/images/photo.png would both be converted to assets/images/photo.webp; rename one of them"
+        );
+
+        // A file already in place, such as a WebP committed next to its source, is kept
+        fs::remove_file(images.join("photo.png")).unwrap();
+        fs::write(images.join("photo.webp"), "committed webp").unwrap();
+        let manifest = process_images(&dir, &ImagesConfig::default(), None).unwrap();
+        assert_eq!(
+            fs::read_to_string(images.join("photo.webp")).unwrap(),
+            "committed webp"
+        );
+        assert!(images.join("photo.jpg").exists());
+        assert!(manifest.get("assets/images/photo.jpg").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn animated_gifs_are_copied_as_they_are() {
+        let gif = |frames: &[u8]| {
+            let mut gif = Vec::new();
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut gif);
+            for &shade in frames {
+                let pixels =
+                    image::RgbaImage::from_pixel(8, 8, image::Rgba([shade, shade, shade, 255]));
+                encoder.encode_frame(image::Frame::new(pixels)).unwrap();
+            }
+            drop(encoder);
+            gif
+        };
+        let dir = test_dir("image-gifs");
+        let images = dir.join("assets/images");
+        fs::create_dir_all(&images).unwrap();
+        let animated = gif(&[0, 255]);
+        fs::write(images.join("spinner.gif"), &animated).unwrap();
+        fs::write(images.join("still.gif"), gif(&[128])).unwrap();
+
+        let manifest = process_images(&dir, &ImagesConfig::default(), None).unwrap();
+
+        assert_eq!(fs::read(images.join("spinner.gif")).unwrap(), animated);
+        assert!(!images.join("spinner.webp").exists());
+        assert!(manifest.get("assets/images/spinner.gif").is_none());
+        assert!(images.join("still.webp").exists());
+        assert!(manifest.get("assets/images/still.gif").is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn images_get_srcset_dimensions_and_lazy_loading() {
+        let images = manifest(false, PlaceholderMode::None);
+        let rewrite = |html: &str, page: &str| rewrite_image_sources(html, Some(&images), page);
+
+        assert_eq!(
+            rewrite(
+                "<img src=\"../assets/photo.jpg\" alt=\"A\">",
+                "blog/post.md"
+            ),
+            "<img src=\"../assets/photo.webp\" alt=\"A\" srcset=\"../assets/photo-480w.webp 480w, ../assets/photo.webp 960w\" sizes=\"(min-width: 42rem) 42rem, 100vw\" width=\"960\" height=\"640\" loading=\"lazy\" decoding=\"async\">"
+        );
+        // A declared width sets `sizes` and the height that keeps the aspect ratio
+        assert_eq!(
+            rewrite(
+                "<img src=\"assets/photo.jpg?v=1\" width=\"320\" loading=\"eager\" />",
+                "index.md"
+            ),
+            "<img src=\"assets/photo.webp?v=1\" width=\"320\" loading=\"eager\" srcset=\"assets/photo-480w.webp 480w, assets/photo.webp 960w\" sizes=\"320px\" height=\"213\" decoding=\"async\" />"
+        );
+        // Authors who wrote their own `srcset` keep full control
+        assert_eq!(
+            rewrite(
+                "<img src=\"assets/photo.jpg\" srcset=\"a.jpg 1x\">",
+                "index.md"
+            ),
+            "<img src=\"assets/photo.webp\" srcset=\"a.jpg 1x\">"
+        );
+        for untouched in [
+            "<img src=\"assets/other.jpg\">",
+            "<img src=\"https://example.com/assets/photo.jpg\">",
+            "<img alt=\"no source\">",
+        ] {
+            assert_eq!(rewrite(untouched, "index.md"), untouched);
+        }
+        assert_eq!(
+            rewrite_image_sources("<img src=\"assets/photo.jpg\">", None, "index.md"),
+            "<img src=\"assets/photo.jpg\">"
+        );
+    }
+
+    #[test]
+    fn images_in_several_formats_become_pictures() {
+        let images = manifest(true, PlaceholderMode::None);
+        assert_eq!(
+            rewrite_image_sources(
+                "<img src=\"assets/photo.jpg\" width=\"320\">",
+                Some(&images),
+                "index.md"
+            ),
+            "<picture><source type=\"image/avif\" srcset=\"assets/photo-480w.avif 480w, assets/photo.avif 960w\" sizes=\"320px\"><img src=\"assets/photo.webp\" width=\"320\" srcset=\"assets/photo-480w.webp 480w, assets/photo.webp 960w\" sizes=\"320px\" height=\"213\" loading=\"lazy\" decoding=\"async\"></picture>"
+        );
+    }
+
+    #[test]
+    fn cached_images_are_restored_until_settings_change() {
+        let dir = test_dir("image-cache");
+        let output = dir.join("output");
+        let cache = dir.join("cache");
+        let build = |settings: &str| {
+            let _ = fs::remove_dir_all(&output);
+            write_image(&output.join("assets/photo.jpg"), 100, 50);
+            process_images(&output, &config(settings), Some(&cache)).unwrap()
+        };
+        let entries = || {
+            fs::read_dir(cache.join("images"))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect::<Vec<_>>()
+        };
+
+        let first = build("widths = [32]");
+        let first_entries = entries();
+        assert_eq!(first_entries.len(), 1);
+        let webp = fs::read(output.join("assets/photo.webp")).unwrap();
+
+        let second = build("widths = [32]");
+        assert_eq!(entries(), first_entries);
+        assert_eq!(fs::read(output.join("assets/photo.webp")).unwrap(), webp);
+        assert_eq!(dimensions(&output.join("assets/photo-32w.webp")), (32, 16));
+        assert!(!output.join("assets/photo.jpg").exists());
+        assert_eq!(
+            serde_json::to_string(second.get("assets/photo.jpg").unwrap()).unwrap(),
+            serde_json::to_string(first.get("assets/photo.jpg").unwrap()).unwrap()
+        );
+
+        // Other settings make another entry, and the unused one is removed
+        build("widths = [32]\nquality = 60");
+        let third_entries = entries();
+        assert_eq!(third_entries.len(), 1);
+        assert_ne!(third_entries, first_entries);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn templates_resize_images_from_the_content_directory() {
+        let dir = test_dir("resize-image");
+        let content = dir.join("content");
+        let output = dir.join("output");
+        write_image(&content.join("assets/cover.jpg"), 200, 100);
+        let resizer = ImageResizer::new(&content, &output, None, &ImagesConfig::default());
+        let resize = |width, height, op: Option<&str>| {
+            let request = ResizeRequest {
+                width,
+                height,
+                op: op.map(str::to_string),
+                format: None,
+                quality: None,
+            };
+            resizer.resize("assets/cover.jpg", &request).unwrap()
+        };
+
+        for (width, height, op, expected) in [
+            (Some(80), Some(80), None, (80, 80)),
+            (Some(80), Some(80), Some("fit"), (80, 40)),
+            (Some(80), Some(80), Some("scale"), (80, 80)),
+            (Some(50), None, None, (50, 25)),
+            (None, Some(20), None, (40, 20)),
+            // Fitting never enlarges the image
+            (Some(400), None, None, (200, 100)),
+        ] {
+            let resized = resize(width, height, op);
+            assert_eq!((resized.width, resized.height), expected, "{:?}", op);
+            assert_eq!((resized.orig_width, resized.orig_height), (200, 100));
+            assert_eq!(dimensions(&output.join(&resized.url)), expected);
+            assert!(
+                resized.url.starts_with("processed_images/"),
+                "{}",
+                resized.url
+            );
+            assert!(resized.url.ends_with(".webp"), "{}", resized.url);
+        }
+        // The implied op names the same file
+        assert_eq!(
+            resize(Some(50), None, None).url,
+            resize(Some(50), None, Some("fit_width")).url
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resize_image_reports_bad_arguments() {
+        let dir = test_dir("resize-image-errors");
+        let content = dir.join("content");
+        write_image(&content.join("assets/cover.jpg"), 20, 10);
+        let resizer = Arc::new(ImageResizer::new(
+            &content,
+            &dir.join("output"),
+            None,
+            &ImagesConfig::default(),
+        ));
+        let function = resizer.tera_function();
+        let call = |args: serde_json::Value| {
+            let args: HashMap<String, Value> = serde_json::from_value(args).unwrap();
+            tera::Function::call(&function, &args)
+                .unwrap_err()
+                .to_string()
+        };
+
+        for (args, message) in [
+            (
+                serde_json::json!({ "width": 10 }),
+                "resize_image: `path` is required",
+            ),
+            (
+                serde_json::json!({ "path": "assets/cover.jpg", "width": "10" }),
+                "resize_image: `width` must be a positive integer",
+            ),
+            (
+                serde_json::json!({ "path": "assets/cover.jpg" }),
+                "resize_image(path=\"assets/cover.jpg\"): `width` or `height` is required",
+            ),
+            (
+                serde_json::json!({ "path": "assets/cover.jpg", "width": 10, "op": "fill" }),
+                "resize_image(path=\"assets/cover.jpg\"): op `fill` needs both `width` and `height`",
+            ),
+            (
+                serde_json::json!({ "path": "assets/cover.jpg", "width": 10, "op": "crop" }),
+                "resize_image(path=\"assets/cover.jpg\"): unknown op `crop` (expected fill, fit, fit_width, fit_height or scale)",
+            ),
+            (
+                serde_json::json!({ "path": "assets/cover.jpg", "width": 10, "format": "gif" }),
+                "resize_image(path=\"assets/cover.jpg\"): unknown format `gif` (expected webp, avif, jpg or png)",
+            ),
+            (
+                serde_json::json!({ "path": "../cover.jpg", "width": 10 }),
+                "resize_image(path=\"../cover.jpg\"): path points outside the content directory",
+            ),
+            (
+                serde_json::json!({ "path": "assets/missing.jpg", "width": 10 }),
+                "resize_image(path=\"assets/missing.jpg\"): cannot read content/assets/missing.jpg",
+            ),
+        ] {
+            let err = call(args);
+            assert!(err.starts_with(message), "{}", err);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn images_show_a_placeholder_while_loading() {
+        let rewrite = |placeholder, html: &str| {
+            rewrite_image_sources(html, Some(&manifest(false, placeholder)), "index.md")
+        };
+        assert!(
+            rewrite(PlaceholderMode::Lqip, "<img src=\"assets/photo.jpg\">").ends_with(
+                " decoding=\"async\" style=\"background: #112233 url(data:image/webp;base64,AAAA) center / cover no-repeat\" data-dominant-color=\"#112233\">"
+            )
+        );
+        assert!(
+            rewrite(PlaceholderMode::Color, "<img src=\"assets/photo.jpg\">").ends_with(
+                " decoding=\"async\" style=\"background-color: #112233\" data-dominant-color=\"#112233\">"
+            )
+        );
+        // An author's style is left alone
+        assert!(
+            !rewrite(
+                PlaceholderMode::Lqip,
+                "<img src=\"assets/photo.jpg\" style=\"border: 0\">"
+            )
+            .contains("data-dominant-color")
+        );
+    }
+
+    #[test]
+    fn placeholders_are_the_average_color_and_a_tiny_preview() {
+        let opaque = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            64,
+            32,
+            image::Rgb([200, 100, 50]),
+        ));
+        let preview = placeholder(&opaque).unwrap();
+        assert_eq!(preview.color, "#c86432");
+        let lqip = preview.lqip.unwrap();
+        let preview = BASE64
+            .decode(lqip.strip_prefix("data:image/webp;base64,").unwrap())
+            .unwrap();
+        assert_eq!(
+            image::load_from_memory(&preview).unwrap().dimensions(),
+            (16, 8)
+        );
+
+        // A preview would hide what is behind transparent images
+        let transparent = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            8,
+            8,
+            image::Rgba([0, 0, 0, 0]),
+        ));
+        assert!(placeholder(&transparent).unwrap().lqip.is_none());
+
+        let resized = ResizedImage::new("cover.webp".to_string(), &opaque, (128, 64)).unwrap();
+        assert!(
+            resized
+                .style
+                .starts_with("background: #c86432 url(data:image/webp;base64,"),
+            "{}",
+            resized.style
+        );
+    }
+
+    #[test]
+    fn photos_are_upright_and_lose_their_metadata() {
+        let dir = test_dir("image-metadata");
+        let cache = dir.join("cache");
+        let output = dir.join("output");
+        let photo = test_phone_photo();
+        let settings = config("keep_copyright = true\nexclude = [\"assets/raw/**\"]");
+
+        for _ in 0..2 {
+            let _ = fs::remove_dir_all(&output);
+            fs::create_dir_all(output.join("assets/raw")).unwrap();
+            fs::write(output.join("assets/phone.jpg"), &photo).unwrap();
+            fs::write(output.join("assets/raw/phone.jpg"), &photo).unwrap();
+            process_images(&output, &settings, Some(&cache)).unwrap();
+
+            // Encoded variants are upright
+            let webp = output.join("assets/phone.webp");
+            assert_eq!(dimensions(&webp), (20, 40));
+            assert_eq!(
+                test_exif_tags(&fs::read(&webp).unwrap()),
+                [Tag::Copyright.number()]
+            );
+            // Excluded files keep their pixels, so they keep the orientation too
+            let raw = fs::read(output.join("assets/raw/phone.jpg")).unwrap();
+            assert_eq!(dimensions(&output.join("assets/raw/phone.jpg")), (40, 20));
+            assert_eq!(
+                test_exif_tags(&raw),
+                [Tag::Orientation.number(), Tag::Copyright.number()]
+            );
+            assert!(!read_metadata(&raw).has_gps);
+        }
+
+        // A cached image still reports the location of its source
+        fs::write(output.join("assets/phone.jpg"), &photo).unwrap();
+        let processed = process_cached(
+            &output.join("assets/phone.jpg"),
+            "assets/phone.jpg",
+            &Settings::from_config(&settings).unwrap(),
+            Some(&cache.join("images")),
+        )
+        .unwrap();
+        assert!(processed.cached && processed.has_gps);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
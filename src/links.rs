@@ -0,0 +1,629 @@
+use crate::images::{ImageManifest, rewrite_image_sources};
+use crate::utils::{BuildConfig, Page, root_path_for_url, slugify};
+use anyhow::{Result, bail};
+use regex::{Captures, Regex};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// How the build treats links that cannot be resolved, from `[build] broken_links`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BrokenLinks {
+    Warn,
+    Error,
+}
+
+impl BrokenLinks {
+    pub fn from_config(config: &BuildConfig) -> Result<Self> {
+        match config.broken_links.as_deref().unwrap_or("warn") {
+            "warn" => Ok(BrokenLinks::Warn),
+            "error" => Ok(BrokenLinks::Error),
+            other => bail!(
+                "Unknown [build] broken_links `{}` (expected warn or error)",
+                other
+            ),
+        }
+    }
+}
+
+/// Lookup table from content source paths (e.g. `blog/post.md`) to rendered pages,
+/// used to rewrite `.md` links and wikilinks into final URLs.
+pub struct LinkIndex {
+    pages: HashMap<String, IndexedPage>,
+    // Lowercased title, file stem or extension-less path -> source paths
+    names: HashMap<String, BTreeSet<String>>,
+    // Lowercased file name or path under `content/` -> asset paths under `content/`
+    assets: HashMap<String, BTreeSet<String>>,
+    images: Option<ImageManifest>,
+}
+
+struct IndexedPage {
+    url: String,
+    title: String,
+    anchors: HashSet<String>,
+}
+
+/// Result of rewriting the links of a single page
+struct Rewrite {
+    html: String,
+    targets: Vec<String>,
+    problems: Vec<String>,
+}
+
+impl LinkIndex {
+    pub fn new<'a>(
+        pages: impl IntoIterator<Item = &'a Page>,
+        content_dir: &Path,
+        images: Option<&ImageManifest>,
+    ) -> Self {
+        let mut indexed = HashMap::new();
+        let mut names: HashMap<String, BTreeSet<String>> = HashMap::new();
+        for page in pages {
+            let title = page_title(page);
+            let without_ext = page
+                .source_path
+                .rsplit_once('.')
+                .map(|(path, _)| path)
+                .unwrap_or(&page.source_path);
+            for key in [title.as_str(), page.slug.as_str(), without_ext] {
+                names
+                    .entry(key.trim().to_lowercase())
+                    .or_default()
+                    .insert(page.source_path.clone());
+            }
+            indexed.insert(
+                page.source_path.clone(),
+                IndexedPage {
+                    url: page.url.clone(),
+                    title,
+                    anchors: collect_ids(&page.content_html),
+                },
+            );
+        }
+
+        // Only content/assets is copied to the output, so only those files can be embedded
+        let mut assets: HashMap<String, BTreeSet<String>> = HashMap::new();
+        for entry in WalkDir::new(content_dir.join("assets"))
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+        {
+            let Ok(relative) = entry.path().strip_prefix(content_dir) else {
+                continue;
+            };
+            let relative = relative.to_string_lossy().replace('\\', "/");
+            let file_name = entry.file_name().to_string_lossy().to_lowercase();
+            assets
+                .entry(file_name)
+                .or_default()
+                .insert(relative.clone());
+            assets
+                .entry(relative.to_lowercase())
+                .or_default()
+                .insert(relative);
+        }
+
+        LinkIndex {
+            pages: indexed,
+            names,
+            assets,
+            images: images.cloned(),
+        }
+    }
+
+    /// Rewrite the links of every page. Returns a description of each link that
+    /// could not be resolved, and every `(source, target)` edge between pages.
+    pub fn resolve(&self, pages: &mut [&mut Page]) -> (Vec<String>, Vec<(String, String)>) {
+        let mut problems = Vec::new();
+        let mut edges = Vec::new();
+
+        for page in pages.iter_mut() {
+            let rewrite = self.rewrite(page);
+            page.content_html = rewrite.html;
+            problems.extend(
+                rewrite
+                    .problems
+                    .into_iter()
+                    .map(|problem| format!("content/{}: {}", page.source_path, problem)),
+            );
+            edges.extend(
+                rewrite
+                    .targets
+                    .into_iter()
+                    .map(|target| (page.source_path.clone(), target)),
+            );
+        }
+
+        (problems, edges)
+    }
+
+    fn rewrite(&self, page: &Page) -> Rewrite {
+        let root_path = root_path_for_url(&page.url);
+        let mut targets = Vec::new();
+        let mut problems = Vec::new();
+
+        let wikilink_re =
+            Regex::new(r#"<a class="wikilink" href="mdbear-wikilink:([^"]*)">"#).unwrap();
+        let html = wikilink_re
+            .replace_all(&page.content_html, |caps: &Captures| {
+                let raw = unescape_html(&caps[1]);
+                let (name, heading) = match raw.split_once('#') {
+                    Some((name, heading)) => (name.trim(), Some(heading.trim())),
+                    None => (raw.trim(), None),
+                };
+                let source_path = if name.is_empty() {
+                    Some(page.source_path.clone())
+                } else {
+                    self.find_page(name, &mut problems)
+                };
+                let Some(source_path) = source_path else {
+                    problems.push(format!("wikilink `[[{}]]` matches no page", raw));
+                    return "<a class=\"wikilink wikilink-missing\">".to_string();
+                };
+                let fragment = heading.map(slugify);
+                if let Some(fragment) = &fragment
+                    && !self.pages[&source_path].anchors.contains(fragment)
+                {
+                    problems.push(format!(
+                        "wikilink `[[{}]]` points to missing heading #{} in content/{}",
+                        raw, fragment, source_path
+                    ));
+                }
+                let url = &self.pages[&source_path].url;
+                let href = match fragment {
+                    Some(fragment) if name.is_empty() => format!("#{}", fragment),
+                    Some(fragment) => format!("{}/{}#{}", root_path, url, fragment),
+                    None => format!("{}/{}", root_path, url),
+                };
+                targets.push(source_path);
+                format!("<a class=\"wikilink\" href=\"{}\">", href)
+            })
+            .to_string();
+
+        let embed_re = Regex::new(
+            r#"<img class="wikilink-embed" src="mdbear-wikilink:([^"]*)" alt="([^"]*)"([^>]*)>"#,
+        )
+        .unwrap();
+        let html = embed_re
+            .replace_all(&html, |caps: &Captures| {
+                let name = unescape_html(&caps[1]);
+                if let Some(asset) = self.find_asset(&name, &mut problems) {
+                    let src = format!("{}/{}", root_path, asset);
+                    if !is_image(&asset) {
+                        return format!(
+                            "<a class=\"wikilink-embed\" href=\"{}\">{}</a>",
+                            src, &caps[2]
+                        );
+                    }
+                    let img = format!(
+                        "<img class=\"wikilink-embed\" src=\"{}\" alt=\"{}\"{}>",
+                        src, &caps[2], &caps[3]
+                    );
+                    return rewrite_image_sources(&img, self.images.as_ref(), &page.source_path);
+                }
+                // Embedding a note links to it instead of transcluding it
+                if let Some(source_path) = self.find_page(&name, &mut problems) {
+                    let target = &self.pages[&source_path];
+                    let link = format!(
+                        "<a class=\"wikilink-embed\" href=\"{}/{}\">{}</a>",
+                        root_path,
+                        target.url,
+                        escape_attr(&target.title)
+                    );
+                    targets.push(source_path);
+                    return link;
+                }
+                problems.push(format!(
+                    "embed `![[{}]]` matches no page or file in content/assets",
+                    name
+                ));
+                format!(
+                    "<span class=\"wikilink wikilink-missing\">{}</span>",
+                    &caps[2]
+                )
+            })
+            .to_string();
+
+        let href_re = Regex::new(r#"href="([^"]*)""#).unwrap();
+        let html = href_re
+            .replace_all(&html, |caps: &Captures| {
+                let href = &caps[1];
+                let (path, fragment) = match href.split_once('#') {
+                    Some((path, fragment)) => (path, Some(fragment)),
+                    None => (href, None),
+                };
+                if !is_markdown_link(path) {
+                    return caps[0].to_string();
+                }
+
+                let Some(target_source) = resolve_source_path(&page.source_path, path) else {
+                    problems.push(format!(
+                        "link `{}` points outside the content directory",
+                        href
+                    ));
+                    return caps[0].to_string();
+                };
+                let Some(target) = self.pages.get(&target_source) else {
+                    problems.push(format!(
+                        "link `{}` points to content/{}, which is not a rendered page",
+                        href, target_source
+                    ));
+                    return caps[0].to_string();
+                };
+
+                if let Some(fragment) = fragment
+                    && !fragment.is_empty()
+                    && !target.anchors.contains(&percent_decode(fragment))
+                {
+                    problems.push(format!(
+                        "link `{}` points to missing anchor #{} in content/{}",
+                        href, fragment, target_source
+                    ));
+                }
+                targets.push(target_source.clone());
+
+                match fragment {
+                    Some(fragment) => format!("href=\"{}/{}#{}\"", root_path, target.url, fragment),
+                    None => format!("href=\"{}/{}\"", root_path, target.url),
+                }
+            })
+            .to_string();
+
+        Rewrite {
+            html,
+            targets,
+            problems,
+        }
+    }
+
+    /// Find a page by title, file stem or path, as Obsidian does for `[[Page Name]]`
+    fn find_page(&self, name: &str, problems: &mut Vec<String>) -> Option<String> {
+        let key = name.trim().trim_end_matches(".md").to_lowercase();
+        let candidates = self.names.get(&key)?;
+        let first = candidates.first()?;
+        if candidates.len() > 1 {
+            problems.push(format!(
+                "wikilink `{}` is ambiguous ({}), using content/{}",
+                name,
+                candidates
+                    .iter()
+                    .map(|c| format!("content/{}", c))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                first
+            ));
+        }
+        Some(first.clone())
+    }
+
+    fn find_asset(&self, name: &str, problems: &mut Vec<String>) -> Option<String> {
+        let key = name.trim().trim_start_matches('/').to_lowercase();
+        let candidates = self.assets.get(&key)?;
+        let first = candidates.first()?;
+        if candidates.len() > 1 {
+            problems.push(format!(
+                "embed `{}` is ambiguous, using content/{}",
+                name, first
+            ));
+        }
+        Some(first.clone())
+    }
+}
+
+fn page_title(page: &Page) -> String {
+    page.meta.title.clone().unwrap_or_else(|| page.slug.clone())
+}
+
+fn is_image(path: &str) -> bool {
+    let lower = path.to_lowercase();
+    [".png", ".jpg", ".jpeg", ".gif", ".webp", ".svg", ".avif"]
+        .iter()
+        .any(|ext| lower.ends_with(ext))
+}
+
+fn is_markdown_link(path: &str) -> bool {
+    if path.starts_with("//") || path.contains("://") || path.starts_with("mailto:") {
+        return false;
+    }
+    let lower = path.to_lowercase();
+    lower.ends_with(".md") || lower.ends_with(".markdown")
+}
+
+/// Resolve `link` relative to the directory of `source_path`, both relative to the
+/// content directory. A leading `/` resolves from the content root.
+pub fn resolve_source_path(source_path: &str, link: &str) -> Option<String> {
+    let link = percent_decode(link);
+    let mut segments: Vec<&str> = if link.starts_with('/') {
+        Vec::new()
+    } else {
+        let mut dir: Vec<&str> = source_path.split('/').collect();
+        dir.pop();
+        dir
+    };
+
+    for segment in link.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            other => segments.push(other),
+        }
+    }
+
+    Some(segments.join("/"))
+}
+
+/// Collect every `id="..."` attribute in an HTML fragment.
+pub fn collect_ids(html: &str) -> HashSet<String> {
+    let id_re = Regex::new(r#"\sid="([^"]+)""#).unwrap();
+    id_re
+        .captures_iter(html)
+        .map(|caps| caps[1].to_string())
+        .collect()
+}
+
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(hex) = input.get(i + 1..i + 3)
+            && let Ok(byte) = u8::from_str_radix(hex, 16)
+        {
+            decoded.push(byte);
+            i += 3;
+            continue;
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn unescape_html(content: &str) -> String {
+    content
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn escape_attr(content: &str) -> String {
+    content
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{test_dir, test_page as page};
+    use std::fs;
+
+    /// Resolve the links of `pages`, with the assets of `content_dir`
+    fn resolve(pages: &mut [Page], content_dir: &Path) -> (Vec<String>, Vec<(String, String)>) {
+        let index = LinkIndex::new(pages.iter(), content_dir, None);
+        let mut pages: Vec<&mut Page> = pages.iter_mut().collect();
+        index.resolve(&mut pages)
+    }
+
+    #[test]
+    fn markdown_links_point_at_page_urls() {
+        let mut pages = [
+            page(
+                "index.md",
+                "index.html",
+                "Home",
+                "<h2 id=\"margin-notes\">Margin notes</h2>",
+            ),
+            page(
+                "about.md",
+                "about.html",
+                "About",
+                "<a href=\"blog/post.md#design-goals\">goals</a> <a href=\"https://example.com/a.md\">out</a>",
+            ),
+            page(
+                "blog/post.md",
+                "blog/post.html",
+                "Post",
+                "<h2 id=\"design-goals\">Design goals</h2><a href=\"../index.md#margin-notes\">notes</a> <a href=\"../index.md\">home</a>",
+            ),
+        ];
+        let (problems, edges) = resolve(&mut pages, Path::new("no-content"));
+
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(
+            pages[1].content_html,
+            "<a href=\"./blog/post.html#design-goals\">goals</a> <a href=\"https://example.com/a.md\">out</a>"
+        );
+        assert!(
+            pages[2]
+                .content_html
+                .ends_with("<a href=\"../index.html#margin-notes\">notes</a> <a href=\"../index.html\">home</a>"),
+            "{}",
+            pages[2].content_html
+        );
+        assert_eq!(
+            edges,
+            [
+                ("about.md".to_string(), "blog/post.md".to_string()),
+                ("blog/post.md".to_string(), "index.md".to_string()),
+                ("blog/post.md".to_string(), "index.md".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn broken_markdown_links_are_reported() {
+        let mut pages = [
+            page("index.md", "index.html", "Home", ""),
+            page(
+                "about.md",
+                "about.html",
+                "About",
+                "<a href=\"missing.md\">gone</a> <a href=\"index.md#nowhere\">bad anchor</a> <a href=\"../outside.md\">outside</a>",
+            ),
+        ];
+        let (problems, _) = resolve(&mut pages, Path::new("no-content"));
+
+        assert_eq!(
+            problems,
+            [
+                "content/about.md: link `missing.md` points to content/missing.md, which is not a rendered page",
+                "content/about.md: link `index.md#nowhere` points to missing anchor #nowhere in content/index.md",
+                "content/about.md: link `../outside.md` points outside the content directory",
+            ]
+        );
+        // Broken links are left as written
+        assert!(pages[1].content_html.contains("href=\"missing.md\""));
+    }
+
+    #[test]
+    fn broken_links_setting_is_warn_or_error() {
+        let config = |broken_links: Option<&str>| BuildConfig {
+            broken_links: broken_links.map(str::to_string),
+            ..Default::default()
+        };
+        assert_eq!(
+            BrokenLinks::from_config(&config(None)).unwrap(),
+            BrokenLinks::Warn
+        );
+        assert_eq!(
+            BrokenLinks::from_config(&config(Some("error"))).unwrap(),
+            BrokenLinks::Error
+        );
+        assert_eq!(
+            BrokenLinks::from_config(&config(Some("erorr")))
+                .unwrap_err()
+                .to_string(),
+            "Unknown [build] broken_links `erorr` (expected warn or error)"
+        );
+    }
+
+    #[test]
+    fn wikilinks_resolve_by_title_or_path() {
+        let wikilink = |target: &str| {
+            format!(
+                "<a class=\"wikilink\" href=\"mdbear-wikilink:{}\">{}</a>",
+                target, target
+            )
+        };
+        let about: String = [
+            "Home",
+            "blog/post#Design Goals",
+            "#Local",
+            "Nowhere",
+            "Home#Gone",
+            "Draft",
+        ]
+        .map(wikilink)
+        .concat();
+        let mut pages = [
+            page("index.md", "index.html", "Home", ""),
+            page(
+                "about.md",
+                "about.html",
+                "About",
+                &format!("<h2 id=\"local\">Local</h2>{}", about),
+            ),
+            page(
+                "blog/post.md",
+                "blog/post.html",
+                "Post",
+                "<h2 id=\"design-goals\">Design goals</h2>",
+            ),
+            page("drafts/a.md", "drafts/a.html", "Draft", ""),
+            page("drafts/b.md", "drafts/b.html", "Draft", ""),
+        ];
+        let (problems, edges) = resolve(&mut pages, Path::new("no-content"));
+
+        assert_eq!(
+            pages[1].content_html,
+            concat!(
+                "<h2 id=\"local\">Local</h2>",
+                "<a class=\"wikilink\" href=\"./index.html\">Home</a>",
+                "<a class=\"wikilink\" href=\"./blog/post.html#design-goals\">blog/post#Design Goals</a>",
+                "<a class=\"wikilink\" href=\"#local\">#Local</a>",
+                "<a class=\"wikilink wikilink-missing\">Nowhere</a>",
+                "<a class=\"wikilink\" href=\"./index.html#gone\">Home#Gone</a>",
+                "<a class=\"wikilink\" href=\"./drafts/a.html\">Draft</a>",
+            )
+        );
+        assert_eq!(
+            problems,
+            [
+                "content/about.md: wikilink `[[Nowhere]]` matches no page",
+                "content/about.md: wikilink `[[Home#Gone]]` points to missing heading #gone in content/index.md",
+                "content/about.md: wikilink `Draft` is ambiguous (content/drafts/a.md, content/drafts/b.md), using content/drafts/a.md",
+            ]
+        );
+        let targets: Vec<&str> = edges.iter().map(|(_, target)| target.as_str()).collect();
+        assert_eq!(
+            targets,
+            [
+                "index.md",
+                "blog/post.md",
+                "about.md",
+                "index.md",
+                "drafts/a.md"
+            ]
+        );
+    }
+
+    #[test]
+    fn embeds_show_assets_and_link_notes() {
+        let dir = test_dir("embeds");
+        fs::create_dir_all(dir.join("assets/docs")).unwrap();
+        fs::write(dir.join("assets/Photo.jpg"), "").unwrap();
+        fs::write(dir.join("assets/docs/notes.pdf"), "").unwrap();
+        let embed = |target: &str| {
+            format!(
+                "<img class=\"wikilink-embed\" src=\"mdbear-wikilink:{}\" alt=\"{}\" width=\"320\">",
+                target, target
+            )
+        };
+        let mut pages = [
+            page(
+                "blog/post.md",
+                "blog/post.html",
+                "Post",
+                &[
+                    "assets/photo.jpg",
+                    "notes.pdf",
+                    "About & more",
+                    "missing.png",
+                ]
+                .map(embed)
+                .concat(),
+            ),
+            page("about.md", "about.html", "About & more", ""),
+        ];
+        let (problems, edges) = resolve(&mut pages, &dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            pages[0].content_html,
+            concat!(
+                "<img class=\"wikilink-embed\" src=\"../assets/Photo.jpg\" alt=\"assets/photo.jpg\" width=\"320\">",
+                "<a class=\"wikilink-embed\" href=\"../assets/docs/notes.pdf\">notes.pdf</a>",
+                "<a class=\"wikilink-embed\" href=\"../about.html\">About &amp; more</a>",
+                "<span class=\"wikilink wikilink-missing\">missing.png</span>",
+            )
+        );
+        assert_eq!(
+            problems,
+            [
+                "content/blog/post.md: embed `![[missing.png]]` matches no page or file in content/assets"
+            ]
+        );
+        assert_eq!(
+            edges,
+            [("blog/post.md".to_string(), "about.md".to_string())]
+        );
+    }
+}
@@ -1,6 +1,21 @@
+mod assets;
 mod cli;
 mod cmd;
+mod csp;
+mod favicons;
+mod figures;
+mod fonts;
+mod graph;
+mod icons;
+mod image_metadata;
+mod images;
+mod links;
+mod minify;
+mod palettes;
+mod pwa;
+mod sass;
 pub mod utils;
+mod vendor;
 
 use anyhow::Result;
 use clap::Parser;
@@ -14,10 +29,27 @@ async fn main() -> Result<()> {
         Commands::Init { name } => {
             cmd::init::execute(&name)?;
         }
-        Commands::Build { config } => {
-            cmd::build::execute(&config)?;
+        Commands::Build {
+            config,
+            jobs,
+            minify,
+        } => {
+            cmd::build::configure_jobs(jobs)?;
+            let options = cmd::build::BuildOptions {
+                minify: minify.then_some(true),
+                ..Default::default()
+            };
+            cmd::build::execute(&config, &options)?;
         }
-        Commands::Serve { port, config } => {
+        Commands::Check { config, jobs } => {
+            cmd::build::configure_jobs(jobs)?;
+            cmd::check::execute(&config)?;
+        }
+        Commands::Clean { config, cache } => {
+            cmd::clean::execute(&config, cache)?;
+        }
+        Commands::Serve { port, config, jobs } => {
+            cmd::build::configure_jobs(jobs)?;
             cmd::serve::execute(port, &config).await?;
         }
         Commands::Sync { project } => {
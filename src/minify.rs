@@ -0,0 +1,177 @@
+use anyhow::{Context, Result, anyhow};
+use colored::Colorize;
+use lightningcss::stylesheet::{MinifyOptions, ParserOptions, PrinterOptions, StyleSheet};
+use rayon::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Minify the rendered `pages` with their inline `<style>` and `<script>`
+/// blocks, the theme's `stylesheets` and `search_index.json`, then report the
+/// savings. Whitespace in `<pre>` and `<textarea>` is kept, and files copied
+/// from `static/` are left as they are.
+pub fn minify_site(pages: &[PathBuf], stylesheets: &[PathBuf], output_dir: &Path) -> Result<()> {
+    let mut cfg = minify_html::Cfg::new();
+    cfg.minify_css = true;
+    cfg.minify_js = true;
+    // Stay valid HTML, which the SPA navigation and later passes parse
+    cfg.do_not_minify_doctype = true;
+    cfg.ensure_spec_compliant_unquoted_attribute_values = true;
+    cfg.keep_spaces_between_attributes = true;
+    cfg.keep_closing_tags = true;
+    cfg.keep_html_and_head_opening_tags = true;
+
+    let mut sizes = pages
+        .par_iter()
+        .map(|page| rewrite(page, |html| Ok(minify_html::minify(html.as_bytes(), &cfg))))
+        .collect::<Result<Vec<_>>>()?;
+
+    for stylesheet in stylesheets {
+        sizes.push(rewrite(stylesheet, |css| minify_css(css, stylesheet))?);
+    }
+
+    let search_index = output_dir.join("search_index.json");
+    if search_index.is_file() {
+        sizes.push(rewrite(&search_index, |json| {
+            let value: serde_json::Value = serde_json::from_str(json)?;
+            Ok(serde_json::to_vec(&value)?)
+        })?);
+    }
+
+    let before: usize = sizes.iter().map(|(before, _)| before).sum();
+    let after: usize = sizes.iter().map(|(_, after)| after).sum();
+    println!(
+        "{} {} {}",
+        "Minified".cyan(),
+        sizes.len().to_string().cyan(),
+        format!(
+            "file(s): {} KB -> {} KB ({}% smaller)",
+            before.div_ceil(1024),
+            after.div_ceil(1024),
+            before.saturating_sub(after) * 100 / before.max(1)
+        )
+        .cyan()
+    );
+    Ok(())
+}
+
+/// Replace `path` with `minify` applied to it, returning the sizes before and after
+fn rewrite(path: &Path, minify: impl Fn(&str) -> Result<Vec<u8>>) -> Result<(usize, usize)> {
+    let original =
+        fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    let minified =
+        minify(&original).with_context(|| format!("Failed to minify {}", path.display()))?;
+    fs::write(path, &minified)?;
+    Ok((original.len(), minified.len()))
+}
+
+fn minify_css(css: &str, path: &Path) -> Result<Vec<u8>> {
+    let filename = path.to_string_lossy().into_owned();
+    let mut stylesheet = StyleSheet::parse(
+        css,
+        ParserOptions {
+            filename,
+            ..ParserOptions::default()
+        },
+    )
+    .map_err(|err| anyhow!("{}", err))?;
+    stylesheet
+        .minify(MinifyOptions::default())
+        .map_err(|err| anyhow!("{}", err))?;
+    let output = stylesheet
+        .to_css(PrinterOptions {
+            minify: true,
+            ..PrinterOptions::default()
+        })
+        .map_err(|err| anyhow!("{}", err))?;
+    Ok(output.code.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    #[test]
+    fn pages_stylesheets_and_search_index_are_minified() {
+        let dir = test_dir("minify");
+        let page = dir.join("index.html");
+        fs::write(
+            &page,
+            concat!(
+                "<!DOCTYPE html>\n<html lang=\"en\">\n  <head>\n    <style>\n      body { color: #ff0000; }\n    </style>\n  </head>\n",
+                "  <body>\n    <p>Some   text</p>\n    <pre><code>keep\n    indented</code></pre>\n",
+                "    <textarea>  keep\n    these   spaces</textarea>\n",
+                "    <script>\n      const greeting = \"hi\";\n      console.log(greeting);\n    </script>\n  </body>\n</html>\n",
+            ),
+        )
+        .unwrap();
+        let stylesheet = dir.join("style.css");
+        fs::write(
+            &stylesheet,
+            "body {\n  margin: 0px;\n  color: #ff0000;\n}\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("search_index.json"),
+            "[\n  {\n    \"title\": \"Home\"\n  }\n]\n",
+        )
+        .unwrap();
+
+        minify_site(
+            std::slice::from_ref(&page),
+            std::slice::from_ref(&stylesheet),
+            &dir,
+        )
+        .unwrap();
+
+        let html = fs::read_to_string(&page).unwrap();
+        assert!(
+            html.starts_with("<!doctype html><html lang=en><head><style>body{color:red}</style>"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("<pre><code>keep\n    indented</code></pre>"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("<textarea>  keep\n    these   spaces</textarea>"),
+            "{}",
+            html
+        );
+        assert!(
+            html.ends_with(
+                "<script>const greeting=`hi`;console.log(greeting)</script></body></html>"
+            ),
+            "{}",
+            html
+        );
+        assert_eq!(
+            fs::read_to_string(&stylesheet).unwrap(),
+            "body{color:red;margin:0}"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("search_index.json")).unwrap(),
+            "[{\"title\":\"Home\"}]"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_stylesheets_fail_the_build() {
+        let dir = test_dir("minify-invalid");
+        let stylesheet = dir.join("style.css");
+        fs::write(
+            &stylesheet,
+            "@media (min-width: 42rem { body { color: red; } }\n@import \"late.css\";",
+        )
+        .unwrap();
+        let err = minify_site(&[], std::slice::from_ref(&stylesheet), &dir).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Failed to minify {}", stylesheet.display())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
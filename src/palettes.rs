@@ -0,0 +1,370 @@
+use crate::utils::{ColorSet, ThemeConfig};
+use anyhow::{Result, bail};
+
+/// The colors a scheme sets, with their CSS custom properties
+type Colors = [(&'static str, &'static str); 7];
+
+/// A built-in scheme: the colors used in light mode and in dark mode, and the
+/// mode it stands for when `[theme] mode` is not set
+struct Scheme {
+    name: &'static str,
+    light: Colors,
+    dark: Colors,
+    mode: Option<&'static str>,
+}
+
+const fn colors(
+    bg: &'static str,
+    paper: &'static str,
+    ink: &'static str,
+    muted: &'static str,
+    line: &'static str,
+    accent: &'static str,
+    accent_soft: &'static str,
+) -> Colors {
+    [
+        ("bg", bg),
+        ("paper", paper),
+        ("ink", ink),
+        ("muted", muted),
+        ("line", line),
+        ("accent", accent),
+        ("accent-soft", accent_soft),
+    ]
+}
+
+const MDBEAR_LIGHT: Colors = colors(
+    "#f6f4ef", "#fffdf8", "#262522", "#767069", "#ded8cf", "#8f4f36", "#ead7cc",
+);
+const MDBEAR_DARK: Colors = colors(
+    "#191917", "#20201d", "#eee9df", "#aaa297", "#393630", "#d99d7e", "#3a2b24",
+);
+const LATTE: Colors = colors(
+    "#e6e9ef", "#eff1f5", "#4c4f69", "#6c6f85", "#ccd0da", "#8839ef", "#dcd0f5",
+);
+const FRAPPE: Colors = colors(
+    "#292c3c", "#303446", "#c6d0f5", "#a5adce", "#414559", "#ca9ee6", "#463f5e",
+);
+const MACCHIATO: Colors = colors(
+    "#1e2030", "#24273a", "#cad3f5", "#a5adcb", "#363a4f", "#c6a0f6", "#3d3656",
+);
+const MOCHA: Colors = colors(
+    "#181825", "#1e1e2e", "#cdd6f4", "#a6adc8", "#313244", "#cba6f7", "#3a3150",
+);
+
+/// Built-in schemes. `catppuccin` pairs Latte (light) with Mocha (dark); a
+/// single Catppuccin flavor is used in both modes and fixes the mode to its own.
+const SCHEMES: [Scheme; 9] = [
+    Scheme {
+        name: "mdbear",
+        light: MDBEAR_LIGHT,
+        dark: MDBEAR_DARK,
+        mode: None,
+    },
+    Scheme {
+        name: "catppuccin",
+        light: LATTE,
+        dark: MOCHA,
+        mode: None,
+    },
+    Scheme {
+        name: "catppuccin-latte",
+        light: LATTE,
+        dark: LATTE,
+        mode: Some("light"),
+    },
+    Scheme {
+        name: "catppuccin-frappe",
+        light: FRAPPE,
+        dark: FRAPPE,
+        mode: Some("dark"),
+    },
+    Scheme {
+        name: "catppuccin-macchiato",
+        light: MACCHIATO,
+        dark: MACCHIATO,
+        mode: Some("dark"),
+    },
+    Scheme {
+        name: "catppuccin-mocha",
+        light: MOCHA,
+        dark: MOCHA,
+        mode: Some("dark"),
+    },
+    Scheme {
+        name: "solarized",
+        light: colors(
+            "#eee8d5", "#fdf6e3", "#586e75", "#93a1a1", "#e0dbc7", "#268bd2", "#d9e6ee",
+        ),
+        dark: colors(
+            "#002b36", "#073642", "#93a1a1", "#839496", "#0d4452", "#268bd2", "#0b3f55",
+        ),
+        mode: None,
+    },
+    Scheme {
+        name: "nord",
+        light: colors(
+            "#e5e9f0", "#eceff4", "#2e3440", "#4c566a", "#d8dee9", "#5e81ac", "#d3dfec",
+        ),
+        dark: colors(
+            "#2e3440", "#3b4252", "#eceff4", "#a3acbd", "#434c5e", "#88c0d0", "#3b4f5e",
+        ),
+        mode: None,
+    },
+    Scheme {
+        name: "gruvbox",
+        light: colors(
+            "#f2e5bc", "#fbf1c7", "#3c3836", "#7c6f64", "#d5c4a1", "#af3a03", "#f0d3b4",
+        ),
+        dark: colors(
+            "#1d2021", "#282828", "#ebdbb2", "#a89984", "#3c3836", "#fe8019", "#4a3424",
+        ),
+        mode: None,
+    },
+];
+
+/// The color scheme and light/dark mode chosen under `[theme]`
+pub struct ThemePalette {
+    mode: &'static str,
+    css: String,
+}
+
+impl ThemePalette {
+    /// Resolve `color_scheme` against the built-in schemes and the ones
+    /// defined under `[theme.palettes]`, which take precedence. Colors a
+    /// palette leaves out come from the `mdbear` scheme.
+    pub fn from_config(theme: &ThemeConfig) -> Result<Self> {
+        let mode = match theme.mode.as_deref() {
+            None => None,
+            Some("auto") => Some("auto"),
+            Some("light") => Some("light"),
+            Some("dark") => Some("dark"),
+            Some(other) => bail!(
+                "Unknown theme.mode '{}' (expected \"light\", \"dark\" or \"auto\")",
+                other
+            ),
+        };
+        let Some(name) = theme.color_scheme.as_deref() else {
+            return Ok(ThemePalette {
+                mode: mode.unwrap_or("auto"),
+                css: String::new(),
+            });
+        };
+
+        let (light, dark, mode) = if let Some(palette) = theme.palettes.get(name) {
+            (
+                custom_colors(name, "light", palette.light.as_ref(), MDBEAR_LIGHT)?,
+                custom_colors(name, "dark", palette.dark.as_ref(), MDBEAR_DARK)?,
+                mode,
+            )
+        } else if let Some(scheme) = SCHEMES.iter().find(|scheme| scheme.name == name) {
+            (
+                to_owned(scheme.light),
+                to_owned(scheme.dark),
+                mode.or(scheme.mode),
+            )
+        } else {
+            let mut known: Vec<&str> = SCHEMES.iter().map(|scheme| scheme.name).collect();
+            known.extend(theme.palettes.keys().map(String::as_str));
+            bail!(
+                "Unknown theme.color_scheme '{}' (available: {})",
+                name,
+                known.join(", ")
+            );
+        };
+
+        // Same selectors as style.css, so these win by coming after it
+        let css = format!(
+            ":root {{\n{}}}\nhtml[data-theme=\"dark\"] {{\n{}}}\n\
+             @media (prefers-color-scheme: dark) {{\n    :root:not([data-theme=\"light\"]) {{\n{}    }}\n}}\n",
+            declarations(&light, 64, "    "),
+            declarations(&dark, 62, "    "),
+            declarations(&dark, 62, "        "),
+        );
+        Ok(ThemePalette {
+            mode: mode.unwrap_or("auto"),
+            css,
+        })
+    }
+
+    /// `"auto"` follows the reader's system and toggle; `"light"` and
+    /// `"dark"` are fixed
+    pub fn mode(&self) -> &'static str {
+        self.mode
+    }
+
+    /// Custom properties overriding the theme's colors, empty when no
+    /// `color_scheme` is set
+    pub fn css(&self) -> &str {
+        &self.css
+    }
+}
+
+fn to_owned(colors: Colors) -> Vec<(&'static str, String)> {
+    colors
+        .iter()
+        .map(|(key, value)| (*key, value.to_string()))
+        .collect()
+}
+
+/// A `[theme.palettes.<name>.<variant>]` table over the `fallback` colors
+fn custom_colors(
+    name: &str,
+    variant: &str,
+    set: Option<&ColorSet>,
+    fallback: Colors,
+) -> Result<Vec<(&'static str, String)>> {
+    let mut colors = to_owned(fallback);
+    let Some(set) = set else {
+        return Ok(colors);
+    };
+    let values = [
+        &set.bg,
+        &set.paper,
+        &set.ink,
+        &set.muted,
+        &set.line,
+        &set.accent,
+        &set.accent_soft,
+    ];
+    for ((key, color), value) in colors.iter_mut().zip(values) {
+        let Some(value) = value else {
+            continue;
+        };
+        // The value lands in a <style> block, so it must stay one declaration
+        if value.trim().is_empty() || value.contains([';', '{', '}', '<', '>']) {
+            bail!(
+                "theme.palettes.{}.{}.{} is not a CSS color: '{}'",
+                name,
+                variant,
+                key.replace('-', "_"),
+                value
+            );
+        }
+        *color = value.trim().to_string();
+    }
+    Ok(colors)
+}
+
+/// The `--theme-*` properties, and the translucent `--rail` over the paper color
+fn declarations(colors: &[(&'static str, String)], rail_percent: u8, indent: &str) -> String {
+    let mut css: String = colors
+        .iter()
+        .map(|(key, value)| format!("{}--theme-{}: {};\n", indent, key, value))
+        .collect();
+    css.push_str(&format!(
+        "{}--rail: color-mix(in srgb, var(--theme-paper) {}%, transparent);\n",
+        indent, rail_percent
+    ));
+    css
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn palette(toml: &str) -> Result<ThemePalette> {
+        ThemePalette::from_config(&toml::from_str(toml).unwrap())
+    }
+
+    #[test]
+    fn without_a_scheme_only_the_mode_is_set() {
+        let default = palette("").unwrap();
+        assert_eq!(default.mode(), "auto");
+        assert_eq!(default.css(), "");
+        assert_eq!(palette("mode = \"dark\"").unwrap().mode(), "dark");
+    }
+
+    #[test]
+    fn schemes_set_both_modes_and_follow_the_system() {
+        let palette = palette("color_scheme = \"catppuccin\"").unwrap();
+        assert_eq!(palette.mode(), "auto");
+        let css = palette.css();
+        assert!(
+            css.starts_with(":root {\n    --theme-bg: #e6e9ef;\n"),
+            "{}",
+            css
+        );
+        assert!(
+            css.contains("html[data-theme=\"dark\"] {\n    --theme-bg: #181825;\n"),
+            "{}",
+            css
+        );
+        assert!(
+            css.contains(
+                "@media (prefers-color-scheme: dark) {\n    :root:not([data-theme=\"light\"]) {\n        --theme-bg: #181825;\n"
+            ),
+            "{}",
+            css
+        );
+        assert!(
+            css.contains("--rail: color-mix(in srgb, var(--theme-paper) 64%, transparent);"),
+            "{}",
+            css
+        );
+    }
+
+    #[test]
+    fn flavors_fix_the_mode_unless_it_is_set() {
+        let latte = palette("color_scheme = \"catppuccin-latte\"").unwrap();
+        assert_eq!(latte.mode(), "light");
+        assert!(
+            latte
+                .css()
+                .contains("html[data-theme=\"dark\"] {\n    --theme-bg: #e6e9ef;\n")
+        );
+        assert!(!latte.css().contains("#181825"));
+        let frappe = palette("color_scheme = \"catppuccin-frappe\"").unwrap();
+        assert_eq!(frappe.mode(), "dark");
+        assert!(
+            frappe
+                .css()
+                .starts_with(":root {\n    --theme-bg: #292c3c;\n")
+        );
+        let forced = palette("mode = \"auto\"\ncolor_scheme = \"catppuccin-frappe\"").unwrap();
+        assert_eq!(forced.mode(), "auto");
+    }
+
+    #[test]
+    fn custom_palettes_take_missing_colors_from_mdbear() {
+        let palette = palette(
+            "color_scheme = \"nord\"\n\n[palettes.nord.dark]\nbg = \" #0f1c24 \"\naccent_soft = \"#16323f\"",
+        )
+        .unwrap();
+        assert_eq!(palette.mode(), "auto");
+        let css = palette.css();
+        assert!(
+            css.starts_with(":root {\n    --theme-bg: #f6f4ef;\n"),
+            "{}",
+            css
+        );
+        assert!(
+            css.contains("html[data-theme=\"dark\"] {\n    --theme-bg: #0f1c24;\n    --theme-paper: #20201d;\n"),
+            "{}",
+            css
+        );
+        assert!(css.contains("--theme-accent-soft: #16323f;"), "{}", css);
+    }
+
+    #[test]
+    fn unknown_settings_and_colors_are_errors() {
+        let error = |toml: &str| palette(toml).err().unwrap().to_string();
+        assert_eq!(
+            error("mode = \"sepia\""),
+            "Unknown theme.mode 'sepia' (expected \"light\", \"dark\" or \"auto\")"
+        );
+        let err = error("color_scheme = \"dracula\"\n\n[palettes.harbor]");
+        assert!(
+            err.starts_with(
+                "Unknown theme.color_scheme 'dracula' (available: mdbear, catppuccin, "
+            ),
+            "{}",
+            err
+        );
+        assert!(err.ends_with(", gruvbox, harbor)"), "{}", err);
+        assert_eq!(
+            error("color_scheme = \"harbor\"\n\n[palettes.harbor.light]\naccent = \"red; }\""),
+            "theme.palettes.harbor.light.accent is not a CSS color: 'red; }'"
+        );
+    }
+}
@@ -0,0 +1,483 @@
+use crate::csp::{TAG_RE, attributes};
+use crate::favicons::IconLink;
+use crate::utils::{Config, Page};
+use anyhow::{Context, Result};
+use colored::Colorize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+pub const MANIFEST_FILE: &str = "manifest.webmanifest";
+pub const SERVICE_WORKER_FILE: &str = "sw.js";
+/// Emoji `site_icon`s are drawn into this SVG for the manifest
+const EMOJI_ICON_FILE: &str = "icon.svg";
+
+/// Root files of the app shell, precached when the build produced them
+const SHELL_FILES: [&str; 6] = [
+    "blog.html",
+    "search_index.json",
+    "favicon.ico",
+    "icon-192.png",
+    "icon-512.png",
+    "apple-touch-icon.png",
+];
+
+/// The service worker, with `__PRECACHE__` and `__VERSION__` filled in.
+/// Precached files are served from the cache; everything else, including
+/// scripts, styles, fonts and images from CDNs, goes to the network first and
+/// falls back to the copy saved on the last visit.
+const SERVICE_WORKER: &str = r#"// Generated by mdBear. Precaches the site shell and the newest posts.
+const PRECACHE = __PRECACHE__;
+const PREFIX = 'mdbear-precache-';
+const CACHE = PREFIX + '__VERSION__';
+const RUNTIME = 'mdbear-runtime';
+const SUBRESOURCES = ['script', 'style', 'font', 'image'];
+const REVISIONS = new URL('__mdbear-revisions', self.registration.scope).href;
+const absolute = (url) => new URL(url, self.registration.scope).href;
+
+// Browsers refuse redirected responses for navigations, e.g. from hosts
+// that drop `.html`, so keep only the body and headers
+async function cleaned(response) {
+    if (!response.redirected) return response;
+    return new Response(await response.blob(), { status: response.status, headers: response.headers });
+}
+
+self.addEventListener('install', (event) => {
+    event.waitUntil((async () => {
+        const cache = await caches.open(CACHE);
+        // Files whose revision did not change are copied from the previous version
+        const previousName = (await caches.keys()).find((name) => name.startsWith(PREFIX) && name !== CACHE);
+        const previous = previousName && await caches.open(previousName);
+        const revisions = previous && await previous.match(REVISIONS);
+        const known = revisions ? await revisions.json() : {};
+        await Promise.all(PRECACHE.map(async ({ url, revision }) => {
+            const href = absolute(url);
+            let response = known[href] === revision && await previous.match(href);
+            if (!response) {
+                response = await fetch(new Request(href, { cache: 'reload' }));
+                if (!response.ok) throw new Error(`Cannot precache ${url}: HTTP ${response.status}`);
+            }
+            await cache.put(href, await cleaned(response));
+        }));
+        const current = Object.fromEntries(PRECACHE.map(({ url, revision }) => [absolute(url), revision]));
+        await cache.put(REVISIONS, new Response(JSON.stringify(current)));
+        await self.skipWaiting();
+    })());
+});
+
+self.addEventListener('activate', (event) => {
+    event.waitUntil((async () => {
+        for (const name of await caches.keys()) {
+            if (name.startsWith(PREFIX) && name !== CACHE) await caches.delete(name);
+        }
+        await self.clients.claim();
+    })());
+});
+
+self.addEventListener('fetch', (event) => {
+    const request = event.request;
+    const url = new URL(request.url);
+    const local = url.origin === self.location.origin;
+    if (request.method !== 'GET') return;
+    // Other origins only for what pages load from CDNs, not e.g. embedded frames
+    if (!local && !SUBRESOURCES.includes(request.destination)) return;
+    event.respondWith((async () => {
+        const cache = await caches.open(CACHE);
+        const path = url.origin + url.pathname;
+        const keys = !local ? [] : path.endsWith('/') ? [path + 'index.html'] : [path, path + '.html'];
+        for (const key of keys) {
+            const cached = await cache.match(key);
+            if (cached) return cached;
+        }
+        try {
+            const response = await fetch(request);
+            // Files from CDNs loaded without CORS are opaque, with no status to check
+            if (response.ok || response.type === 'opaque') {
+                const runtime = await caches.open(RUNTIME);
+                await runtime.put(request, response.clone());
+            }
+            return response;
+        } catch (error) {
+            const saved = await caches.match(request, { cacheName: RUNTIME });
+            if (saved) return saved;
+            if (request.mode !== 'navigate') throw error;
+            return new Response(
+                '<!DOCTYPE html><meta charset="utf-8"><meta name="viewport" content="width=device-width"><title>Offline</title>'
+                + '<p>You are offline, and this page was not saved for offline reading.</p><p><a href="' + absolute('index.html') + '">Back to the home page</a></p>',
+                { status: 503, headers: { 'Content-Type': 'text/html; charset=utf-8' } },
+            );
+        }
+    })());
+});
+"#;
+
+/// Write `manifest.webmanifest` for `[pwa]`, listing the PNG icons generated
+/// from an image `site_icon` or an SVG of an emoji one. It takes the place
+/// of `site.webmanifest`, whose link is dropped from `icon_links`.
+pub fn write_manifest(
+    config: &Config,
+    icon_links: &mut Vec<IconLink>,
+    output_dir: &Path,
+) -> Result<()> {
+    icon_links.retain(|link| link.rel != "manifest");
+    let site_manifest = output_dir.join("site.webmanifest");
+    if site_manifest.is_file() {
+        fs::remove_file(site_manifest)?;
+    }
+
+    let mut icons: Vec<_> = icon_links
+        .iter()
+        .filter(|link| link.mime == Some("image/png"))
+        .map(|link| json!({ "src": link.href, "sizes": link.sizes, "type": "image/png" }))
+        .collect();
+    if icons.is_empty() {
+        let svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 100 100\"><text y=\".9em\" font-size=\"90\">{}</text></svg>",
+            config
+                .site_icon
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+        );
+        fs::write(output_dir.join(EMOJI_ICON_FILE), svg)?;
+        icons.push(json!({ "src": EMOJI_ICON_FILE, "sizes": "any", "type": "image/svg+xml" }));
+    }
+
+    let pwa = &config.pwa;
+    let mut manifest = json!({
+        "name": config.site_name,
+        "short_name": pwa.short_name.as_deref().unwrap_or(&config.site_name),
+        "lang": config.language,
+        "start_url": "./index.html",
+        "scope": "./",
+        "display": pwa.display.as_deref().unwrap_or("standalone"),
+        "icons": icons,
+    });
+    if let Some(description) = &config.site_description {
+        manifest["description"] = json!(description);
+    }
+    if let Some(color) = &pwa.theme_color {
+        manifest["theme_color"] = json!(color);
+    }
+    if let Some(color) = &pwa.background_color {
+        manifest["background_color"] = json!(color);
+    }
+    fs::write(
+        output_dir.join(MANIFEST_FILE),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+    Ok(())
+}
+
+/// Write `sw.js` precaching the final build output: the Markdown `pages`,
+/// the shell files, stylesheets, `fonts/` and `vendor/`, the newest
+/// `[pwa] recent_posts` of `posts` (which are sorted newest first), and the
+/// same-origin images, scripts and media those pages load. Each file is
+/// listed with a hash of its content, so a deploy only refetches what
+/// changed. Call it last, once nothing renames or rewrites the output.
+pub fn write_service_worker(
+    config: &Config,
+    pages: &[Page],
+    posts: &[Page],
+    output_dir: &Path,
+) -> Result<()> {
+    let mut urls: Vec<String> = pages.iter().map(|page| page.url.clone()).collect();
+    urls.extend(SHELL_FILES.iter().map(|file| file.to_string()));
+    urls.extend([MANIFEST_FILE.to_string(), EMOJI_ICON_FILE.to_string()]);
+    // Stylesheets under their fingerprinted names, and any copied from static/
+    for entry in fs::read_dir(output_dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.ends_with(".css") {
+            urls.push(name);
+        }
+    }
+    for dir in ["fonts", "vendor"] {
+        for entry in WalkDir::new(output_dir.join(dir))
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+        {
+            let relative = entry.path().strip_prefix(output_dir)?;
+            urls.push(relative.to_string_lossy().replace('\\', "/"));
+        }
+    }
+    let recent_posts = config.pwa.recent_posts.unwrap_or(10);
+    urls.extend(posts.iter().take(recent_posts).map(|post| post.url.clone()));
+    let mut assets = Vec::new();
+    for url in urls.iter().filter(|url| url.ends_with(".html")) {
+        if let Ok(html) = fs::read_to_string(output_dir.join(url)) {
+            assets.extend(page_assets(&html, url));
+        }
+    }
+    urls.extend(assets);
+
+    let mut precache = Vec::new();
+    let mut version = Sha256::new();
+    for url in urls {
+        if precache
+            .iter()
+            .any(|entry: &serde_json::Value| entry["url"] == url.as_str())
+        {
+            continue;
+        }
+        let path = output_dir.join(&url);
+        if !path.is_file() {
+            continue;
+        }
+        let bytes = fs::read(&path).with_context(|| format!("Cannot read {}", path.display()))?;
+        let revision = format!("{:x}", Sha256::digest(&bytes));
+        version.update(format!("{} {}\n", url, revision));
+        precache.push(json!({ "url": url, "revision": &revision[..16] }));
+    }
+    let version = format!("{:x}", version.finalize());
+
+    let script = SERVICE_WORKER
+        .replace("__VERSION__", &version[..16])
+        .replace("__PRECACHE__", &serde_json::to_string_pretty(&precache)?);
+    fs::write(output_dir.join(SERVICE_WORKER_FILE), script)?;
+    println!(
+        "  {} {} {}",
+        "Generated service worker:".green(),
+        SERVICE_WORKER_FILE.green(),
+        format!(
+            "(precaching {} file(s), {} recent post(s))",
+            precache.len(),
+            posts.len().min(recent_posts)
+        )
+        .green()
+    );
+    Ok(())
+}
+
+/// Files of the output that `html`, the page at `page_url`, loads: images
+/// with their `srcset` variants, media, scripts and `<link>`ed files. Links to
+/// other pages and URLs on other hosts are left out.
+fn page_assets(html: &str, page_url: &str) -> Vec<String> {
+    let mut assets = Vec::new();
+    for tag in TAG_RE.captures_iter(html) {
+        let name = tag[1].to_ascii_lowercase();
+        for (key, value) in attributes(&tag[2]) {
+            let urls: Vec<&str> = match key.as_str() {
+                "src" | "poster" => vec![&value],
+                "srcset" => value
+                    .split(',')
+                    .filter_map(|candidate| candidate.split_whitespace().next())
+                    .collect(),
+                "href" if name == "link" => vec![&value],
+                _ => continue,
+            };
+            assets.extend(
+                urls.into_iter()
+                    .filter_map(|url| resolve_url(page_url, url))
+                    .filter(|path| !path.ends_with(".html")),
+            );
+        }
+    }
+    assets
+}
+
+/// The output path `url` points at from the page at `page_url`, if it stays on the site
+fn resolve_url(page_url: &str, url: &str) -> Option<String> {
+    if url.is_empty()
+        || url.starts_with('#')
+        || url.starts_with("//")
+        || url.starts_with("data:")
+        || url.contains("://")
+    {
+        return None;
+    }
+    let path = url.split(['?', '#']).next().unwrap_or("");
+    let mut parts: Vec<&str> = Vec::new();
+    if !path.starts_with('/') {
+        parts.extend(page_url.split('/'));
+        parts.pop(); // the page's own file name
+    }
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            part => parts.push(part),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{test_dir, test_page};
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(&format!(
+            "site_icon = \"<🐻>\"\nsite_name = \"Site\"\nauthor = \"A\"\noutput_dir = \"public\"\nnav = []\n{}",
+            toml
+        ))
+        .unwrap()
+    }
+
+    fn icon_link(rel: &'static str, href: &str, mime: Option<&'static str>) -> IconLink {
+        IconLink {
+            rel,
+            href: href.to_string(),
+            sizes: mime.map(|_| "192x192".to_string()),
+            mime,
+        }
+    }
+
+    fn write(dir: &Path, path: &str, contents: &str) {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn manifest_replaces_the_site_manifest_and_lists_the_icons() {
+        let dir = test_dir("pwa-manifest");
+        fs::write(dir.join("site.webmanifest"), "{}").unwrap();
+        let mut icon_links = vec![
+            icon_link("icon", "favicon.ico", None),
+            icon_link("icon", "icon-192.png", Some("image/png")),
+            icon_link("manifest", "site.webmanifest", None),
+        ];
+        let config = config("[pwa]\nenabled = true\ntheme_color = \"#1f2937\"\n");
+
+        write_manifest(&config, &mut icon_links, &dir).unwrap();
+
+        assert_eq!(icon_links.len(), 2);
+        assert!(!dir.join("site.webmanifest").exists());
+        let manifest: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.join(MANIFEST_FILE)).unwrap()).unwrap();
+        assert_eq!(manifest["name"], "Site");
+        assert_eq!(manifest["short_name"], "Site");
+        assert_eq!(manifest["start_url"], "./index.html");
+        assert_eq!(manifest["display"], "standalone");
+        assert_eq!(manifest["theme_color"], "#1f2937");
+        assert!(manifest.get("background_color").is_none());
+        assert_eq!(
+            manifest["icons"],
+            json!([{ "src": "icon-192.png", "sizes": "192x192", "type": "image/png" }])
+        );
+        assert!(!dir.join(EMOJI_ICON_FILE).exists());
+
+        // Without PNG icons, the emoji is drawn into an SVG
+        write_manifest(&config, &mut Vec::new(), &dir).unwrap();
+        let manifest: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.join(MANIFEST_FILE)).unwrap()).unwrap();
+        assert_eq!(
+            manifest["icons"],
+            json!([{ "src": "icon.svg", "sizes": "any", "type": "image/svg+xml" }])
+        );
+        assert!(
+            fs::read_to_string(dir.join(EMOJI_ICON_FILE))
+                .unwrap()
+                .contains(">&lt;🐻&gt;</text>")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn service_worker_precaches_the_shell_recent_posts_and_what_they_load() {
+        let dir = test_dir("pwa-service-worker");
+        for (path, contents) in [
+            (
+                "index.html",
+                "<link rel=\"stylesheet\" href=\"style.0123456789abcdef.css\"><script src=\"vendor/lib.js\"></script><script src=\"https://cdn.example.com/lib.js\"></script>",
+            ),
+            ("blog.html", "<a href=\"blog/new.html\">New</a>"),
+            (
+                "blog/new.html",
+                "<img src=\"../assets/photo.jpg\" srcset=\"../assets/photo-480w.webp 480w, ../assets/photo.jpg 960w\"><img src=\"data:image/png;base64,AAAA\">",
+            ),
+            ("blog/old.html", "<img src=\"../assets/old.jpg\">"),
+            ("style.0123456789abcdef.css", "body{}"),
+            ("fonts/body.woff2", "font"),
+            ("vendor/lib.js", "lib"),
+            ("assets/photo.jpg", "jpg"),
+            ("assets/photo-480w.webp", "webp"),
+            ("assets/old.jpg", "old"),
+            ("assets/unused.jpg", "unused"),
+        ] {
+            write(&dir, path, contents);
+        }
+        let pages = [test_page("index.md", "index.html", "Home", "")];
+        let posts = [
+            test_page("blog/new.md", "blog/new.html", "New", ""),
+            test_page("blog/old.md", "blog/old.html", "Old", ""),
+        ];
+
+        write_service_worker(&config("[pwa]\nrecent_posts = 1\n"), &pages, &posts, &dir).unwrap();
+
+        let sw = fs::read_to_string(dir.join(SERVICE_WORKER_FILE)).unwrap();
+        let list = sw
+            .split_once("const PRECACHE = ")
+            .and_then(|(_, rest)| rest.split_once(";\n"))
+            .unwrap()
+            .0;
+        let precache: Vec<serde_json::Value> = serde_json::from_str(list).unwrap();
+        let mut urls: Vec<&str> = precache
+            .iter()
+            .map(|entry| entry["url"].as_str().unwrap())
+            .collect();
+        urls.sort();
+        assert_eq!(
+            urls,
+            [
+                "assets/photo-480w.webp",
+                "assets/photo.jpg",
+                "blog.html",
+                "blog/new.html",
+                "fonts/body.woff2",
+                "index.html",
+                "style.0123456789abcdef.css",
+                "vendor/lib.js",
+            ]
+        );
+        for entry in &precache {
+            let bytes = fs::read(dir.join(entry["url"].as_str().unwrap())).unwrap();
+            let hash = format!("{:x}", Sha256::digest(&bytes));
+            assert_eq!(entry["revision"], &hash[..16], "{}", entry["url"]);
+        }
+        assert!(!sw.contains("__VERSION__"));
+
+        // The version changes with the content of any precached file
+        let version = |sw: &str| sw.split_once("PREFIX + '").unwrap().1[..16].to_string();
+        let before = version(&sw);
+        fs::write(dir.join("assets/photo.jpg"), "new jpg").unwrap();
+        write_service_worker(&config("[pwa]\nrecent_posts = 1\n"), &pages, &posts, &dir).unwrap();
+        assert_ne!(
+            version(&fs::read_to_string(dir.join(SERVICE_WORKER_FILE)).unwrap()),
+            before
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn urls_resolve_against_the_page_unless_they_leave_the_site() {
+        assert_eq!(
+            resolve_url("blog/post.html", "../assets/a.jpg?v=1#top"),
+            Some("assets/a.jpg".to_string())
+        );
+        assert_eq!(
+            resolve_url("blog/post.html", "./b.png"),
+            Some("blog/b.png".to_string())
+        );
+        assert_eq!(
+            resolve_url("blog/post.html", "/fonts/a.woff2"),
+            Some("fonts/a.woff2".to_string())
+        );
+        for url in [
+            "",
+            "#notes",
+            "//cdn.example.com/a.js",
+            "https://cdn.example.com/a.js",
+            "data:image/png;base64,AAAA",
+            "../../outside.png",
+        ] {
+            assert_eq!(resolve_url("blog/post.html", url), None, "{}", url);
+        }
+    }
+}
@@ -0,0 +1,606 @@
+use crate::utils::ThemeConfig;
+use anyhow::{Result, anyhow};
+use colored::Colorize;
+use regex::Regex;
+use serde_json::{Value, json};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// `/*@mdbear at <source> <line> <column>*/`, as left in the CSS by
+/// `mark_statements`
+static MARKER_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"/\*@mdbear (at|rule) (\d+) (\d+) (\d+)\*/").unwrap());
+
+/// Compiles `theme/sass/*.scss` to CSS at the output root, with the `[theme]`
+/// config available to them as `$theme-<key>` variables (`null` when unset,
+/// so `!default` still applies). With source maps, each stylesheet also gets
+/// a `<name>.css.map` next to it.
+pub struct SassCompiler {
+    sass_dir: PathBuf,
+    variables: String,
+    source_maps: bool,
+}
+
+impl SassCompiler {
+    pub fn new(theme_dir: &Path, theme: &ThemeConfig, source_maps: bool) -> Result<Self> {
+        Ok(SassCompiler {
+            sass_dir: theme_dir.join("sass"),
+            variables: theme_variables(theme)?,
+            source_maps,
+        })
+    }
+
+    /// Compile every file in `theme/sass/` not starting with `_` to
+    /// `output_dir/<name>.css`, replacing a copied `style.css` of the same
+    /// name, and return the paths written
+    pub fn compile_to(&self, output_dir: &Path) -> Result<Vec<PathBuf>> {
+        let Ok(entries) = fs::read_dir(&self.sass_dir) else {
+            return Ok(Vec::new());
+        };
+        let mut sources: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "scss"))
+            .filter(|path| {
+                path.file_name()
+                    .is_some_and(|name| !name.to_string_lossy().starts_with('_'))
+            })
+            .collect();
+        sources.sort();
+
+        let mut written = Vec::new();
+        for source in &sources {
+            let name = source.file_stem().unwrap().to_string_lossy();
+            let css_name = format!("{}.css", name);
+            let theme_fs = ThemeFs {
+                entry: source.clone(),
+                variables: self.variables.clone(),
+                source_maps: self.source_maps,
+                read: Mutex::new(Vec::new()),
+            };
+            let options = grass::Options::default()
+                .fs(&theme_fs)
+                .load_path(&self.sass_dir);
+            let mut css = grass::from_path(source, &options)
+                .map_err(|err| anyhow!("Failed to compile {}:\n{}", source.display(), err))?;
+
+            if self.source_maps {
+                let map_name = format!("{}.map", css_name);
+                let read = theme_fs.read.lock().unwrap();
+                let (unmarked, map) = source_map(&css, &css_name, &read);
+                fs::write(output_dir.join(&map_name), map)?;
+                css = format!("{}\n/*# sourceMappingURL={} */\n", unmarked, map_name);
+            }
+            fs::write(output_dir.join(&css_name), css)?;
+            written.push(output_dir.join(&css_name));
+            println!(
+                "  {} {} -> {}",
+                "Compiled:".green(),
+                source.display().to_string().green(),
+                css_name.green()
+            );
+        }
+        Ok(written)
+    }
+}
+
+/// `$theme-mode: "dark"; $theme-color-scheme: null; ` for the `[theme]` table
+fn theme_variables(theme: &ThemeConfig) -> Result<String> {
+    let Value::Object(fields) = serde_json::to_value(theme)? else {
+        return Ok(String::new());
+    };
+    Ok(fields
+        .iter()
+        .filter_map(|(key, value)| {
+            let literal = match value {
+                Value::Null => "null".to_string(),
+                Value::Bool(value) => value.to_string(),
+                Value::Number(value) => value.to_string(),
+                Value::String(value) => {
+                    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+                }
+                _ => return None,
+            };
+            Some(format!("$theme-{}: {}; ", key.replace('_', "-"), literal))
+        })
+        .collect())
+}
+
+/// The real file system, except that the entry file starts with the theme
+/// variables. They go on its first line, so that line numbers in errors stay
+/// right. With source maps, statements are marked in every Sass file read,
+/// and the files are recorded in order.
+#[derive(Debug)]
+struct ThemeFs {
+    entry: PathBuf,
+    variables: String,
+    source_maps: bool,
+    read: Mutex<Vec<(PathBuf, String)>>,
+}
+
+impl grass::Fs for ThemeFs {
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut contents = fs::read_to_string(path)?;
+        if self.source_maps && path.extension().is_some_and(|ext| ext == "scss") {
+            let mut read = self.read.lock().unwrap();
+            let source = match read.iter().position(|(known, _)| known == path) {
+                Some(source) => source,
+                None => {
+                    read.push((path.to_path_buf(), contents.clone()));
+                    read.len() - 1
+                }
+            };
+            contents = mark_statements(&contents, source);
+        }
+        if path == self.entry {
+            return Ok(format!("{}{}", self.variables, contents).into_bytes());
+        }
+        Ok(contents.into_bytes())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Block {
+    Rule,
+    AtRule,
+    Property,
+    Function,
+    Interpolation,
+}
+
+/// Put a `/*@mdbear at <source> <line> <column>*/` comment before each
+/// statement, and a `/*@mdbear rule ...*/` one first in the block of each
+/// style rule. grass keeps loud comments where they were, so in the CSS they
+/// tell which statement every line came from. Function bodies, where comments
+/// are dropped, and nested properties, where they are not allowed, are left
+/// as they are.
+fn mark_statements(src: &str, source: usize) -> String {
+    let bytes = src.as_bytes();
+    let mut marks: Vec<(usize, String)> = Vec::new();
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut parens = 0usize;
+    let mut start: Option<usize> = None; // where the current statement starts
+    let marker = |kind: &str, at: usize| {
+        let line_start = src[..at].rfind('\n').map_or(0, |newline| newline + 1);
+        format!(
+            "/*@mdbear {} {} {} {}*/",
+            kind,
+            source,
+            src[..at].matches('\n').count(),
+            src[line_start..at].chars().count()
+        )
+    };
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &src[i..];
+        if rest.starts_with("//") {
+            i += rest.find('\n').unwrap_or(rest.len());
+            continue;
+        }
+        if let Some(comment) = rest.strip_prefix("/*") {
+            i += comment.find("*/").map_or(rest.len(), |end| end + 4);
+            continue;
+        }
+        let markable = matches!(blocks.last(), None | Some(Block::Rule | Block::AtRule))
+            && !blocks.contains(&Block::Function);
+        let byte = bytes[i];
+        if start.is_none() && !byte.is_ascii_whitespace() {
+            start = Some(i);
+            if markable && byte != b'}' && !rest.starts_with("@else") {
+                marks.push((i, marker("at", i)));
+            }
+        }
+        match byte {
+            b'"' | b'\'' => {
+                let mut end = i + 1;
+                while end < bytes.len() && bytes[end] != byte {
+                    end += if bytes[end] == b'\\' { 2 } else { 1 };
+                }
+                i = end + 1;
+                continue;
+            }
+            // An unquoted `url(...)` may hold `//`
+            b'(' if src[..i].to_ascii_lowercase().ends_with("url")
+                && !rest[1..].trim_start().starts_with(['"', '\'']) =>
+            {
+                i += rest.find(')').unwrap_or(rest.len());
+                continue;
+            }
+            b'(' => parens += 1,
+            b')' => parens = parens.saturating_sub(1),
+            b'#' if rest.starts_with("#{") => {
+                blocks.push(Block::Interpolation);
+                i += 2;
+                continue;
+            }
+            b'{' if parens == 0 => {
+                let statement = src[start.unwrap_or(i)..i].trim();
+                let block =
+                    if blocks.contains(&Block::Function) || statement.starts_with("@function") {
+                        Block::Function
+                    } else if statement.starts_with('@') {
+                        Block::AtRule
+                    } else if statement.ends_with(':') {
+                        Block::Property
+                    } else {
+                        Block::Rule
+                    };
+                if block == Block::Rule && markable {
+                    marks.push((i + 1, marker("rule", start.unwrap_or(i))));
+                }
+                blocks.push(block);
+                start = None;
+            }
+            b'}' => {
+                // Ends the statement, unless it closes an interpolation
+                let closed = blocks.pop();
+                if closed != Some(Block::Interpolation) {
+                    start = None;
+                }
+            }
+            b';' if parens == 0 && blocks.last() != Some(&Block::Interpolation) => start = None,
+            _ => {}
+        }
+        i += 1;
+    }
+
+    let mut marked = String::with_capacity(src.len() + marks.len() * 32);
+    let mut copied = 0;
+    for (at, mark) in marks {
+        marked.push_str(&src[copied..at]);
+        marked.push_str(&mark);
+        copied = at;
+    }
+    marked.push_str(&src[copied..]);
+    marked
+}
+
+/// Strip the markers left by `mark_statements` from `css`, and return it with
+/// a source map sending each line to the statement it came from: a rule's
+/// selector to the rule, anything else to the last statement marked before
+/// it. Rules left empty by removing the markers are dropped.
+fn source_map(css: &str, css_name: &str, sources: &[(PathBuf, String)]) -> (String, String) {
+    type Position = (usize, usize, usize);
+    let mut lines: Vec<(String, Option<Position>)> = Vec::new();
+    let mut last_marked: Option<Position> = None;
+    for line in css.lines() {
+        let marked = MARKER_RE.is_match(line);
+        for marker in MARKER_RE.captures_iter(line) {
+            let position = (
+                marker[2].parse().unwrap_or(0),
+                marker[3].parse().unwrap_or(0),
+                marker[4].parse().unwrap_or(0),
+            );
+            match &marker[1] {
+                "rule" => {
+                    if let Some((selector, mapped)) = lines.last_mut()
+                        && selector.ends_with('{')
+                    {
+                        *mapped = Some(position);
+                    }
+                }
+                _ => last_marked = Some(position),
+            }
+        }
+        let line = MARKER_RE.replace_all(line, "");
+        if line.trim().is_empty() {
+            if !marked {
+                lines.push((String::new(), None));
+            }
+            continue;
+        }
+        let mapped = if line.trim() == "}" {
+            last_marked = None;
+            None
+        } else {
+            last_marked.take()
+        };
+        lines.push((line.into_owned(), mapped));
+    }
+
+    // Drop `a {` + `}` pairs, innermost first, then the blank lines doubled
+    let mut index = 0;
+    while index + 1 < lines.len() {
+        let opens = lines[index].0.ends_with('{');
+        let next = lines[index + 1..]
+            .iter()
+            .position(|(line, _)| !line.is_empty());
+        match next {
+            Some(offset) if opens && lines[index + 1 + offset].0.trim() == "}" => {
+                lines.drain(index..=index + 1 + offset);
+                index = index.saturating_sub(1);
+            }
+            _ => index += 1,
+        }
+    }
+    lines.dedup_by(|(line, _), (previous, _)| line.is_empty() && previous.is_empty());
+    while lines.first().is_some_and(|(line, _)| line.is_empty()) {
+        lines.remove(0);
+    }
+    while lines.last().is_some_and(|(line, _)| line.is_empty()) {
+        lines.pop();
+    }
+
+    let mut mappings = String::new();
+    let mut previous = (0i64, 0i64, 0i64);
+    for (number, (line, mapped)) in lines.iter().enumerate() {
+        if number > 0 {
+            mappings.push(';');
+        }
+        if let Some((source, source_line, column)) = *mapped {
+            let current = (source as i64, source_line as i64, column as i64);
+            let indent = line.len() - line.trim_start().len();
+            push_vlq(&mut mappings, indent as i64);
+            push_vlq(&mut mappings, current.0 - previous.0);
+            push_vlq(&mut mappings, current.1 - previous.1);
+            push_vlq(&mut mappings, current.2 - previous.2);
+            previous = current;
+        }
+    }
+
+    let css = lines
+        .iter()
+        .map(|(line, _)| line.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let map = json!({
+        "version": 3,
+        "file": css_name,
+        "sources": sources
+            .iter()
+            .map(|(path, _)| path.to_string_lossy().replace('\\', "/"))
+            .collect::<Vec<_>>(),
+        "sourcesContent": sources.iter().map(|(_, contents)| contents).collect::<Vec<_>>(),
+        "names": [],
+        "mappings": mappings,
+    })
+    .to_string();
+    (css, map)
+}
+
+/// Append `value` as a Base64 VLQ, as source map mappings are written
+fn push_vlq(out: &mut String, value: i64) {
+    let mut vlq = if value < 0 {
+        ((-value) << 1) | 1
+    } else {
+        value << 1
+    };
+    loop {
+        let mut digit = vlq & 31;
+        vlq >>= 5;
+        if vlq > 0 {
+            digit |= 32;
+        }
+        out.push(BASE64[digit as usize] as char);
+        if vlq == 0 {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    /// `theme/sass/` holding `files`, in a fresh temporary directory
+    fn theme_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = test_dir(&format!("sass-{}", name));
+        fs::create_dir_all(dir.join("theme/sass")).unwrap();
+        fs::create_dir_all(dir.join("out")).unwrap();
+        for (file, contents) in files {
+            fs::write(dir.join("theme/sass").join(file), contents).unwrap();
+        }
+        dir
+    }
+
+    fn decode_vlq(chars: &mut std::iter::Peekable<std::str::Chars>) -> i64 {
+        let (mut value, mut shift) = (0i64, 0);
+        for char in chars.by_ref() {
+            let digit = BASE64.iter().position(|&b| b as char == char).unwrap() as i64;
+            value |= (digit & 31) << shift;
+            shift += 5;
+            if digit & 32 == 0 {
+                break;
+            }
+        }
+        if value & 1 == 1 {
+            -(value >> 1)
+        } else {
+            value >> 1
+        }
+    }
+
+    /// Each generated line with the (source file, line) its first segment
+    /// points at
+    fn mapped_lines(css: &str, map: &str) -> Vec<(String, Option<(String, usize)>)> {
+        let map: Value = serde_json::from_str(map).unwrap();
+        let sources = map["sources"].as_array().unwrap();
+        let mut position = (0i64, 0i64, 0i64);
+        let segments: Vec<Option<(String, usize)>> = map["mappings"]
+            .as_str()
+            .unwrap()
+            .split(';')
+            .map(|line| {
+                if line.is_empty() {
+                    return None;
+                }
+                let mut chars = line.chars().peekable();
+                decode_vlq(&mut chars);
+                position.0 += decode_vlq(&mut chars);
+                position.1 += decode_vlq(&mut chars);
+                position.2 += decode_vlq(&mut chars);
+                let source = sources[position.0 as usize].as_str().unwrap();
+                let file = source.rsplit('/').next().unwrap().to_string();
+                Some((file, position.1 as usize))
+            })
+            .collect();
+        css.lines()
+            .map(str::to_string)
+            .zip(segments.into_iter().chain(std::iter::repeat(None)))
+            .collect()
+    }
+
+    #[test]
+    fn source_maps_point_each_line_at_its_own_partial() {
+        let dir = theme_dir(
+            "partials",
+            &[
+                (
+                    "main.scss",
+                    "@use \"a\";\n@use \"b\";\n\nbody {\n  margin: 0;\n}\n",
+                ),
+                ("_a.scss", ".box {\n  color: red;\n}\n"),
+                (
+                    "_b.scss",
+                    "// The same selector again\n.box {\n  a {\n    color: blue;\n  }\n}\n",
+                ),
+            ],
+        );
+        let compiler =
+            SassCompiler::new(&dir.join("theme"), &ThemeConfig::default(), true).unwrap();
+        compiler.compile_to(&dir.join("out")).unwrap();
+
+        let css = fs::read_to_string(dir.join("out/main.css")).unwrap();
+        let map = fs::read_to_string(dir.join("out/main.css.map")).unwrap();
+        assert!(!css.contains("@mdbear"), "{}", css);
+        assert!(
+            css.ends_with("/*# sourceMappingURL=main.css.map */\n"),
+            "{}",
+            css
+        );
+
+        let lines = mapped_lines(&css, &map);
+        let mapping = |text: &str| {
+            let found: Vec<_> = lines
+                .iter()
+                .filter(|(line, _)| line.trim() == text)
+                .collect();
+            assert_eq!(found.len(), 1, "{} in {:?}", text, lines);
+            found[0].1.clone()
+        };
+        assert_eq!(mapping(".box {"), Some(("_a.scss".to_string(), 0)));
+        assert_eq!(mapping("color: red;"), Some(("_a.scss".to_string(), 1)));
+        assert_eq!(mapping(".box a {"), Some(("_b.scss".to_string(), 2)));
+        assert_eq!(mapping("color: blue;"), Some(("_b.scss".to_string(), 3)));
+        assert_eq!(mapping("body {"), Some(("main.scss".to_string(), 3)));
+        assert_eq!(mapping("margin: 0;"), Some(("main.scss".to_string(), 4)));
+        // The rule holding only a nested one is not left behind empty
+        assert_eq!(
+            css.lines().filter(|line| line.starts_with(".box")).count(),
+            2,
+            "{}",
+            css
+        );
+
+        let map: Value = serde_json::from_str(&map).unwrap();
+        assert_eq!(
+            map["sourcesContent"][2],
+            "// The same selector again\n.box {\n  a {\n    color: blue;\n  }\n}\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stylesheets_match_with_and_without_source_maps() {
+        let files = [(
+            "main.scss",
+            "@function double($n) {\n  @return $n * 2;\n}\n@mixin tint($c) {\n  color: $c;\n}\n\
+             .a-#{1 + 1} {\n  width: double(2px);\n  font: {\n    family: serif;\n  }\n  \
+             background: url(//example.com/a.png);\n  @include tint(red);\n  @if true {\n    \
+             top: 0;\n  } @else {\n    top: 1px;\n  }\n  &:hover { content: \"}\"; }\n}\n\
+             @media print {\n  .b {\n    display: none;\n  }\n}\n",
+        )];
+        let plain = theme_dir("plain", &files);
+        SassCompiler::new(&plain.join("theme"), &ThemeConfig::default(), false)
+            .unwrap()
+            .compile_to(&plain.join("out"))
+            .unwrap();
+        let mapped = theme_dir("mapped", &files);
+        SassCompiler::new(&mapped.join("theme"), &ThemeConfig::default(), true)
+            .unwrap()
+            .compile_to(&mapped.join("out"))
+            .unwrap();
+
+        let plain_css = fs::read_to_string(plain.join("out/main.css")).unwrap();
+        let mapped_css = fs::read_to_string(mapped.join("out/main.css")).unwrap();
+        let mapped_css = mapped_css
+            .split("\n/*# sourceMappingURL")
+            .next()
+            .unwrap()
+            .to_string();
+        assert!(plain_css.contains(".a-2:hover {"), "{}", plain_css);
+        assert_eq!(plain_css.trim_end(), mapped_css.trim_end());
+        assert!(!plain.join("out/main.css.map").exists());
+        fs::remove_dir_all(&plain).unwrap();
+        fs::remove_dir_all(&mapped).unwrap();
+    }
+
+    #[test]
+    fn theme_settings_are_sass_variables() {
+        let dir = theme_dir(
+            "variables",
+            &[
+                ("_palette.scss", "$accent: #c33 !default;\n"),
+                (
+                    "extra.scss",
+                    concat!(
+                        "@use \"palette\";\n\n",
+                        "$theme-color-scheme: \"fallback\" !default;\n",
+                        "$theme-mode: \"auto\" !default;\n\n",
+                        ".scheme::after { content: \"#{$theme-color-scheme} #{$theme-mode}\"; }\n",
+                        ".nav { a { &:hover { color: palette.$accent; } } }\n",
+                    ),
+                ),
+            ],
+        );
+        let theme = ThemeConfig {
+            mode: Some("dark".to_string()),
+            ..Default::default()
+        };
+        let written = SassCompiler::new(&dir.join("theme"), &theme, false)
+            .unwrap()
+            .compile_to(&dir.join("out"))
+            .unwrap();
+
+        assert_eq!(written, [dir.join("out/extra.css")]);
+        let css = fs::read_to_string(dir.join("out/extra.css")).unwrap();
+        // Unset settings are null, so `!default` applies
+        assert!(css.contains("content: \"fallback dark\";"), "{}", css);
+        assert!(css.contains(".nav a:hover {\n  color: #c33;\n}"), "{}", css);
+        assert!(!dir.join("out/_palette.css").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compile_errors_name_the_stylesheet() {
+        let dir = theme_dir("broken", &[("broken.scss", "a {\n  color: $missing;\n}\n")]);
+        let err = SassCompiler::new(&dir.join("theme"), &ThemeConfig::default(), false)
+            .unwrap()
+            .compile_to(&dir.join("out"))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with(&format!(
+                "Failed to compile {}:\n",
+                dir.join("theme/sass/broken.scss").display()
+            )),
+            "{}",
+            err
+        );
+        assert!(err.contains("Undefined variable."), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
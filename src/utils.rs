@@ -1,19 +1,28 @@
-use anyhow::{Context, Result};
+use crate::figures::Figures;
+use crate::images::{ImageManifest, rewrite_image_sources};
+use anyhow::{Context, Result, bail};
 use chrono::{DateTime, NaiveDate, TimeZone, Utc};
 use colored::Colorize;
 use gray_matter::{Matter, ParsedEntity, engine::YAML};
-use image::{DynamicImage, GenericImageView, ImageFormat, imageops::Lanczos3};
 use pulldown_cmark::{CowStr, Event, HeadingLevel, Options, Parser as MdParser, Tag, TagEnd, html};
+use rayon::prelude::*;
 use regex::Regex;
 use rust_embed::RustEmbed;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::Path;
+use tera::{Context as TeraContext, Tera};
+
+/// Directory, relative to the project root, where encoded images are kept between builds
+pub const CACHE_DIR: &str = ".mdbear-cache";
+
+/// Directory, in the project and in themes, whose files are copied verbatim to the site root
+pub const STATIC_DIR: &str = "static";
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Config {
-    pub site_icon: String,
+    pub site_icon: String, // an emoji, or an image file such as "static/logo.png"
     pub site_name: String,
     pub author: String,
     pub output_dir: String,
@@ -22,12 +31,26 @@ pub struct Config {
     pub blog_url: Option<String>,
     #[serde(default)]
     pub site_description: Option<String>,
+    pub head_html: Option<String>, // HTML file added to every page's <head>, e.g. "includes/analytics.html"
+    pub body_end_html: Option<String>, // HTML file added before every page's </body>
     #[serde(default)]
     pub social: SocialLinks,
     #[serde(default)]
     pub theme: ThemeConfig,
     #[serde(default)]
     pub blog: BlogConfig,
+    #[serde(default)]
+    pub build: BuildConfig,
+    #[serde(default)]
+    pub graph: GraphConfig,
+    #[serde(default)]
+    pub images: ImagesConfig,
+    #[serde(default)]
+    pub figures: FiguresConfig,
+    #[serde(default)]
+    pub fonts: FontsConfig,
+    #[serde(default)]
+    pub pwa: PwaConfig,
     pub nav: Vec<NavItem>,
 }
 
@@ -48,6 +71,26 @@ pub struct SocialLinks {
 pub struct ThemeConfig {
     pub mode: Option<String>,         // "light", "dark", or "auto"
     pub color_scheme: Option<String>, // "catppuccin-latte", "catppuccin-frappe", etc.
+    #[serde(default)]
+    pub palettes: BTreeMap<String, PaletteConfig>, // color schemes defined in the config
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct PaletteConfig {
+    pub light: Option<ColorSet>,
+    pub dark: Option<ColorSet>,
+}
+
+/// Colors of one palette variant; unset ones come from the default scheme
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ColorSet {
+    pub bg: Option<String>,          // page background
+    pub paper: Option<String>,       // cards and panels
+    pub ink: Option<String>,         // body text
+    pub muted: Option<String>,       // dates, captions
+    pub line: Option<String>,        // borders and rules
+    pub accent: Option<String>,      // links and highlights
+    pub accent_soft: Option<String>, // accent backgrounds
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
@@ -57,6 +100,60 @@ pub struct BlogConfig {
     pub sort_order: Option<String>, // "asc", "desc"
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct BuildConfig {
+    pub broken_links: Option<String>, // "warn" (default) or "error"; anything else is rejected
+    pub fingerprint: Option<bool>, // add content hashes to stylesheet and fonts/ names, and hashed copies of other asset_url files (default false)
+    pub minify: Option<bool>, // minify HTML, CSS, inline scripts and search_index.json (default false)
+    pub csp: Option<String>, // Content-Security-Policy hashing inline code: "meta" (tag in each page) or "headers" (_headers file)
+    #[serde(default)]
+    pub integrity: BTreeMap<String, String>, // with csp, SRI values of other external files, by URL, e.g. "sha384-..."
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct GraphConfig {
+    pub related_posts: Option<usize>, // number of related posts per page (default 3, 0 disables)
+    pub json: Option<bool>,           // write graph.json with all pages and links
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ImagesConfig {
+    pub max_width: Option<u32>, // scale wider images down to this width (default 1201)
+    pub quality: Option<u8>,    // lossy WebP/AVIF/JPEG quality, 1-100 (default 80)
+    pub formats: Option<Vec<String>>, // "webp", "avif", "original" in order of preference, see README (default ["webp"])
+    pub widths: Option<Vec<u32>>,     // smaller copies for `srcset` (default [480, 960])
+    pub sizes: Option<String>, // `sizes` attribute for responsive images (default "(min-width: 42rem) 42rem, 100vw")
+    pub include: Option<Vec<String>>, // globs relative to content/, e.g. "assets/photos/**" (default: all images)
+    pub exclude: Option<Vec<String>>, // globs of images to copy untouched
+    pub placeholder: Option<String>, // "lqip" (blurred preview, default), "color" (dominant color) or "none"
+    pub keep_original: Option<bool>, // keep the full-resolution source file in the output (default false)
+    pub keep_copyright: Option<bool>, // keep the EXIF Copyright and Artist fields when stripping metadata (default false)
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct FiguresConfig {
+    pub caption: Option<String>, // caption standalone images with their "title" (default), "alt" text or "none"
+    pub numbering: Option<bool>, // number captioned figures and resolve `[@fig:name]` references (default false)
+    pub label: Option<String>,   // word before the number, e.g. "图" (default "Figure")
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct FontsConfig {
+    pub subset: Option<bool>, // subset theme fonts to the characters the site uses (default false)
+    pub safety_margin: Option<String>, // characters always kept: "none", "ascii" (default) or "latin1"
+    pub extra_characters: Option<String>, // more characters to always keep, e.g. "→✓"
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct PwaConfig {
+    pub enabled: Option<bool>, // write manifest.webmanifest and a service worker for offline reading (default false)
+    pub recent_posts: Option<usize>, // number of newest posts precached for offline reading (default 10)
+    pub short_name: Option<String>,  // name under the home screen icon (default site_name)
+    pub display: Option<String>,     // "standalone" (default), "minimal-ui" or "browser"
+    pub theme_color: Option<String>, // browser UI color, e.g. "#1f2937"
+    pub background_color: Option<String>, // splash screen color while the site loads
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct NavItem {
     pub name: String,
@@ -71,6 +168,36 @@ pub struct PostMeta {
     pub title: Option<String>,
     pub date: Option<String>,
     pub lang: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_tags")]
+    pub tags: Vec<String>,
+    pub cover: Option<String>, // image path relative to content/, e.g. "assets/cover.jpg"
+    #[serde(default)]
+    pub extra_css: Vec<String>, // stylesheets for this page, relative to content/ or absolute URLs
+    #[serde(default)]
+    pub extra_js: Vec<String>, // scripts for this page, relative to content/ or absolute URLs
+}
+
+/// Accept `tags: [a, b]` as well as the shorthand `tags: a, b`
+fn deserialize_tags<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Tags {
+        List(Vec<String>),
+        Single(String),
+    }
+
+    let tags = match Tags::deserialize(deserializer)? {
+        Tags::List(tags) => tags,
+        Tags::Single(tags) => tags.split(',').map(str::to_string).collect(),
+    };
+    Ok(tags
+        .into_iter()
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect())
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -87,7 +214,16 @@ pub struct Page {
     pub toc: Vec<TocItem>,
     pub slug: String,
     pub url: String,
+    pub source_path: String, // path relative to the content directory, e.g. "blog/post.md"
     pub reading_time: usize, // estimated reading time in minutes
+    pub backlinks: Vec<PageRef>, // pages linking to this one
+    pub related: Vec<PageRef>, // posts sharing tags or similar text
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct PageRef {
+    pub title: String,
+    pub url: String,
 }
 
 #[derive(RustEmbed)]
@@ -100,7 +236,9 @@ pub fn load_page(
     base_content_dir: &Path,
     file_path: &str,
     strict_mode: bool,
-    converted_images: Option<&HashSet<String>>,
+    images: Option<&ImageManifest>,
+    shortcodes: Option<&Tera>,
+    figures: &Figures,
 ) -> Result<Page> {
     let full_path = base_content_dir.join(file_path);
     let content = fs::read_to_string(&full_path)
@@ -140,34 +278,29 @@ pub fn load_page(
         ));
     }
 
-    let (processed_content, sidenote_blocks) = protect_sidenotes(&result.content);
+    let (processed_content, shortcode_blocks) = match shortcodes {
+        Some(tera) => protect_shortcodes(&result.content, tera, file_path)?,
+        None => (result.content.clone(), Vec::new()),
+    };
+    let (processed_content, sidenote_blocks) = protect_sidenotes(&processed_content);
     let (processed_content, math_blocks) = protect_math(&processed_content);
     let (processed_content, typst_blocks) = protect_typst(&processed_content);
+    // After math and Typst, whose `[[...]]` is not a link
+    let processed_content = render_wikilinks(&processed_content);
     let parser = MdParser::new_ext(&processed_content, markdown_options());
     let (events, toc) = collect_toc(parser);
     let mut html_output = String::new();
     html::push_html(&mut html_output, events.into_iter());
+    let html_output = figures.render(&html_output, file_path);
     let html_output = restore_typst(&html_output, &typst_blocks);
     let html_output = restore_math(&html_output, &math_blocks);
     let html_output = restore_sidenotes(&html_output, &sidenote_blocks);
+    let html_output = restore_blocks(&html_output, &shortcode_blocks, "MDBEAR_SHORTCODE", |b| {
+        b.to_string()
+    });
     let html_output = render_fontawesome(&html_output);
 
-    let re = Regex::new(r#"(?i)(src=["'][^"']*/([^/"']+))\.(png|jpe?g|gif)([^"']*)(["'])"#).unwrap();
-    let html_output = re
-        .replace_all(&html_output, |caps: &regex::Captures| {
-            let stem = caps.get(2).map(|m| m.as_str()).unwrap_or("");
-            if converted_images
-                .map(|set| set.contains(stem))
-                .unwrap_or(true)
-            {
-                // Image was converted (or no tracking info): rewrite to .webp
-                format!("{}.webp{}{}", &caps[1], &caps[4], &caps[5])
-            } else {
-                // Image was NOT converted: keep original reference
-                caps[0].to_string()
-            }
-        })
-        .to_string();
+    let html_output = rewrite_image_sources(&html_output, images, file_path);
 
     let url = page_url_from_source_path(file_path, &stem);
 
@@ -179,7 +312,7 @@ pub fn load_page(
     let reading_time = if char_count == 0 {
         1
     } else {
-        std::cmp::max(1, (char_count + 299) / 300)
+        std::cmp::max(1, char_count.div_ceil(300))
     };
 
     Ok(Page {
@@ -188,7 +321,10 @@ pub fn load_page(
         toc,
         slug: stem,
         url,
+        source_path: normalized_source_path,
         reading_time,
+        backlinks: Vec::new(),
+        related: Vec::new(),
     })
 }
 
@@ -206,6 +342,22 @@ fn page_url_from_source_path(file_path: &str, stem: &str) -> String {
     }
 }
 
+/// Relative path from a page's output location back to the site root, e.g. `..` for `blog/post.html`
+pub fn root_path_for_url(url: &str) -> String {
+    let depth = Path::new(url)
+        .parent()
+        .map(|parent| parent.components().count())
+        .unwrap_or(0);
+
+    if depth == 0 {
+        ".".to_string()
+    } else {
+        std::iter::repeat_n("..", depth)
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
 pub fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> std::io::Result<()> {
     fs::create_dir_all(&dst)?;
     for entry in fs::read_dir(src)? {
@@ -305,8 +457,9 @@ fn heading_level_number(level: HeadingLevel) -> u8 {
     }
 }
 
-fn unique_slug(title: &str, slugs: &mut HashMap<String, usize>) -> String {
-    let mut slug = title
+/// Heading id for `title`, as generated for TOC anchors (without de-duplication)
+pub fn slugify(title: &str) -> String {
+    let slug = title
         .to_lowercase()
         .chars()
         .map(|c| if c.is_alphanumeric() { c } else { '-' })
@@ -315,9 +468,14 @@ fn unique_slug(title: &str, slugs: &mut HashMap<String, usize>) -> String {
         .to_string();
 
     if slug.is_empty() {
-        slug = "section".to_string();
+        "section".to_string()
+    } else {
+        slug
     }
+}
 
+fn unique_slug(title: &str, slugs: &mut HashMap<String, usize>) -> String {
+    let slug = slugify(title);
     let count = slugs.entry(slug.clone()).or_insert(0);
     let result = if *count == 0 {
         slug.clone()
@@ -333,24 +491,32 @@ fn protect_math(content: &str) -> (String, Vec<String>) {
     let mut blocks = Vec::new();
     let mut rest = content;
 
-    // Handle block math $$...$$ first, then inline $...$
+    // Handle block math $$...$$ first, then inline $...$ in the text around it
     while let Some(start) = rest.find("$$") {
-        output.push_str(&rest[..start]);
         let after_start = &rest[start + "$$".len()..];
         if let Some(end) = after_start.find("$$") {
+            protect_inline_math(&rest[..start], &mut output, &mut blocks);
             let block = format!("{}{}{}", "$$", &after_start[..end], "$$");
             let index = blocks.len();
             blocks.push(block);
             output.push_str(&format!("\nMDBEAR_MATH{}\n", index));
             rest = &after_start[end + "$$".len()..];
         } else {
+            // An unclosed $$ is kept as written, with the text after it
+            protect_inline_math(&rest[..start], &mut output, &mut blocks);
             output.push_str(&rest[start..]);
             rest = "";
             break;
         }
     }
-    // Handle inline math $...$ in the remaining content
-    let mut remaining = rest;
+    protect_inline_math(rest, &mut output, &mut blocks);
+
+    (output, blocks)
+}
+
+/// Replace the inline math $...$ of `text` with placeholders
+fn protect_inline_math(text: &str, output: &mut String, blocks: &mut Vec<String>) {
+    let mut remaining = text;
     while let Some(start) = remaining.find('$') {
         if start > 0 && remaining.chars().nth(start - 1) == Some('\\') {
             // Escaped dollar sign, keep it
@@ -370,14 +536,12 @@ fn protect_math(content: &str) -> (String, Vec<String>) {
             remaining = &after_start[end + 1..];
         } else {
             output.push('$');
-            output.push_str(&after_start);
+            output.push_str(after_start);
             remaining = "";
             break;
         }
     }
     output.push_str(remaining);
-
-    (output, blocks)
 }
 
 fn restore_math(content: &str, blocks: &[String]) -> String {
@@ -434,13 +598,180 @@ where
     F: Fn(&str) -> String,
 {
     let mut output = content.to_string();
-    for (index, block) in blocks.iter().enumerate() {
+    // Restore in reverse so that e.g. PREFIX1 does not clobber PREFIX10
+    for (index, block) in blocks.iter().enumerate().rev() {
         output = output.replace(&format!("<p>{}{}</p>", prefix, index), &render(block));
         output = output.replace(&format!("{}{}", prefix, index), &render(block));
     }
     output
 }
 
+/// Marks the `{{<` of shortcodes outside code, so that examples in code stay as written
+const SHORTCODE_OPEN: &str = "\u{E000}";
+
+/// A shortcode that cannot be rendered. Unlike other problems with a blog
+/// post, which only skip the post, it fails the build.
+#[derive(Debug)]
+struct ShortcodeError(anyhow::Error);
+
+impl std::fmt::Display for ShortcodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for ShortcodeError {}
+
+/// Render `{{< name key="value" >}}` and `{{< name >}}body{{< /name >}}` shortcodes
+/// against `theme/shortcodes/<name>.html`, leaving placeholders in the Markdown.
+/// Shortcodes in code blocks and inline code are left as written.
+fn protect_shortcodes(content: &str, tera: &Tera, source: &str) -> Result<(String, Vec<String>)> {
+    let marked = map_outside_code(content, |text| text.replace("{{<", SHORTCODE_OPEN));
+    let (output, blocks) = expand_shortcodes(&marked, tera, source).map_err(ShortcodeError)?;
+    // An opening `{{<` that never became a shortcode is plain text
+    let unmark = |text: &str| text.replace(SHORTCODE_OPEN, "{{<");
+    Ok((unmark(&output), blocks.iter().map(|b| unmark(b)).collect()))
+}
+
+fn expand_shortcodes(content: &str, tera: &Tera, source: &str) -> Result<(String, Vec<String>)> {
+    let tag_re = Regex::new(r"(?s)\x{E000}\s*(/)?\s*([A-Za-z0-9_-]+)(.*?)\s*(/)?>\}\}").unwrap();
+    let tags: Vec<regex::Captures> = tag_re.captures_iter(content).collect();
+
+    // A closing tag belongs to the nearest open tag of the same name, so the
+    // same shortcode can be nested, or used inline before a block one. Tags
+    // that are never closed, or written as `{{< name />}}`, are inline.
+    let mut closing = vec![None; tags.len()];
+    let mut open: Vec<usize> = Vec::new();
+    for (index, caps) in tags.iter().enumerate() {
+        let name = &caps[2];
+        if caps.get(1).is_some() {
+            let Some(position) = open.iter().rposition(|&opener| &tags[opener][2] == name) else {
+                bail!(
+                    "Unexpected closing shortcode `{{{{< /{} >}}}}` in {}",
+                    name,
+                    source
+                );
+            };
+            closing[open[position]] = Some(index);
+            open.truncate(position);
+        } else if caps.get(4).is_none() {
+            open.push(index);
+        }
+    }
+
+    let mut output = String::new();
+    let mut blocks = Vec::new();
+    let mut rest_start = 0;
+    let mut index = 0;
+    while index < tags.len() {
+        let caps = &tags[index];
+        let whole = caps.get(0).unwrap();
+        let name = &caps[2];
+        let args = parse_shortcode_args(&caps[3]);
+        output.push_str(&content[rest_start..whole.start()]);
+
+        let close = closing[index].map(|close| tags[close].get(0).unwrap());
+        let body_html = match close {
+            Some(close) => {
+                let body = content[whole.end()..close.start()].trim();
+                let (body, nested) = expand_shortcodes(body, tera, source)?;
+                let mut body_html = String::new();
+                html::push_html(&mut body_html, MdParser::new_ext(&body, markdown_options()));
+                Some(restore_blocks(
+                    &body_html,
+                    &nested,
+                    "MDBEAR_SHORTCODE",
+                    |b| b.to_string(),
+                ))
+            }
+            None => None,
+        };
+
+        let rendered = render_shortcode(tera, name, &args, body_html.as_deref(), source)?;
+        let block = blocks.len();
+        blocks.push(rendered);
+        match close {
+            Some(close) => {
+                output.push_str(&format!("\nMDBEAR_SHORTCODE{}\n", block));
+                rest_start = close.end();
+                index = closing[index].unwrap() + 1;
+            }
+            None => {
+                output.push_str(&format!("MDBEAR_SHORTCODE{}", block));
+                rest_start = whole.end();
+                index += 1;
+            }
+        }
+    }
+
+    output.push_str(&content[rest_start..]);
+    Ok((output, blocks))
+}
+
+fn parse_shortcode_args(raw: &str) -> serde_json::Map<String, serde_json::Value> {
+    let arg_re =
+        Regex::new(r#"([A-Za-z_][A-Za-z0-9_-]*)\s*=\s*(?:"((?:[^"\\]|\\.)*)"|'([^']*)'|(\S+))"#)
+            .unwrap();
+    let mut args = serde_json::Map::new();
+    for caps in arg_re.captures_iter(raw) {
+        let key = caps[1].to_string();
+        let value = if let Some(quoted) = caps.get(2).or_else(|| caps.get(3)) {
+            serde_json::Value::String(quoted.as_str().replace("\\\"", "\""))
+        } else {
+            // Unquoted values may be numbers or booleans
+            let bare = &caps[4];
+            serde_json::from_str(bare)
+                .unwrap_or_else(|_| serde_json::Value::String(bare.to_string()))
+        };
+        args.insert(key, value);
+    }
+    args
+}
+
+fn render_shortcode(
+    tera: &Tera,
+    name: &str,
+    args: &serde_json::Map<String, serde_json::Value>,
+    body: Option<&str>,
+    source: &str,
+) -> Result<String> {
+    let template = format!("shortcodes/{}.html", name);
+    if !tera.get_template_names().any(|t| t == template) {
+        bail!(
+            "Unknown shortcode `{}` in {} (expected template theme/{})",
+            name,
+            source,
+            template
+        );
+    }
+    // Shortcode templates receive these alongside the arguments
+    if let Some(reserved) = ["args", "body"]
+        .iter()
+        .find(|name| args.contains_key(**name))
+    {
+        bail!(
+            "Shortcode `{}` in {} has an argument named `{}`, which is reserved",
+            name,
+            source,
+            reserved
+        );
+    }
+
+    let mut ctx = TeraContext::new();
+    for (key, value) in args {
+        ctx.insert(key.as_str(), value);
+    }
+    ctx.insert("args", args);
+    if let Some(body) = body {
+        ctx.insert("body", body);
+    }
+
+    let rendered = tera
+        .render(&template, &ctx)
+        .with_context(|| format!("Failed to render shortcode `{}` in {}", name, source))?;
+    Ok(rendered.trim().to_string())
+}
+
 fn protect_sidenotes(content: &str) -> (String, Vec<String>) {
     let mut output = String::new();
     let mut blocks = Vec::new();
@@ -470,7 +801,8 @@ fn restore_sidenotes(content: &str, blocks: &[String]) -> String {
     let mut output = content.to_string();
     for (index, block) in blocks.iter().enumerate() {
         let mut inner_html = String::new();
-        let parser = MdParser::new_ext(block, markdown_options());
+        let block = render_wikilinks(block);
+        let parser = MdParser::new_ext(&block, markdown_options());
         pulldown_cmark::html::push_html(&mut inner_html, parser);
 
         let marker = (index + 1).to_string();
@@ -506,6 +838,71 @@ fn find_matching_bracket(content: &str) -> Option<usize> {
     None
 }
 
+/// Turn Obsidian-style `[[Page]]`, `[[Page#Heading|label]]` and `![[image.png]]`
+/// into links with `mdbear-wikilink:` targets, resolved once all pages are loaded.
+fn render_wikilinks(content: &str) -> String {
+    let re = Regex::new(r"(!?)\[\[([^\[\]|#\n]*)(#[^\[\]|\n]*)?(?:\|([^\[\]\n]*))?\]\]").unwrap();
+    map_outside_code(content, |text| {
+        re.replace_all(text, |caps: &regex::Captures| {
+            let target = caps[2].trim();
+            let fragment = caps.get(3).map(|m| m.as_str().trim()).unwrap_or("");
+            let label = caps
+                .get(4)
+                .map(|m| m.as_str().trim())
+                .filter(|l| !l.is_empty());
+            if caps[1].is_empty() {
+                let label = label.unwrap_or(if target.is_empty() {
+                    fragment.trim_start_matches('#')
+                } else {
+                    target
+                });
+                format!(
+                    "<a class=\"wikilink\" href=\"mdbear-wikilink:{}{}\">{}</a>",
+                    escape_html(target),
+                    escape_html(fragment),
+                    escape_html(label)
+                )
+            } else {
+                // `![[image.png|300]]` sets a width, any other label is alt text
+                let (alt, width) = match label {
+                    Some(label) if label.parse::<u32>().is_ok() => (target, Some(label)),
+                    Some(label) => (label, None),
+                    None => (target, None),
+                };
+                format!(
+                    "<img class=\"wikilink-embed\" src=\"mdbear-wikilink:{}\" alt=\"{}\"{}>",
+                    escape_html(target),
+                    escape_html(alt),
+                    width
+                        .map(|w| format!(" width=\"{}\"", w))
+                        .unwrap_or_default()
+                )
+            }
+        })
+        .to_string()
+    })
+}
+
+/// Apply `transform` to the parts of `content` outside code blocks and inline
+/// code spans, as the Markdown parser finds them
+fn map_outside_code<F>(content: &str, transform: F) -> String
+where
+    F: Fn(&str) -> String,
+{
+    let mut output = String::new();
+    let mut rest_start = 0;
+    for (event, range) in MdParser::new_ext(content, markdown_options()).into_offset_iter() {
+        let is_code = matches!(event, Event::Code(_) | Event::Start(Tag::CodeBlock(_)));
+        if is_code && range.start >= rest_start {
+            output.push_str(&transform(&content[rest_start..range.start]));
+            output.push_str(&content[range.clone()]);
+            rest_start = range.end;
+        }
+    }
+    output.push_str(&transform(&content[rest_start..]));
+    output
+}
+
 fn render_fontawesome(content: &str) -> String {
     let re = Regex::new(r#":fa-([a-z0-9-]+):"#).unwrap();
     re.replace_all(
@@ -630,32 +1027,50 @@ fn sanitize_cdata(content: &str) -> String {
     content.replace("]]>", "]]]]><![CDATA[>")
 }
 
-pub fn scan_blog_posts(content_dir: &Path, converted_images: Option<&HashSet<String>>) -> Result<Vec<Page>> {
+pub fn scan_blog_posts(
+    content_dir: &Path,
+    images: Option<&ImageManifest>,
+    shortcodes: Option<&Tera>,
+    figures: &Figures,
+) -> Result<Vec<Page>> {
     let blog_dir = content_dir.join("blog");
     if !blog_dir.exists() {
         return Ok(Vec::new());
     }
 
-    let mut posts = Vec::new();
-    let entries = std::fs::read_dir(&blog_dir)?;
-    for entry in entries {
-        let entry = entry?;
-        let path = entry.path();
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(&blog_dir)? {
+        let path = entry?.path();
         if path.extension().and_then(|s| s.to_str()) == Some("md") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    // Render posts in parallel; results keep the sorted path order
+    let results: Vec<_> = paths
+        .par_iter()
+        .map(|path| {
             let relative = path
                 .strip_prefix(content_dir)
                 .unwrap()
                 .to_str()
                 .unwrap()
                 .to_string();
-            match load_page(content_dir, &relative, true, converted_images) {
-                Ok(mut page) => {
-                    page.url = page.url.replace('\\', "/");
-                    posts.push(page);
-                }
-                Err(e) => {
-                    eprintln!("  {} {}: {}", "跳过".yellow(), path.display(), e);
-                }
+            load_page(content_dir, &relative, true, images, shortcodes, figures)
+        })
+        .collect();
+
+    let mut posts = Vec::new();
+    for (path, result) in paths.iter().zip(results) {
+        match result {
+            Ok(mut page) => {
+                page.url = page.url.replace('\\', "/");
+                posts.push(page);
+            }
+            Err(e) if e.is::<ShortcodeError>() => return Err(e),
+            Err(e) => {
+                eprintln!("  {} {}: {}", "跳过".yellow(), path.display(), e);
             }
         }
     }
@@ -670,105 +1085,209 @@ pub fn scan_blog_posts(content_dir: &Path, converted_images: Option<&HashSet<Str
     Ok(posts)
 }
 
-pub fn images2webp(src_dir: &Path, dst_dir: &Path) -> Result<HashSet<String>> {
-    let mut converted = HashSet::new();
-    if !src_dir.exists() {
-        return Ok(converted);
+/// Very simple HTML tag stripper for reading time calculation
+pub fn strip_html_tags(html: &str) -> String {
+    let mut result = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => result.push(c),
+            _ => {}
+        }
     }
+    // Collapse multiple whitespace
+    result.split_whitespace().collect::<Vec<_>>().join(" ")
+}
 
-    fs::create_dir_all(dst_dir)?;
+/// A fresh, empty directory for the files of a unit test, named after it
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("mdbear-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
 
-    const MAX_WIDTH: u32 = 1201;
+/// A rendered page for unit tests, titled `title` and without front matter otherwise
+#[cfg(test)]
+pub(crate) fn test_page(source_path: &str, url: &str, title: &str, content_html: &str) -> Page {
+    Page {
+        meta: PostMeta {
+            title: Some(title.to_string()),
+            ..Default::default()
+        },
+        content_html: content_html.to_string(),
+        toc: Vec::new(),
+        slug: slugify(title),
+        url: url.to_string(),
+        source_path: source_path.to_string(),
+        reading_time: 1,
+        backlinks: Vec::new(),
+        related: Vec::new(),
+    }
+}
 
-    for entry in fs::read_dir(src_dir)? {
-        let entry = entry?;
-        let path = entry.path();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shortcodes() -> Tera {
+        let mut tera = Tera::default();
+        tera.add_raw_templates([
+            (
+                "shortcodes/figure.html",
+                "<figure><img src=\"{{ src }}\">{% if caption %}<figcaption>{{ caption }}</figcaption>{% endif %}</figure>",
+            ),
+            (
+                "shortcodes/gallery.html",
+                "<div style=\"--gallery-columns: {{ columns }}\">{{ body | safe }}</div>",
+            ),
+        ])
+        .unwrap();
+        tera
+    }
 
-        if path.is_dir() {
-            let sub_dst = dst_dir.join(entry.file_name());
-            let sub_converted = images2webp(&path, &sub_dst)?;
-            converted.extend(sub_converted);
-            continue;
-        }
+    /// The HTML of `markdown` as a page, with the shortcodes above
+    fn render(name: &str, markdown: &str) -> Result<String> {
+        let dir = test_dir(name);
+        fs::write(dir.join("page.md"), markdown).unwrap();
+        let page = load_page(
+            &dir,
+            "page.md",
+            false,
+            None,
+            Some(&shortcodes()),
+            &Figures::default(),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        page.map(|page| page.content_html)
+    }
 
-        // Only process common image extensions
-        let ext = path
-            .extension()
-            .and_then(|e| e.to_str())
-            .map(|e| e.to_lowercase())
-            .unwrap_or_default();
-        if !matches!(ext.as_str(), "png" | "jpg" | "jpeg" | "gif") {
-            continue;
-        }
+    #[test]
+    fn shortcodes_render_their_templates() {
+        let html = render(
+            "shortcodes",
+            "{{< figure src=\"inline.png\" caption=\"Inline\" >}}\n\n\
+             {{< figure src=\"block.png\" >}}\nIgnored body\n{{< /figure >}}\n",
+        )
+        .unwrap();
+        assert!(
+            html.contains(
+                "<figure><img src=\"inline.png\"><figcaption>Inline</figcaption></figure>"
+            ),
+            "{}",
+            html
+        );
+        assert!(html.contains("<img src=\"block.png\">"), "{}", html);
+        assert!(!html.contains("Ignored body"), "{}", html);
+    }
 
-        let stem = path
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .unwrap_or("")
-            .to_string();
-        if stem.is_empty() {
-            continue;
-        }
+    #[test]
+    fn shortcodes_nest_and_wrap_markdown() {
+        let html = render(
+            "nested-shortcodes",
+            "{{< gallery columns=1 >}}\n{{< gallery columns=2 >}}\nInner\n{{< /gallery >}}\nOuter\n{{< /gallery >}}\n",
+        )
+        .unwrap();
+        assert!(
+            html.contains(
+                "<div style=\"--gallery-columns: 1\"><div style=\"--gallery-columns: 2\"><p>Inner</p>\n</div>\n<p>Outer</p>\n</div>"
+            ),
+            "{}",
+            html
+        );
+    }
 
-        match image::open(&path) {
-            Ok(img) => {
-                let (width, height) = img.dimensions();
+    #[test]
+    fn shortcodes_in_code_are_left_as_written() {
+        let html = render(
+            "code-shortcodes",
+            concat!(
+                "Write `{{< carousel >}}` to show a carousel:\n\n",
+                "```markdown\n{{< carousel src=\"a.png\" >}}\n```\n\n",
+                "    {{< carousel indented >}}\n\n",
+                "A span with a backtick: ``{{< carousel >}} ` tick``\n\n",
+                "~~~\n```\n{{< carousel tilde >}}\n~~~\n",
+            ),
+        )
+        .unwrap();
+        for code in [
+            "<code>{{&lt; carousel &gt;}}</code>",
+            "{{&lt; carousel src=\"a.png\" &gt;}}\n</code></pre>",
+            "<pre><code>{{&lt; carousel indented &gt;}}\n</code></pre>",
+            "<code>{{&lt; carousel &gt;}} ` tick</code>",
+            "<pre><code>```\n{{&lt; carousel tilde &gt;}}\n</code></pre>",
+        ] {
+            assert!(html.contains(code), "{} in {}", code, html);
+        }
+    }
 
-                let resized: DynamicImage = if width > MAX_WIDTH {
-                    let new_height = (height as u64 * MAX_WIDTH as u64 / width as u64).max(1) as u32;
-                    img.resize(MAX_WIDTH, new_height, Lanczos3)
-                } else {
-                    img
-                };
+    #[test]
+    fn unknown_shortcodes_and_reserved_arguments_are_errors() {
+        let err = render("unknown-shortcode", "{{< carousel src=\"a.png\" >}}\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown shortcode `carousel` in page.md (expected template theme/shortcodes/carousel.html)"
+        );
+        assert!(err.is::<ShortcodeError>());
 
-                let new_filename = stem.clone() + ".webp";
-                let dst_path = dst_dir.join(&new_filename);
-
-                resized.write_to(
-                    &mut std::io::BufWriter::new(fs::File::create(&dst_path)?),
-                    ImageFormat::WebP,
-                )?;
-
-                fs::remove_file(&path)?;
-                converted.insert(stem);
-
-                println!(
-                    "{} {} -> {} ({}x{} -> {}x{})",
-                    "Converted:".cyan(),
-                    path.display(),
-                    dst_path.display(),
-                    width,
-                    height,
-                    resized.width(),
-                    resized.height()
-                );
-            }
-            Err(e) => {
-                eprintln!(
-                    "{} {}: {}",
-                    "Warning: could not convert".yellow(),
-                    path.display(),
-                    e
-                );
-            }
-        }
+        let err = render(
+            "reserved-argument",
+            "{{< figure src=\"a.png\" body=\"Caption\" >}}\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Shortcode `figure` in page.md has an argument named `body`, which is reserved"
+        );
     }
 
-    Ok(converted)
-}
+    #[test]
+    fn blog_posts_with_unknown_shortcodes_fail_the_build() {
+        let dir = test_dir("post-shortcodes");
+        fs::create_dir_all(dir.join("blog")).unwrap();
+        fs::write(
+            dir.join("blog/post.md"),
+            "---\ntitle: Post\ndate: 2026-07-02\n---\n\n{{< carousel >}}\n",
+        )
+        .unwrap();
+        let err =
+            scan_blog_posts(&dir, None, Some(&shortcodes()), &Figures::default()).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Unknown shortcode `carousel` in blog/post.md"),
+            "{}",
+            err
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-/// Very simple HTML tag stripper for reading time calculation
-pub fn strip_html_tags(html: &str) -> String {
-    let mut result = String::new();
-    let mut in_tag = false;
-    for c in html.chars() {
-        match c {
-            '<' => in_tag = true,
-            '>' => in_tag = false,
-            c if !in_tag => result.push(c),
-            _ => {}
+    #[test]
+    fn wikilinks_become_links_outside_code_and_math() {
+        let html = render(
+            "wikilinks",
+            concat!(
+                "See [[Home]], [[Post#Design Goals|the goals]] and [[#Local]].\n\n",
+                "![[photo.jpg|320]] ![[notes.pdf|The notes]]\n\n",
+                "Not `[[code]]`, a matrix $[[a, b]]$ or\n\n$$\n[[1, 2], [3, 4]]\n$$\n\n",
+                "A note[^side: back to [[Home|the start]]].\n",
+            ),
+        )
+        .unwrap();
+        for expected in [
+            "<a class=\"wikilink\" href=\"mdbear-wikilink:Home\">Home</a>",
+            "<a class=\"wikilink\" href=\"mdbear-wikilink:Post#Design Goals\">the goals</a>",
+            "<a class=\"wikilink\" href=\"mdbear-wikilink:#Local\">Local</a>",
+            "<img class=\"wikilink-embed\" src=\"mdbear-wikilink:photo.jpg\" alt=\"photo.jpg\" width=\"320\">",
+            "<img class=\"wikilink-embed\" src=\"mdbear-wikilink:notes.pdf\" alt=\"The notes\">",
+            "<code>[[code]]</code>",
+            "<span class=\"math-inline\" data-katex-inline>[[a, b]]</span>",
+            "[[1, 2], [3, 4]]",
+            "back to <a class=\"wikilink\" href=\"mdbear-wikilink:Home\">the start</a>",
+        ] {
+            assert!(html.contains(expected), "{} in {}", expected, html);
         }
     }
-    // Collapse multiple whitespace
-    result.split_whitespace().collect::<Vec<_>>().join(" ")
 }
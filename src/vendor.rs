@@ -0,0 +1,293 @@
+use crate::icons::IconSprite;
+use crate::utils::{DefaultAssets, copy_dir_all};
+use anyhow::Result;
+use rust_embed::EmbeddedFile;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A third-party library the theme loads from `theme/vendor/<name>/`, or from
+/// its CDN when it is not installed there. Both share the same layout. The
+/// copies in `defaults/theme/vendor/` are these versions, as fetched by
+/// scripts/update-vendor.sh, which reads them from here.
+struct Library {
+    name: &'static str,
+    cdn: &'static str,
+    files: &'static [&'static str], // files the templates reference, relative to the base URL
+}
+
+const LIBRARIES: [Library; 5] = [
+    Library {
+        name: "fontawesome",
+        cdn: "https://cdnjs.cloudflare.com/ajax/libs/font-awesome/6.5.2",
+        files: &["css/all.min.css"],
+    },
+    Library {
+        name: "prism",
+        cdn: "https://cdnjs.cloudflare.com/ajax/libs/prism/1.29.0",
+        files: &[
+            "themes/prism-tomorrow.min.css",
+            "components/prism-core.min.js",
+            "components/prism-markup.min.js",
+            "components/prism-bash.min.js",
+        ],
+    },
+    Library {
+        name: "katex",
+        cdn: "https://cdn.jsdelivr.net/npm/katex@0.16.21/dist",
+        files: &[
+            "katex.min.css",
+            "katex.min.js",
+            "contrib/auto-render.min.js",
+        ],
+    },
+    Library {
+        name: "minisearch",
+        cdn: "https://cdn.jsdelivr.net/npm/minisearch@7.1.1/dist/umd",
+        files: &["index.min.js"],
+    },
+    Library {
+        name: "lightbox2",
+        cdn: "https://cdn.jsdelivr.net/npm/lightbox2@2.11.4/dist",
+        files: &["css/lightbox.min.css", "js/lightbox-plus-jquery.min.js"],
+    },
+];
+
+/// The bundled copy of a file the theme loads from a library's CDN
+pub fn bundled_copy(url: &str) -> Option<EmbeddedFile> {
+    LIBRARIES.iter().find_map(|library| {
+        let file = url.strip_prefix(library.cdn)?.strip_prefix('/')?;
+        DefaultAssets::get(&format!("theme/vendor/{}/{}", library.name, file))
+    })
+}
+
+/// Which libraries the theme has vendored, detected once per build, and the
+/// icons pages can inline
+pub struct Vendor {
+    vendor_dir: PathBuf,
+    installed: Vec<&'static str>,
+    icons: IconSprite,
+}
+
+impl Vendor {
+    /// A library counts as installed when every file the templates reference exists
+    pub fn detect(theme_dir: &Path) -> Self {
+        let vendor_dir = theme_dir.join("vendor");
+        let svgs = vendor_dir.join("fontawesome").join("svgs");
+        Vendor {
+            icons: IconSprite::new(svgs.is_dir().then_some(svgs)),
+            installed: LIBRARIES
+                .iter()
+                .filter(|library| {
+                    library
+                        .files
+                        .iter()
+                        .all(|file| vendor_dir.join(library.name).join(file).is_file())
+                })
+                .map(|library| library.name)
+                .collect(),
+            vendor_dir,
+        }
+    }
+
+    /// The sprite that replaces icon font tags with inline SVGs
+    pub fn icons(&self) -> &IconSprite {
+        &self.icons
+    }
+
+    /// Libraries that will be loaded from their CDN
+    pub fn missing(&self) -> Vec<&'static str> {
+        LIBRARIES
+            .iter()
+            .map(|library| library.name)
+            .filter(|name| !self.installed.contains(name))
+            .collect()
+    }
+
+    /// Copy the installed libraries to `output_dir/vendor`. Font Awesome's
+    /// SVGs are left out, as icons are inlined from them.
+    pub fn copy_to(&self, output_dir: &Path) -> Result<()> {
+        for name in &self.installed {
+            let src = self.vendor_dir.join(name);
+            let dst = output_dir.join("vendor").join(name);
+            if *name != "fontawesome" {
+                copy_dir_all(src, dst)?;
+                continue;
+            }
+            fs::create_dir_all(&dst)?;
+            for entry in fs::read_dir(src)? {
+                let entry = entry?;
+                if entry.file_name() == "svgs" {
+                    continue;
+                }
+                if entry.file_type()?.is_dir() {
+                    copy_dir_all(entry.path(), dst.join(entry.file_name()))?;
+                } else {
+                    fs::copy(entry.path(), dst.join(entry.file_name()))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Base URL of every library as seen from a page at `root_path`, for the
+    /// `vendor` template variable, e.g. `{{ vendor.katex }}/katex.min.js`
+    pub fn urls(&self, root_path: &str) -> BTreeMap<&'static str, String> {
+        LIBRARIES
+            .iter()
+            .map(|library| {
+                let url = if self.installed.contains(&library.name) {
+                    format!("{}/vendor/{}", root_path, library.name)
+                } else {
+                    library.cdn.to_string()
+                };
+                (library.name, url)
+            })
+            .collect()
+    }
+}
+
+/// Optional libraries a rendered page needs, for the `needs` template variable
+#[derive(Serialize, Debug, Default)]
+pub struct Needs {
+    pub icons: bool,    // Font Awesome's CSS and icon font, when its SVGs are missing
+    pub math: bool,     // KaTeX
+    pub code: bool,     // Prism
+    pub lightbox: bool, // Lightbox2
+    pub search: bool,   // MiniSearch
+}
+
+impl Needs {
+    pub fn for_content(html: &str, vendor: &Vendor) -> Self {
+        Needs {
+            icons: vendor.icons.needs_font(),
+            math: html.contains("data-katex-") || html.contains("class=\"math-block\""),
+            code: html.contains("<code class=\"language-"),
+            lightbox: html.contains("<img"),
+            search: false,
+        }
+    }
+
+    /// Names of the libraries to load, for the `libraries` template variable
+    pub fn libraries(&self) -> Vec<&'static str> {
+        [
+            (self.icons, "fontawesome"),
+            (self.code, "prism"),
+            (self.search, "minisearch"),
+            (self.math, "katex"),
+            (self.lightbox, "lightbox2"),
+        ]
+        .into_iter()
+        .filter(|(needed, _)| *needed)
+        .map(|(_, name)| name)
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    /// A theme with `files` under `vendor/`
+    fn theme(name: &str, files: &[&str]) -> PathBuf {
+        let dir = test_dir(name);
+        for file in files {
+            let path = dir.join("vendor").join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, format!("/* {} */", file)).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn complete_libraries_are_served_from_vendor() {
+        let dir = theme(
+            "vendor-detect",
+            &[
+                "katex/katex.min.css",
+                "katex/katex.min.js",
+                "katex/contrib/auto-render.min.js",
+                // An incomplete copy is loaded from the CDN
+                "prism/components/prism-core.min.js",
+            ],
+        );
+        let vendor = Vendor::detect(&dir);
+
+        assert_eq!(
+            vendor.missing(),
+            ["fontawesome", "prism", "minisearch", "lightbox2"]
+        );
+        let urls = vendor.urls("..");
+        assert_eq!(urls["katex"], "../vendor/katex");
+        assert_eq!(
+            urls["prism"],
+            "https://cdnjs.cloudflare.com/ajax/libs/prism/1.29.0"
+        );
+        assert_eq!(urls.len(), LIBRARIES.len());
+
+        let output = dir.join("output");
+        vendor.copy_to(&output).unwrap();
+        assert_eq!(
+            fs::read_to_string(output.join("vendor/katex/contrib/auto-render.min.js")).unwrap(),
+            "/* katex/contrib/auto-render.min.js */"
+        );
+        assert!(!output.join("vendor/prism").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn font_awesome_is_copied_without_its_svgs() {
+        let dir = theme(
+            "vendor-fontawesome",
+            &[
+                "fontawesome/css/all.min.css",
+                "fontawesome/webfonts/fa-solid-900.woff2",
+                "fontawesome/svgs/solid/house.svg",
+            ],
+        );
+        let vendor = Vendor::detect(&dir);
+        assert!(!vendor.missing().contains(&"fontawesome"));
+
+        let output = dir.join("output");
+        vendor.copy_to(&output).unwrap();
+        let fontawesome = output.join("vendor/fontawesome");
+        assert!(fontawesome.join("css/all.min.css").is_file());
+        assert!(fontawesome.join("webfonts/fa-solid-900.woff2").is_file());
+        assert!(!fontawesome.join("svgs").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pages_load_only_the_libraries_they_use() {
+        let dir = test_dir("vendor-needs");
+        let vendor = Vendor::detect(&dir);
+        let libraries = |html: &str| Needs::for_content(html, &vendor).libraries();
+
+        assert_eq!(
+            libraries("<p>Plain</p>"),
+            ["fontawesome"],
+            "without SVGs, icons need the font"
+        );
+        assert_eq!(
+            libraries(
+                "<code class=\"language-rust\"></code><span data-katex-inline></span><img src=\"a.png\">"
+            ),
+            ["fontawesome", "prism", "katex", "lightbox2"]
+        );
+        assert_eq!(
+            libraries("<div class=\"math-block\">x</div>"),
+            ["fontawesome", "katex"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_library_urls_have_bundled_copies() {
+        assert!(bundled_copy("https://example.com/katex.min.js").is_none());
+        assert!(
+            bundled_copy("https://cdn.jsdelivr.net/npm/katex@0.16.20/dist/katex.min.js").is_none()
+        );
+    }
+}
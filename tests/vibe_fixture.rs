@@ -1,3 +1,5 @@
+use base64::Engine;
+use sha2::{Digest, Sha256, Sha384};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
@@ -8,11 +10,9 @@ static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 #[test]
 fn vibe_fixture_builds_editorial_site() {
-    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    let fixture = root.join("test").join("vibe-demo");
     let workdir = temp_project_dir();
 
-    copy_dir_all(&fixture, &workdir);
+    copy_fixture(&workdir);
     add_nested_page(&workdir);
 
     let output = Command::new(env!("CARGO_BIN_EXE_mdbear"))
@@ -48,9 +48,21 @@ fn vibe_fixture_builds_editorial_site() {
     assert_contains(&index, "pointermove");
     assert_contains(&index, "application/rss+xml");
     assert_contains(&index, "./rss.xml");
+    // Links are resolved once every page and image is known
+    assert_contains(
+        &index,
+        "<a class=\"wikilink\" href=\"./blog/tufted-demo.html#design-goals\">the design goals</a>",
+    );
+    assert_contains(
+        &index,
+        "<img class=\"wikilink-embed\" src=\"./assets/images/magicsquash.webp\" alt=\"magicsquash.jpg\" width=\"320\"",
+    );
+    assert_contains(&index, "./assets/images/magicsquash-480w.webp 480w");
+    assert_contains(&about, "href=\"./blog/tufted-demo.html#design-goals\"");
 
     assert_contains(&blog, "blog-index");
     assert_contains(&blog, "Three-Column Layout Test");
+    assert_contains(&blog, "<img class=\"post-cover\" src=\"./processed_images/");
 
     assert_contains(
         &about,
@@ -75,6 +87,37 @@ fn vibe_fixture_builds_editorial_site() {
     assert_contains(&post, "fa-solid fa-pen-nib");
     assert_contains(&post, "../rss.xml");
     assert_contains(&post, "sidenote-marker");
+    assert_contains(&post, "<figure class=\"shortcode-figure\">");
+    assert_contains(
+        &post,
+        "<figcaption>A squash in the margin of the layout</figcaption>",
+    );
+    assert_contains(&post, "style=\"--gallery-columns: 2\"");
+    assert!(
+        !post.contains("{{&lt;"),
+        "shortcode syntax leaked into output"
+    );
+    assert_contains(&post, "Pages linking here");
+    assert_contains(&post, "<a href=\"../index.html\">Home</a>");
+    assert_contains(
+        &post,
+        "<a href=\"../blog/margin-notes.html\">Writing in the Margins</a>",
+    );
+    assert_contains(
+        &read_output(&workdir, "graph.json"),
+        "\"target\": \"blog/tufted-demo.html\"",
+    );
+
+    // Standalone Markdown images become numbered, referenceable figures
+    let margin_post = read_output(&workdir, "blog/margin-notes.html");
+    assert_contains(
+        &margin_post,
+        "<figure class=\"markdown-figure\" id=\"fig-squash\"><img src=\"../assets/images/magicsquash.webp\"",
+    );
+    assert_contains(
+        &margin_post,
+        "As <a class=\"figure-ref\" href=\"#fig-squash\">Figure 1</a> shows",
+    );
 
     assert_contains(&rss, "<rss version=\"2.0\"");
     assert_contains(&rss, "<title>Vibe Fixture</title>");
@@ -111,11 +154,9 @@ fn vibe_fixture_builds_editorial_site() {
 
 #[test]
 fn build_rejects_project_root_as_output_dir() {
-    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    let fixture = root.join("test").join("vibe-demo");
     let workdir = temp_project_dir();
 
-    copy_dir_all(&fixture, &workdir);
+    copy_fixture(&workdir);
     let config_path = workdir.join("config.toml");
     let config = fs::read_to_string(&config_path).expect("failed to read fixture config");
     let unsafe_config = config
@@ -151,6 +192,524 @@ fn build_rejects_project_root_as_output_dir() {
     fs::remove_dir_all(&workdir).ok();
 }
 
+#[test]
+fn build_reuses_image_cache_and_clean_removes_it() {
+    let project = Project::new();
+
+    let cache_entries = || {
+        fs::read_dir(project.path(".mdbear-cache/images"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>()
+    };
+
+    // AVIF covers cannot be decoded, so a cache hit must not need to read them back
+    project.replace(
+        "theme/blog.html",
+        r#"op="fill")"#,
+        r#"op="fill", format="avif")"#,
+    );
+
+    let first = project.succeed(&["build", "--jobs", "4"]).stdout;
+    assert_contains(&first, "Converted: assets/images/magicsquash.jpg");
+    let entries = cache_entries();
+    assert_eq!(entries.len(), 1);
+    let parallel_post = project.output("blog/tufted-demo.html");
+    let blog = project.output("blog.html");
+    let cover_re = regex::Regex::new(
+        r#"src="\./processed_images/[0-9a-f]{16}\.avif" alt="" width="480" height="270" style="background: #[0-9a-f]{6} url\(data:image/webp;base64,"#,
+    )
+    .unwrap();
+    assert!(cover_re.is_match(&blog), "no AVIF cover in:\n{}", blog);
+
+    let second = project.succeed(&["build", "--jobs", "1"]).stdout;
+    assert_eq!(
+        project.output("blog/tufted-demo.html"),
+        parallel_post,
+        "output should not depend on the number of jobs"
+    );
+    assert!(
+        !second.contains("Converted:"),
+        "cached image was re-encoded"
+    );
+    assert_contains(&second, "Reused 1 cached image(s)");
+    assert_eq!(
+        project.output("blog.html"),
+        blog,
+        "cached cover lost its size or placeholder"
+    );
+    assert_eq!(
+        image::image_dimensions(project.output_path("assets/images/magicsquash-480w.webp"))
+            .unwrap(),
+        (480, 480),
+        "cached variant was not restored"
+    );
+
+    // Changing pipeline settings invalidates the entry, and the stale one is collected
+    project.configure("[images]\nquality = 60");
+    let third = project.build().stdout;
+    assert_contains(&third, "Converted: assets/images/magicsquash.jpg");
+    assert_contains(&third, "Removed 1 stale image cache entries");
+    let new_entries = cache_entries();
+    assert_eq!(new_entries.len(), 1);
+    assert_ne!(entries, new_entries);
+
+    project.succeed(&["clean", "--cache"]);
+    assert!(!project.path("mdbear").exists());
+    assert!(!project.path(".mdbear-cache").exists());
+    assert!(project.path("content").exists());
+}
+
+#[test]
+fn build_serves_vendored_libraries_and_falls_back_to_cdn() {
+    let project = Project::new();
+    project.write(
+        "content/blog/equations.md",
+        "---\ntitle: Equations\ndate: 2026-07-03\n---\n\nEnergy is $E = mc^2$.\n\n```rust\nfn main() {}\n```\n",
+    );
+
+    // The fixture's theme has no copies in vendor/, like a theme whose
+    // bundled copies were deleted, so every library falls back to its CDN
+    let stdout = project.build().stdout;
+    assert_contains(
+        &stdout,
+        "Loading from CDN (not in theme/vendor): fontawesome, prism, katex, minisearch, lightbox2",
+    );
+    assert!(!project.output_path("vendor").exists());
+
+    let math_post = project.output("blog/equations.html");
+    assert_contains(
+        &math_post,
+        "href=\"https://cdn.jsdelivr.net/npm/katex@0.16.21/dist/katex.min.css\"",
+    );
+    assert_contains(
+        &math_post,
+        "src=\"https://cdn.jsdelivr.net/npm/katex@0.16.21/dist/contrib/auto-render.min.js\"",
+    );
+    assert_contains(
+        &math_post,
+        "src=\"https://cdnjs.cloudflare.com/ajax/libs/prism/1.29.0/components/prism-core.min.js\"",
+    );
+    assert_contains(
+        &math_post,
+        "<meta name=\"mdbear-libraries\" content=\"fontawesome prism katex\">",
+    );
+    assert!(!math_post.contains("lightbox.min.css"));
+    assert!(!math_post.contains("minisearch@"));
+
+    let plain_post = project.output("blog/margin-notes.html");
+    assert!(!plain_post.contains("katex.min.js"));
+    assert!(!plain_post.contains("prism-core.min.js"));
+    assert_contains(
+        &plain_post,
+        "/lightbox2@2.11.4/dist/js/lightbox-plus-jquery.min.js",
+    );
+
+    let blog = project.output("blog.html");
+    assert_contains(&blog, "minisearch@7.1.1/dist/umd/index.min.js");
+    assert!(!blog.contains("katex.min.js"));
+}
+
+/// The libraries committed under `defaults/theme/vendor/`
+fn bundled_vendor_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("defaults/theme/vendor")
+}
+
+#[test]
+fn init_and_sync_install_bundled_libraries() {
+    let project = Project {
+        dir: temp_project_dir(),
+    };
+    fs::create_dir_all(&project.dir).expect("failed to create project directory");
+    project.succeed(&["init", "."]);
+
+    let bundled = bundled_vendor_dir();
+    let files = list_files(&bundled);
+    let assert_installed = || {
+        for file in &files {
+            assert_eq!(
+                fs::read(project.path("theme/vendor").join(file)).ok(),
+                fs::read(bundled.join(file)).ok(),
+                "theme/vendor/{} differs from the bundled copy",
+                file.display()
+            );
+        }
+    };
+    assert_installed();
+
+    // sync restores bundled files that were changed or removed
+    project.write("theme/vendor/README.md", "edited");
+    fs::remove_dir_all(project.path("theme/vendor/katex")).ok();
+    project.succeed(&["sync"]);
+    assert_installed();
+}
+
+#[test]
+fn build_links_site_icons_from_every_page() {
+    let project = Project::new();
+    // An emoji site_icon stays an inline SVG favicon
+    project.build();
+    assert_contains(
+        &project.output("index.html"),
+        "<text y='.9em' font-size='90'>🐻</text>",
+    );
+
+    project.replace(
+        "config.toml",
+        "site_icon = \"🐻\"",
+        "site_icon = \"content/assets/images/magicsquash.jpg\"",
+    );
+
+    project.build();
+
+    let post = project.output("blog/tufted-demo.html");
+    assert_contains(
+        &post,
+        "<link rel=\"icon\" href=\"../favicon.ico\" sizes=\"16x16 32x32 48x48\">",
+    );
+    assert_contains(
+        &post,
+        "<link rel=\"icon\" href=\"../icon-192.png\" sizes=\"192x192\" type=\"image/png\">",
+    );
+    assert_contains(
+        &post,
+        "<link rel=\"apple-touch-icon\" href=\"../apple-touch-icon.png\" sizes=\"180x180\">",
+    );
+    assert_contains(
+        &post,
+        "<link rel=\"manifest\" href=\"../site.webmanifest\">",
+    );
+    assert!(!post.contains("data:image/svg+xml"));
+}
+
+#[test]
+fn build_generates_offline_manifest_and_service_worker() {
+    let project = Project::new();
+    // Offline mode is opt-in
+    project.build();
+    assert!(!project.output("index.html").contains("serviceWorker"));
+    assert!(!project.output_path("sw.js").exists());
+
+    project.configure(
+        "[pwa]\nenabled = true\nrecent_posts = 1\ntheme_color = \"#1f2937\"\n\n[build]\nfingerprint = true",
+    );
+
+    project.build();
+
+    assert_contains(
+        &project.output("manifest.webmanifest"),
+        "\"src\": \"icon.svg\"",
+    );
+    assert!(!project.output_path("site.webmanifest").exists());
+
+    let sw = project.output("sw.js");
+    let list = sw
+        .split_once("const PRECACHE = ")
+        .and_then(|(_, rest)| rest.split_once(";\n"))
+        .unwrap()
+        .0;
+    let precache: Vec<serde_json::Value> = serde_json::from_str(list).unwrap();
+    let urls: Vec<&str> = precache
+        .iter()
+        .map(|entry| entry["url"].as_str().unwrap())
+        .collect();
+    for url in [
+        "index.html",
+        "about.html",
+        "blog.html",
+        "manifest.webmanifest",
+    ] {
+        assert!(urls.contains(&url), "{} not precached in {:?}", url, urls);
+    }
+    // Only the newest post, and the stylesheet under its final name
+    assert!(urls.contains(&"blog/margin-notes.html"), "{:?}", urls);
+    assert!(!urls.contains(&"blog/tufted-demo.html"), "{:?}", urls);
+    assert!(!urls.contains(&"style.css"), "{:?}", urls);
+    assert!(
+        urls.iter()
+            .any(|url| url.starts_with("style.") && url.ends_with(".css"))
+    );
+    assert!(
+        urls.contains(&"assets/images/magicsquash-480w.webp"),
+        "{:?}",
+        urls
+    );
+
+    let post = project.output("blog/tufted-demo.html");
+    assert_contains(
+        &post,
+        "<link rel=\"manifest\" href=\"../manifest.webmanifest\">",
+    );
+    assert_contains(&post, "<meta name=\"theme-color\" content=\"#1f2937\">");
+    assert_contains(&post, "navigator.serviceWorker.register('../sw.js')");
+}
+
+#[test]
+fn build_applies_color_scheme_and_forced_mode() {
+    let project = Project::new();
+    // Without [theme] settings pages follow the reader's preference
+    project.build();
+    let index = project.output("index.html");
+    assert_contains(&index, "<html lang=\"en\">");
+    assert!(!index.contains("--theme-bg"));
+
+    project.configure("[theme]\nmode = \"dark\"\ncolor_scheme = \"harbor\"\n\n[theme.palettes.harbor.dark]\nbg = \"#0f1c24\"\naccent_soft = \"#16323f\"");
+
+    project.build();
+
+    let index = project.output("index.html");
+    assert_contains(&index, "<html lang=\"en\" data-theme=\"dark\">");
+    assert_contains(
+        &index,
+        "html[data-theme=\"dark\"] {\n    --theme-bg: #0f1c24;",
+    );
+    assert!(!index.contains("localStorage.getItem('mdbear-theme') ||"));
+    assert!(!index.contains("<button class=\"nav-theme-toggle\""));
+}
+
+#[test]
+fn build_injects_include_files_and_per_page_assets() {
+    let project = Project::new();
+    project.write(
+        "includes/head.html",
+        "<script defer data-domain=\"example.com\" src=\"https://plausible.io/js/script.js\"></script>\n",
+    );
+    project.write("includes/body-end.html", "<!-- fixture footer -->\n");
+    project.configure(
+        "head_html = \"includes/head.html\"\nbody_end_html = \"includes/body-end.html\"",
+    );
+    project.write("content/extras/chart.css", ".chart { color: red; }\n");
+    project.write("content/extras/chart.js", "console.log('chart');\n");
+    project.replace(
+        "content/blog/tufted-demo.md",
+        "lang: en\n",
+        "lang: en\nextra_css: [extras/chart.css]\nextra_js: [extras/chart.js, \"https://cdn.example.com/widget.js\"]\n",
+    );
+
+    project.build();
+
+    let post = project.output("blog/tufted-demo.html");
+    assert_contains(
+        &post,
+        "<link rel=\"stylesheet\" href=\"../extras/chart.css\">\n    <script defer data-domain=\"example.com\" src=\"https://plausible.io/js/script.js\"></script>\n\n</head>",
+    );
+    assert_contains(&post, "<script src=\"../extras/chart.js\"></script>");
+    assert_contains(
+        &post,
+        "<script src=\"https://cdn.example.com/widget.js\"></script>",
+    );
+    assert_contains(&post, "<!-- fixture footer -->\n\n</body>");
+    // The client-side navigation does a full load to run the page's own files
+    assert_contains(
+        &post,
+        "lightbox2 extras&#x2F;chart.css extras&#x2F;chart.js https:&#x2F;&#x2F;cdn.example.com&#x2F;widget.js\">",
+    );
+
+    let other = project.output("blog/margin-notes.html");
+    assert_contains(&other, "plausible.io");
+    assert!(!other.contains("chart.js"));
+    assert!(
+        project
+            .output("blog.html")
+            .contains("<!-- fixture footer -->")
+    );
+}
+
+#[test]
+fn build_writes_content_security_policy_with_hashes() {
+    let project = Project::new();
+    // Neither bundled nor pinned, so it gets no integrity value
+    let chart = "https://cdn.jsdelivr.net/npm/chart.js@4.4.0/dist/chart.umd.min.js";
+    project.write(
+        "includes/head.html",
+        format!(
+            "<script defer src=\"https://plausible.io/js/script.js\"></script>\n<script src=\"{}\"></script>\n",
+            chart
+        ),
+    );
+    project.configure("head_html = \"includes/head.html\"\n\n[build]\ncsp = \"meta\"");
+    let stderr = project.build().stderr;
+    assert!(!stderr.contains("plausible.io"), "{}", stderr);
+    assert_contains(
+        &project.output("index.html"),
+        &format!("<script src=\"{}\"></script>", chart),
+    );
+
+    let index = project.output("index.html");
+    let policy = index
+        .split_once("<head>\n    <meta http-equiv=\"Content-Security-Policy\" content=\"")
+        .and_then(|(_, rest)| rest.split_once('"'))
+        .expect("no CSP meta tag at the top of <head>")
+        .0
+        .to_string();
+    assert_contains(&policy, "default-src 'self'; script-src 'self' 'sha256-");
+    assert_contains(&policy, "object-src 'none'");
+    let theme_script = index
+        .split_once("<script>document.documentElement.dataset.theme")
+        .and_then(|(_, rest)| rest.split_once("</script>"))
+        .unwrap()
+        .0;
+    let theme_script = format!("document.documentElement.dataset.theme{}", theme_script);
+    let digest =
+        base64::engine::general_purpose::STANDARD.encode(Sha256::digest(theme_script.as_bytes()));
+    assert_contains(&policy, &format!("'sha256-{}'", digest));
+    // The same policy everywhere, as client-side navigation keeps the first one
+    assert_contains(&project.output("blog/tufted-demo.html"), &policy);
+
+    // The theme's libraries are hashed from the copies bundled with mdBear
+    let minisearch = "https://cdn.jsdelivr.net/npm/minisearch@7.1.1/dist/umd/index.min.js";
+    let blog = project.output("blog.html");
+    let bundled = bundled_vendor_dir().join("minisearch/index.min.js");
+    if bundled.is_file() {
+        let digest = base64::engine::general_purpose::STANDARD
+            .encode(Sha384::digest(fs::read(bundled).unwrap()));
+        assert_contains(
+            &blog,
+            &format!(
+                "<script src=\"{}\" integrity=\"sha384-{}\" crossorigin=\"anonymous\"></script>",
+                minisearch, digest
+            ),
+        );
+    } else {
+        assert_contains(&blog, &format!("<script src=\"{}\"></script>", minisearch));
+    }
+    assert_contains(
+        &blog,
+        "<script defer src=\"https://plausible.io/js/script.js\"></script>",
+    );
+}
+
+#[test]
+fn check_reports_broken_references_with_locations() {
+    let project = Project::new();
+    project.succeed(&["check"]);
+    assert!(
+        !project.path("mdbear").exists(),
+        "check should not write to output_dir"
+    );
+
+    project.append(
+        "content/index.md",
+        "\n[nowhere](#nowhere) ![missing](assets/missing.png)\n",
+    );
+
+    let stderr = project.fail(&["check"]).stderr;
+    assert!(
+        stderr
+            .lines()
+            .any(|line| line.contains("index.html:") && line.contains("has no anchor #nowhere")),
+        "missing anchor report in:\n{}",
+        stderr
+    );
+    assert_contains(&stderr, "`assets/missing.png` does not exist");
+}
+
+/// A copy of the vibe-demo fixture in its own temporary directory, removed
+/// again when the test ends
+struct Project {
+    dir: PathBuf,
+}
+
+/// What an `mdbear` run printed
+struct Run {
+    stdout: String,
+    stderr: String,
+}
+
+impl Project {
+    fn new() -> Self {
+        let dir = temp_project_dir();
+        copy_fixture(&dir);
+        Project { dir }
+    }
+
+    fn path(&self, relative: &str) -> PathBuf {
+        self.dir.join(relative)
+    }
+
+    fn output_path(&self, relative: &str) -> PathBuf {
+        self.dir.join("mdbear").join(relative)
+    }
+
+    fn read(&self, relative: &str) -> String {
+        fs::read_to_string(self.path(relative))
+            .unwrap_or_else(|_| panic!("missing project file: {}", relative))
+    }
+
+    fn output(&self, relative: &str) -> String {
+        fs::read_to_string(self.output_path(relative))
+            .unwrap_or_else(|_| panic!("missing output file: {}", relative))
+    }
+
+    /// Write a project file, creating its directory
+    fn write(&self, relative: &str, contents: impl AsRef<[u8]>) {
+        let path = self.path(relative);
+        fs::create_dir_all(path.parent().unwrap()).expect("failed to create directory");
+        fs::write(&path, contents).unwrap_or_else(|_| panic!("failed to write {}", path.display()));
+    }
+
+    fn append(&self, relative: &str, text: &str) {
+        self.write(relative, self.read(relative) + text);
+    }
+
+    fn replace(&self, relative: &str, from: &str, to: &str) {
+        let contents = self.read(relative);
+        assert!(contents.contains(from), "{} has no `{}`", relative, from);
+        self.write(relative, contents.replacen(from, to, 1));
+    }
+
+    /// Add settings to `config.toml` ahead of its tables, so that both
+    /// top-level keys and new tables can be given
+    fn configure(&self, toml: &str) {
+        self.replace("config.toml", "[[nav]]", &format!("{}\n\n[[nav]]", toml));
+    }
+
+    fn run(&self, args: &[&str]) -> (bool, Run) {
+        let output = Command::new(env!("CARGO_BIN_EXE_mdbear"))
+            .args(args)
+            .current_dir(&self.dir)
+            .output()
+            .expect("failed to run mdbear");
+        let run = Run {
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        };
+        (output.status.success(), run)
+    }
+
+    /// Run `mdbear` with `args` and fail the test if it fails
+    fn succeed(&self, args: &[&str]) -> Run {
+        let (success, run) = self.run(args);
+        assert!(
+            success,
+            "mdbear {:?} failed\nstdout:\n{}\nstderr:\n{}",
+            args, run.stdout, run.stderr
+        );
+        run
+    }
+
+    /// Run `mdbear` with `args` and fail the test if it succeeds
+    fn fail(&self, args: &[&str]) -> Run {
+        let (success, run) = self.run(args);
+        assert!(
+            !success,
+            "mdbear {:?} unexpectedly succeeded\nstdout:\n{}",
+            args, run.stdout
+        );
+        run
+    }
+
+    fn build(&self) -> Run {
+        self.succeed(&["build"])
+    }
+}
+
+impl Drop for Project {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
 fn add_nested_page(workdir: &Path) {
     let docs_dir = workdir.join("content").join("docs");
     fs::create_dir_all(&docs_dir).expect("failed to create nested content directory");
@@ -166,6 +725,13 @@ fn add_nested_page(workdir: &Path) {
     fs::write(config_path, config).expect("failed to update fixture config");
 }
 
+/// The vibe-demo content and config, with the theme `init` installs
+fn copy_fixture(workdir: &Path) {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    copy_dir_all(&root.join("test").join("vibe-demo"), workdir);
+    copy_dir_all(&root.join("defaults").join("theme"), &workdir.join("theme"));
+}
+
 fn temp_project_dir() -> PathBuf {
     let nanos = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -204,6 +770,24 @@ fn copy_dir_all(src: &Path, dst: &Path) {
     }
 }
 
+/// Every file under `dir`, relative to it
+fn list_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).expect("failed to read directory") {
+        let path = entry.expect("failed to read directory entry").path();
+        if path.is_dir() {
+            files.extend(
+                list_files(&path)
+                    .into_iter()
+                    .map(|file| Path::new(path.file_name().unwrap()).join(file)),
+            );
+        } else {
+            files.push(PathBuf::from(path.file_name().unwrap()));
+        }
+    }
+    files
+}
+
 fn read_output(workdir: &Path, relative: &str) -> String {
     fs::read_to_string(workdir.join("mdbear").join(relative))
         .unwrap_or_else(|_| panic!("missing output file: {}", relative))